@@ -0,0 +1,105 @@
+//! Throughput of building and encoding data packets, and the latency of sending them over
+//! loopback, for every encoding. Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use decentralized_automation_networks_virtual_input_node::{
+    encoding::encode_binary,
+    generator::{DataSample, SampleValue},
+    protocol::{data_packet, DataPacket},
+};
+use std::hint::black_box;
+use std::net::UdpSocket;
+
+const SENT_AT_MICROS: u64 = 1_700_000_000_000_000;
+
+fn sample(seq: u64) -> DataSample {
+    DataSample {
+        seq,
+        channel: None,
+        value: SampleValue::U16(32768),
+        raw: None,
+        generated_at_micros: SENT_AT_MICROS,
+    }
+}
+
+fn packet(samples: &[DataSample]) -> DataPacket<'_> {
+    DataPacket {
+        sent_at_micros: Some(SENT_AT_MICROS),
+        ..DataPacket::new(samples, "Flow 1", "room1", true, false)
+    }
+}
+
+/// Builds and encodes the data packet for a sample into a reused buffer
+type Encode = fn(&DataSample, &mut Vec<u8>);
+
+/// Every encoding, building packets the way the node builds them, and JSON through a `Value` for
+/// comparison
+fn encodings() -> [(&'static str, Encode); 4] {
+    [
+        ("json", |sample, buf| {
+            packet(std::slice::from_ref(sample)).write_json(buf)
+        }),
+        ("json_value", |sample, buf| {
+            let mut packet = data_packet(sample, "Flow 1", "room1", true, false);
+            packet["sent_at_micros"] = SENT_AT_MICROS.into();
+            *buf = packet.to_string().into_bytes();
+        }),
+        ("binary", |sample, buf| {
+            buf.clear();
+            buf.extend_from_slice(&encode_binary(sample, SENT_AT_MICROS));
+        }),
+        ("msgpack", |sample, buf| {
+            packet(std::slice::from_ref(sample)).write_msgpack(buf)
+        }),
+    ]
+}
+
+fn build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build");
+    group.throughput(Throughput::Elements(1));
+    let mut buf = vec![];
+    for (name, encode) in encodings() {
+        group.bench_function(name, |b| {
+            b.iter(|| encode(black_box(&sample(42)), &mut buf))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("build_batch");
+    for size in [10u64, 100] {
+        let samples: Vec<DataSample> = (0..size).map(sample).collect();
+        group.throughput(Throughput::Elements(size));
+        group.bench_with_input(BenchmarkId::new("json", size), &samples, |b, samples| {
+            b.iter(|| packet(black_box(samples)).write_json(&mut buf))
+        });
+    }
+    group.finish();
+}
+
+/// Builds, sends and receives a packet over loopback
+fn send(c: &mut Criterion) {
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender.connect(receiver.local_addr().unwrap()).unwrap();
+    let mut packet = vec![];
+    let mut buf = [0; 2048];
+
+    let mut group = c.benchmark_group("send_loopback");
+    for (name, encode) in encodings() {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || sample(42),
+                |sample| {
+                    encode(&sample, &mut packet);
+                    sender.send(&packet).unwrap();
+                    receiver.recv(&mut buf).unwrap()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, build, send);
+criterion_main!(benches);
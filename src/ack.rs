@@ -0,0 +1,211 @@
+use crate::auth::SharedSecret;
+use crate::capture::Capture;
+use crate::protocol::Direction;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::time;
+use tracing::{debug, warn};
+
+/// Delay before the first resend of an ACK waiting for confirmation, doubled after every attempt
+const ACK_CONFIRM_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Upper bound for the delay between resends of an ACK waiting for confirmation
+const ACK_CONFIRM_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Tells apart ACKs waiting for confirmation: their destination, `type` and the JSON
+/// representation of their request id
+type PendingKey = (SocketAddr, String, String);
+
+/// An ACK waiting for confirmation
+struct Pending {
+    /// the ACK as sent, to recognize retransmitted requests
+    ack: String,
+    confirmed: oneshot::Sender<()>,
+}
+
+/// Sends ACKs for control messages, either repeated a fixed number of times or, if the ACK
+/// carries a `request_id`, until the controller confirms it
+#[derive(Clone)]
+pub struct AckSender {
+    socket: Arc<UdpSocket>,
+    pending: Arc<Mutex<HashMap<PendingKey, Pending>>>,
+    repeat: u32,
+    repeat_interval: Duration,
+    confirm_max_attempts: u32,
+    /// changes when the config is reloaded
+    quiet: Arc<AtomicBool>,
+    /// signs every ACK if set
+    secret: Option<SharedSecret>,
+    capture: Capture,
+}
+
+impl AckSender {
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        repeat: u32,
+        repeat_interval: Duration,
+        confirm_max_attempts: u32,
+        quiet: Arc<AtomicBool>,
+        secret: Option<SharedSecret>,
+        capture: Capture,
+    ) -> Self {
+        AckSender {
+            socket,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            repeat,
+            repeat_interval,
+            confirm_max_attempts,
+            quiet,
+            secret,
+            capture,
+        }
+    }
+
+    /// Sends `ack` to `destination`. ACKs with a `request_id` are resent in the background
+    /// until [`confirm`](Self::confirm) is called for it, all others are sent `repeat` times,
+    /// all but the first in the background. A different ACK with the same destination, `type` and `request_id`
+    /// (e.g. for a corrected retry after a NACK) replaces the one being resent.
+    pub async fn send(&self, mut ack: Value, destination: SocketAddr) {
+        if let Some(secret) = &self.secret {
+            secret.sign(&mut ack);
+        }
+        match ack.get("request_id") {
+            Some(request_id) => {
+                let request_id = request_id.to_string();
+                let key = (destination, ack["type"].to_string(), request_id.clone());
+                let message = ack.to_string();
+                let (confirmed_tx, confirmed_rx) = oneshot::channel();
+                {
+                    let mut pending = self.pending.lock().unwrap();
+                    if pending
+                        .get(&key)
+                        .is_some_and(|pending| pending.ack == message)
+                    {
+                        // a retransmitted request, its ACK is already being resent
+                        return;
+                    }
+                    let replaced = pending.insert(
+                        key.clone(),
+                        Pending {
+                            ack: message.clone(),
+                            confirmed: confirmed_tx,
+                        },
+                    );
+                    if let Some(replaced) = replaced {
+                        let _ = replaced.confirmed.send(());
+                    }
+                }
+                if !self.quiet.load(Ordering::Relaxed) {
+                    debug!(%destination, %ack, "Sending ACK until confirmed");
+                }
+                let acks = self.clone();
+                tokio::spawn(async move {
+                    if !send_until_confirmed(
+                        &acks.socket,
+                        &acks.capture,
+                        message.as_bytes(),
+                        destination,
+                        acks.confirm_max_attempts,
+                        confirmed_rx,
+                    )
+                    .await
+                    {
+                        warn!(
+                            %request_id,
+                            max_attempts = acks.confirm_max_attempts,
+                            "ACK for {} was never confirmed",
+                            ack["type"]
+                        );
+                    }
+                    let mut pending = acks.pending.lock().unwrap();
+                    // unless it was replaced in the meantime
+                    if pending
+                        .get(&key)
+                        .is_some_and(|pending| pending.ack == message)
+                    {
+                        pending.remove(&key);
+                    }
+                });
+            }
+            None => {
+                if !self.quiet.load(Ordering::Relaxed) {
+                    debug!(%destination, %ack, "Sending ACK");
+                }
+                let message = ack.to_string();
+                if self.repeat == 0 {
+                    return;
+                }
+                self.send_copy(message.as_bytes(), destination).await;
+                if self.repeat == 1 {
+                    return;
+                }
+                // send multiple times to "make sure" it arrives, without holding up the caller
+                let acks = self.clone();
+                tokio::spawn(async move {
+                    for _ in 1..acks.repeat {
+                        if !acks.repeat_interval.is_zero() {
+                            time::sleep(acks.repeat_interval).await;
+                        }
+                        acks.send_copy(message.as_bytes(), destination).await;
+                    }
+                });
+            }
+        }
+    }
+
+    /// Sends a single copy of an ACK
+    async fn send_copy(&self, ack: &[u8], destination: SocketAddr) {
+        match self.socket.send_to(ack, destination).await {
+            Ok(_) => self.capture.record(Direction::Outbound, destination, ack),
+            Err(e) => warn!(%destination, error = %e, "Couldn't send ACK"),
+        }
+    }
+
+    /// Stops resending the ACKs for `request_id`, returning whether any was still pending
+    pub fn confirm(&self, request_id: &Value) -> bool {
+        let request_id = request_id.to_string();
+        let mut pending = self.pending.lock().unwrap();
+        let keys: Vec<PendingKey> = pending
+            .keys()
+            .filter(|(_, _, id)| *id == request_id)
+            .cloned()
+            .collect();
+        for key in &keys {
+            if let Some(ack) = pending.remove(key) {
+                // the resending task may have just given up, that's fine
+                let _ = ack.confirmed.send(());
+            }
+        }
+        !keys.is_empty()
+    }
+}
+
+/// Sends a message repeatedly with exponential backoff until `confirmed` fires or `max_attempts`
+/// is reached. Returns whether the message was confirmed.
+async fn send_until_confirmed(
+    socket: &UdpSocket,
+    capture: &Capture,
+    message: &[u8],
+    destination: SocketAddr,
+    max_attempts: u32,
+    mut confirmed: oneshot::Receiver<()>,
+) -> bool {
+    let mut backoff = ACK_CONFIRM_INITIAL_BACKOFF;
+    for _ in 0..max_attempts {
+        match socket.send_to(message, destination).await {
+            Ok(_) => capture.record(Direction::Outbound, destination, message),
+            Err(e) => warn!(%destination, error = %e, "Couldn't send"),
+        }
+        tokio::select! {
+            _ = time::sleep(backoff) => {}
+            result = &mut confirmed => return result.is_ok(),
+        }
+        backoff = (backoff * 2).min(ACK_CONFIRM_MAX_BACKOFF);
+    }
+    false
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A single IP address or a CIDR range like `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 peers of a dual-stack socket show up as IPv4-mapped IPv6 addresses
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = prefix_len as usize / 8;
+    let remaining_bits = prefix_len % 8;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid IP address '{}': {}", address, e))?
+            .to_canonical();
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| {
+                    format!(
+                        "invalid prefix length '{}', expected 0 to {}",
+                        prefix_len, max_prefix_len
+                    )
+                })?,
+            None => max_prefix_len,
+        };
+
+        Ok(IpRange {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl serde::Serialize for IpRange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for IpRange {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn single_address_matches_only_itself() {
+        let range: IpRange = "10.0.0.5".parse().unwrap();
+        assert!(range.contains(ip("10.0.0.5")));
+        assert!(!range.contains(ip("10.0.0.6")));
+    }
+
+    #[test]
+    fn cidr_ranges() {
+        let range: IpRange = "192.168.4.0/22".parse().unwrap();
+        assert!(range.contains(ip("192.168.4.1")));
+        assert!(range.contains(ip("192.168.7.255")));
+        assert!(!range.contains(ip("192.168.8.0")));
+
+        let range: IpRange = "fd00::/8".parse().unwrap();
+        assert!(range.contains(ip("fd12::1")));
+        assert!(!range.contains(ip("fe80::1")));
+        assert!(!range.contains(ip("10.0.0.1")));
+
+        assert!("0.0.0.0/0"
+            .parse::<IpRange>()
+            .unwrap()
+            .contains(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_ranges() {
+        let range: IpRange = "10.0.0.0/8".parse().unwrap();
+        assert!(range.contains(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+        assert!("controller".parse::<IpRange>().is_err());
+    }
+}
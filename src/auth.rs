@@ -0,0 +1,192 @@
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The key used to authenticate control messages and ACKs. Never shows up in logs or
+/// `--print-config` output.
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(transparent)]
+pub struct SharedSecret(String);
+
+impl SharedSecret {
+    fn mac(&self, message: &Value) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(self.0.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(canonical_json(message).as_bytes());
+        mac
+    }
+
+    /// Adds an `hmac` field to `message`, computed over the message without it
+    pub fn sign(&self, message: &mut Value) {
+        if let Some(fields) = message.as_object_mut() {
+            fields.remove("hmac");
+        }
+        let signature = hex::encode(self.mac(message).finalize().into_bytes());
+        message["hmac"] = signature.into();
+    }
+
+    /// Checks the `hmac` field of `message` in constant time
+    pub fn verify(&self, message: &Value) -> Result<(), &'static str> {
+        let signature = message["hmac"].as_str().ok_or("missing `hmac` field")?;
+        let signature = hex::decode(signature).map_err(|_| "`hmac` is not valid hex")?;
+
+        let mut unsigned = message.clone();
+        if let Some(fields) = unsigned.as_object_mut() {
+            fields.remove("hmac");
+        }
+        self.mac(&unsigned)
+            .verify_slice(&signature)
+            .map_err(|_| "`hmac` doesn't match")
+    }
+}
+
+/// Compact JSON with object keys sorted, so both sides compute the HMAC over the same bytes
+/// regardless of how the message was serialized
+fn canonical_json(message: &Value) -> String {
+    // without serde_json's `preserve_order` feature, object keys are always sorted
+    message.to_string()
+}
+
+impl FromStr for SharedSecret {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("the shared secret must not be empty");
+        }
+        Ok(SharedSecret(s.to_string()))
+    }
+}
+
+impl fmt::Debug for SharedSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedSecret(<redacted>)")
+    }
+}
+
+impl serde::Serialize for SharedSecret {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+/// The key data packets are encrypted with in a DTLS session, given as hex. Never shows up in logs
+/// or `--print-config` output either.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PresharedKey(Vec<u8>);
+
+impl PresharedKey {
+    /// The longest key OpenSSL accepts, in bytes
+    pub const MAX_LEN: usize = 256;
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl FromStr for PresharedKey {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = hex::decode(s).map_err(|_| "the pre-shared key must be hex")?;
+        if key.is_empty() {
+            return Err("the pre-shared key must not be empty");
+        }
+        if key.len() > Self::MAX_LEN {
+            return Err("the pre-shared key must be at most 256 bytes");
+        }
+        Ok(PresharedKey(key))
+    }
+}
+
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PresharedKey(<redacted>)")
+    }
+}
+
+impl serde::Serialize for PresharedKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PresharedKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <String as serde::Deserialize>::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn secret() -> SharedSecret {
+        "correct horse battery staple".parse().unwrap()
+    }
+
+    #[test]
+    fn signed_messages_verify() {
+        let mut message =
+            json!({"type": "updateTarget", "target": "10.0.0.5", "target_port": 5123});
+        secret().sign(&mut message);
+        assert!(secret().verify(&message).is_ok());
+    }
+
+    #[test]
+    fn key_order_doesnt_matter() {
+        let mut message = json!({"type": "setInterval", "interval_ms": 100});
+        secret().sign(&mut message);
+        let reordered: Value = serde_json::from_str(&format!(
+            r#"{{"interval_ms":100,"hmac":{},"type":"setInterval"}}"#,
+            message["hmac"]
+        ))
+        .unwrap();
+        assert!(secret().verify(&reordered).is_ok());
+    }
+
+    #[test]
+    fn tampered_or_unsigned_messages_fail() {
+        let mut message = json!({"type": "setInterval", "interval_ms": 100});
+        secret().sign(&mut message);
+
+        let mut tampered = message.clone();
+        tampered["interval_ms"] = 1.into();
+        assert!(secret().verify(&tampered).is_err());
+
+        let other: SharedSecret = "another secret".parse().unwrap();
+        assert!(other.verify(&message).is_err());
+
+        assert!(secret()
+            .verify(&json!({"type": "setInterval", "interval_ms": 100}))
+            .is_err());
+    }
+
+    #[test]
+    fn secret_is_redacted() {
+        assert!(!format!("{:?}", secret()).contains("horse"));
+        assert_eq!(serde_json::to_string(&secret()).unwrap(), "\"<redacted>\"");
+    }
+
+    #[test]
+    fn preshared_keys_are_hex() {
+        let key: PresharedKey = "00c0ffee".parse().unwrap();
+        assert_eq!(key.as_bytes(), [0x00, 0xc0, 0xff, 0xee]);
+        assert!(!format!("{:?}", key).contains("c0ffee"));
+        assert!(serde_json::from_str::<PresharedKey>("\"c0ffee\"").is_ok());
+
+        assert!("".parse::<PresharedKey>().is_err());
+        assert!("not hex".parse::<PresharedKey>().is_err());
+        assert!("00"
+            .repeat(PresharedKey::MAX_LEN + 1)
+            .parse::<PresharedKey>()
+            .is_err());
+    }
+}
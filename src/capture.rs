@@ -0,0 +1,222 @@
+use crate::clock::ClockSource;
+use crate::protocol::Direction;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{self, AsyncWriteExt, BufWriter};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, warn};
+
+/// Records waiting to be written before new ones are dropped
+const CAPTURE_QUEUE_SIZE: usize = 4096;
+
+/// A message the node sent or received
+struct Record {
+    timestamp_micros: u64,
+    direction: Direction,
+    peer: SocketAddr,
+    payload: Vec<u8>,
+}
+
+/// Records the messages the node sends and receives. Recording never blocks: the records are
+/// written by a [`CaptureWriter`] in its own task, and dropped if it can't keep up.
+#[derive(Clone, Default)]
+pub struct Capture {
+    /// `None` if capturing is disabled
+    tx: Option<mpsc::Sender<Record>>,
+    dropped: Arc<AtomicU64>,
+    clock: ClockSource,
+}
+
+impl Capture {
+    /// Opens (or creates) `path` for appending, rotating it once it grows beyond `max_bytes`.
+    /// Records are timestamped by `clock`.
+    pub async fn open(
+        path: &str,
+        max_bytes: u64,
+        clock: ClockSource,
+    ) -> io::Result<(Capture, CaptureWriter)> {
+        let file = open_append(path).await?;
+        let written = file.metadata().await?.len();
+        let (tx, rx) = mpsc::channel(CAPTURE_QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        info!(capture_file = path, "Capturing all messages");
+        Ok((
+            Capture {
+                tx: Some(tx),
+                dropped: dropped.clone(),
+                clock,
+            },
+            CaptureWriter {
+                path: path.to_string(),
+                max_bytes,
+                file: BufWriter::new(file),
+                written,
+                rx,
+                dropped,
+            },
+        ))
+    }
+
+    /// Records a message sent to or received from `peer`
+    pub fn record(&self, direction: Direction, peer: SocketAddr, payload: &[u8]) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        let record = Record {
+            timestamp_micros: self.clock.now_micros(),
+            direction,
+            peer,
+            payload: payload.to_vec(),
+        };
+        // a closed channel means the writer gave up after an error it already logged
+        if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send(record) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Appends the records of a [`Capture`] to the capture file, as JSON lines
+pub struct CaptureWriter {
+    path: String,
+    max_bytes: u64,
+    file: BufWriter<File>,
+    /// size of the current file
+    written: u64,
+    rx: mpsc::Receiver<Record>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl CaptureWriter {
+    /// Writes records until `shutdown` fires, then writes what's left in the queue. Stops early
+    /// if the file can't be written.
+    pub async fn run(&mut self, mut shutdown: broadcast::Receiver<()>) {
+        loop {
+            let record = tokio::select! {
+                Some(record) = self.rx.recv() => record,
+                _ = shutdown.recv() => break,
+            };
+            // flush once the queue is empty instead of after every record
+            let mut next = Some(record);
+            while let Some(record) = next {
+                if let Err(e) = self.write(record).await {
+                    warn!(capture_file = self.path, error = %e, "Couldn't write capture file, no more messages will be captured");
+                    return;
+                }
+                next = self.rx.try_recv().ok();
+            }
+            if let Err(e) = self.file.flush().await {
+                warn!(capture_file = self.path, error = %e, "Couldn't write capture file, no more messages will be captured");
+                return;
+            }
+
+            let dropped = self.dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                warn!(dropped, "Capture queue full, messages were not captured");
+            }
+        }
+
+        self.rx.close();
+        while let Some(record) = self.rx.recv().await {
+            if self.write(record).await.is_err() {
+                break;
+            }
+        }
+        if let Err(e) = self.file.flush().await {
+            warn!(capture_file = self.path, error = %e, "Couldn't write capture file");
+        }
+    }
+
+    async fn write(&mut self, record: Record) -> io::Result<()> {
+        let mut line = record_json(&record).to_string();
+        line.push('\n');
+        // a single record larger than `max_bytes` still ends up in a file of its own
+        if self.written > 0 && self.written + line.len() as u64 > self.max_bytes {
+            self.rotate().await?;
+        }
+        self.file.write_all(line.as_bytes()).await?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    /// Moves the current file to `<path>.1`, replacing the previous one, and starts a new file
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        fs::rename(&self.path, format!("{}.1", self.path)).await?;
+        self.file = BufWriter::new(open_append(&self.path).await?);
+        self.written = 0;
+        info!(capture_file = self.path, "Rotated capture file");
+        Ok(())
+    }
+}
+
+async fn open_append(path: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// JSON payloads are embedded as is, other text as a string and binary payloads as hex
+fn record_json(record: &Record) -> Value {
+    let mut json = json!({
+        "timestamp_micros": record.timestamp_micros,
+        "direction": record.direction,
+        "peer": record.peer.to_string(),
+    });
+    match serde_json::from_slice::<Value>(&record.payload) {
+        Ok(payload) => json["payload"] = payload,
+        Err(_) => match std::str::from_utf8(&record.payload) {
+            Ok(text) => json["payload_text"] = text.into(),
+            Err(_) => json["payload_hex"] = hex::encode(&record.payload).into(),
+        },
+    }
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_lines(path: &str) -> Vec<Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn records_messages_and_rotates() {
+        let path = std::env::temp_dir()
+            .join(format!("vin-{}-capture.jsonl", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.1", path));
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        let (capture, mut writer) = Capture::open(&path, 250, ClockSource::Wall).await.unwrap();
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let writer = tokio::spawn(async move { writer.run(shutdown_rx).await });
+        capture.record(Direction::Inbound, peer, br#"{"type":"pauseData"}"#);
+        capture.record(Direction::Outbound, peer, &[0xff, 0x00]);
+        capture.record(Direction::Outbound, peer, b"not json");
+        shutdown_tx.send(()).unwrap();
+        writer.await.unwrap();
+
+        let mut records = read_lines(&format!("{}.1", path));
+        records.extend(read_lines(&path));
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["direction"], "inbound");
+        assert_eq!(records[0]["peer"], "127.0.0.1:5000");
+        assert_eq!(records[0]["payload"]["type"], "pauseData");
+        assert_eq!(records[1]["direction"], "outbound");
+        assert_eq!(records[1]["payload_hex"], "ff00");
+        assert_eq!(records[2]["payload_text"], "not json");
+        assert!(std::fs::metadata(&path).unwrap().len() <= 250);
+    }
+}
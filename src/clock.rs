@@ -0,0 +1,74 @@
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// The wall-clock time paired with the monotonic clock, taken once per process
+static ANCHOR: OnceLock<(Instant, u64)> = OnceLock::new();
+
+/// Where the timestamps the node sends (data packets, ping replies, status) and captures come from
+#[derive(
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum ClockSource {
+    // the system time, follows every adjustment of the clock, including NTP steps
+    #[default]
+    Wall,
+    // the system time at startup plus the time passed since on the monotonic clock, so
+    // differences between timestamps stay exact, but the clock drifts from the system time
+    MonotonicAnchored,
+}
+
+impl ClockSource {
+    /// The current time as microseconds since the UNIX epoch
+    pub fn now_micros(self) -> u64 {
+        match self {
+            ClockSource::Wall => micros_since_epoch(),
+            ClockSource::MonotonicAnchored => {
+                let (instant, micros) = anchor();
+                micros + instant.elapsed().as_micros() as u64
+            }
+        }
+    }
+}
+
+/// The anchor of [`ClockSource::MonotonicAnchored`], taken on the first call. Called on startup,
+/// so timestamps don't depend on when the first one is needed.
+pub(crate) fn anchor() -> (Instant, u64) {
+    *ANCHOR.get_or_init(|| (Instant::now(), micros_since_epoch()))
+}
+
+/// Current system time as microseconds since the UNIX epoch
+pub(crate) fn micros_since_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Couldn't get system time")
+        .as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn anchored_clock_follows_the_monotonic_clock() {
+        let (_, anchor_micros) = anchor();
+        let first = ClockSource::MonotonicAnchored.now_micros();
+        std::thread::sleep(Duration::from_millis(5));
+        let second = ClockSource::MonotonicAnchored.now_micros();
+        assert!(first >= anchor_micros);
+        assert!(second - first >= 5_000);
+        // both clocks start out the same, a step of the system time would only move `Wall`
+        let wall = ClockSource::Wall.now_micros();
+        assert!(wall.abs_diff(second) < 1_000_000);
+    }
+}
@@ -0,0 +1,145 @@
+use flate2::write::GzEncoder;
+use serde_json::{json, Value};
+use std::io::Write;
+
+/// zstd's default level, a good trade-off for small payloads
+const ZSTD_LEVEL: i32 = 3;
+
+/// How encoded data packets are compressed before they are sent. Compressed packets start with a
+/// byte telling the codec, see [`compress`].
+#[derive(
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    // packets are sent as encoded, without a header
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// The header byte of a packet that is sent uncompressed because compressing didn't make it any
+/// smaller
+pub const HEADER_UNCOMPRESSED: u8 = 0;
+pub const HEADER_GZIP: u8 = 1;
+pub const HEADER_ZSTD: u8 = 2;
+
+/// Compresses `payload` into `buf`, behind a header byte for the codec. Payloads that don't get
+/// any smaller (e.g. single small packets) are copied as they are, behind
+/// [`HEADER_UNCOMPRESSED`]. With [`Compression::None`], `payload` is copied without a header.
+pub fn compress(compression: Compression, payload: &[u8], buf: &mut Vec<u8>) {
+    buf.clear();
+    match compression {
+        Compression::None => {
+            buf.extend_from_slice(payload);
+            return;
+        }
+        Compression::Gzip => {
+            buf.push(HEADER_GZIP);
+            let mut encoder = GzEncoder::new(&mut *buf, flate2::Compression::default());
+            encoder
+                .write_all(payload)
+                .and_then(|_| encoder.finish().map(|_| ()))
+                .expect("writing to a Vec can't fail");
+        }
+        Compression::Zstd => {
+            buf.push(HEADER_ZSTD);
+            zstd::stream::copy_encode(payload, &mut *buf, ZSTD_LEVEL)
+                .expect("writing to a Vec can't fail");
+        }
+    }
+    if buf.len() > payload.len() {
+        buf.clear();
+        buf.push(HEADER_UNCOMPRESSED);
+        buf.extend_from_slice(payload);
+    }
+}
+
+/// Machine-readable description of the header of compressed packets, included in
+/// `--dump-protocol`
+pub fn describe_header() -> Value {
+    json!({
+        "size": 1,
+        "values": {
+            "uncompressed": HEADER_UNCOMPRESSED,
+            "gzip": HEADER_GZIP,
+            "zstd": HEADER_ZSTD,
+        },
+    })
+}
+
+/// Restores the encoded packet from a compressed one (with a header), for receivers written in
+/// Rust
+#[cfg(feature = "decoder")]
+pub fn decompress(packet: &[u8]) -> Result<Vec<u8>, String> {
+    use std::io::Read;
+
+    let (&header, payload) = packet.split_first().ok_or("empty packet")?;
+    match header {
+        HEADER_UNCOMPRESSED => Ok(payload.to_vec()),
+        HEADER_GZIP => {
+            let mut decompressed = vec![];
+            flate2::read::GzDecoder::new(payload)
+                .read_to_end(&mut decompressed)
+                .map_err(|e| format!("invalid gzip data: {}", e))?;
+            Ok(decompressed)
+        }
+        HEADER_ZSTD => {
+            zstd::stream::decode_all(payload).map_err(|e| format!("invalid zstd data: {}", e))
+        }
+        header => Err(format!("unknown compression header {}", header)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_payloads_are_sent_uncompressed() {
+        let mut buf = vec![];
+        compress(Compression::Zstd, b"{}", &mut buf);
+        assert_eq!(buf, b"\x00{}");
+
+        compress(Compression::None, b"{}", &mut buf);
+        assert_eq!(buf, b"{}");
+    }
+
+    #[test]
+    fn batches_get_smaller() {
+        let batch = r#"{"message":"32768","meta":{"flow_name":"Flow 1"}}"#.repeat(20);
+        for (compression, header) in [
+            (Compression::Gzip, HEADER_GZIP),
+            (Compression::Zstd, HEADER_ZSTD),
+        ] {
+            let mut buf = vec![];
+            compress(compression, batch.as_bytes(), &mut buf);
+            assert_eq!(buf[0], header);
+            assert!(buf.len() < batch.len() / 4);
+        }
+    }
+
+    #[cfg(feature = "decoder")]
+    #[test]
+    fn round_trip() {
+        let batch = r#"{"message":"32768"}"#.repeat(20);
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let mut buf = vec![];
+            compress(compression, batch.as_bytes(), &mut buf);
+            assert_eq!(decompress(&buf).unwrap(), batch.as_bytes());
+        }
+        assert_eq!(decompress(b"\x00{}").unwrap(), b"{}");
+        assert!(decompress(b"\x01{}").is_err());
+        assert!(decompress(b"\x07{}").is_err());
+        assert!(decompress(b"").is_err());
+    }
+}
@@ -0,0 +1,1268 @@
+use crate::allowlist::IpRange;
+use crate::auth::{PresharedKey, SharedSecret};
+use crate::clock::ClockSource;
+use crate::compression::Compression;
+use crate::encoding::Encoding;
+use crate::generator::{OverflowPolicy, ValueType, Waveform};
+use crate::protocol::{PingReplyFormat, RESERVED_META_KEYS};
+use crate::target::{TargetAddress, UpdateTargetMode};
+use crate::ticker::MissedTickBehavior;
+use crate::transport::Transport;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+};
+use tracing::warn;
+
+/// Defines a config struct and lists the names and types of its fields in `FIELDS`, for
+/// [`describe_keys`]
+macro_rules! config_struct {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                pub $field:ident: $type:ty,
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        pub struct $name {
+            $(
+                $(#[$field_meta])*
+                pub $field: $type,
+            )*
+        }
+
+        impl $name {
+            /// The name and Rust type of every field
+            pub const FIELDS: &'static [(&'static str, &'static str)] =
+                &[$((stringify!($field), stringify!($type))),*];
+        }
+    };
+}
+
+config_struct! {
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct Config {
+    pub area: String,
+    pub flow_name: String,
+    pub target_ip: String,
+    pub target_port: u16,
+    /// additional targets that receive every data packet as well
+    pub targets: Option<Vec<TargetAddress>>,
+    pub outbound_port_data: u16,
+    pub outbound_port_acks: Option<u16>,
+    pub bind_address: Option<IpAddr>,
+    /// sets `SO_REUSEADDR` on the UDP sockets, so a restarted node can bind its ports right away
+    pub reuse_address: Option<bool>,
+    /// `SO_SNDBUF` of the UDP sockets, the OS default if unset
+    pub socket_send_buffer: Option<usize>,
+    /// `SO_RCVBUF` of the UDP sockets, the OS default if unset
+    pub socket_recv_buffer: Option<usize>,
+    pub inbound_port: u16,
+    /// ms, or a duration like `"1s"`
+    #[serde(default, deserialize_with = "deserialize_duration_ms")]
+    pub interval: Option<u64>,
+    pub jitter_ms: Option<u64>,
+    pub startup_delay_ms: Option<u64>,
+    pub missed_tick_behavior: Option<MissedTickBehavior>,
+    /// ms, or a duration like `"50ms"`
+    #[serde(default, deserialize_with = "deserialize_duration_ms")]
+    pub inbound_poll_interval: Option<u64>,
+    pub inbound_buffer_size: Option<usize>,
+    pub waveform: Option<Waveform>,
+    pub amplitude: Option<f64>,
+    pub offset: Option<f64>,
+    pub period_ms: Option<u64>,
+    pub noise_stddev: Option<f64>,
+    pub value_type: Option<ValueType>,
+    /// lowest value, e.g. of an ADC, replacing `offset` and `amplitude` together with `value_max`
+    pub value_min: Option<f64>,
+    pub value_max: Option<f64>,
+    pub calibration_scale: Option<f64>,
+    pub calibration_offset: Option<f64>,
+    pub calibration_round: Option<bool>,
+    pub include_raw: Option<bool>,
+    /// several sensors with their own generators, instead of the single top-level one
+    pub channels: Option<Vec<ChannelConfig>>,
+    /// send the latest values of all channels together every `interval`
+    pub aggregate: Option<bool>,
+    pub seed: Option<u64>,
+    pub data_queue_size: Option<usize>,
+    pub overflow_policy: Option<OverflowPolicy>,
+    pub send_max_retries: Option<u32>,
+    pub simulate_loss_percent: Option<f64>,
+    pub simulate_dup_percent: Option<f64>,
+    pub simulate_delay_ms: Option<u64>,
+    pub replay_file: Option<String>,
+    pub replay_loop: Option<bool>,
+    pub timestamp_data: Option<bool>,
+    /// where the timestamps of data packets, ping replies, the status and captures come from
+    pub clock_source: Option<ClockSource>,
+    pub checksum: Option<bool>,
+    pub emit_message_as_number: Option<bool>,
+    /// static tags added to the `meta` of every data packet
+    pub meta_extra: Option<BTreeMap<String, serde_json::Value>>,
+    pub encoding: Option<Encoding>,
+    pub compression: Option<Compression>,
+    pub transport: Option<Transport>,
+    /// where data packets are POSTed with `transport: http` (`http://<host>[:<port>][/<path>]`)
+    pub http_url: Option<String>,
+    /// added to every request with `transport: http`
+    pub http_headers: Option<BTreeMap<String, String>>,
+    pub http_timeout_ms: Option<u64>,
+    /// encrypts the DTLS sessions with `transport: dtls` instead of certificates
+    pub dtls_psk: Option<PresharedKey>,
+    pub dtls_psk_identity: Option<String>,
+    /// CA certificates (PEM) to check the targets' certificates against, instead of the system's
+    pub dtls_ca_file: Option<String>,
+    /// the name the targets' certificates must be issued for, instead of their IP addresses
+    pub dtls_server_name: Option<String>,
+    /// client certificate (PEM) presented to the targets, with its key in `dtls_key_file`
+    pub dtls_cert_file: Option<String>,
+    pub dtls_key_file: Option<String>,
+    /// also publish data packets to this MQTT broker (`mqtt://<host>[:<port>]`)
+    pub mqtt_url: Option<String>,
+    pub mqtt_topic: Option<String>,
+    pub mqtt_qos: Option<u8>,
+    /// only publish to MQTT, without sending to the targets
+    pub mqtt_only: Option<bool>,
+    pub batch_size: Option<usize>,
+    pub batch_timeout_ms: Option<u64>,
+    /// batches larger than this are split into several packets
+    pub max_payload_bytes: Option<usize>,
+    pub target_resolve_interval: Option<u64>,
+    pub state_file: Option<String>,
+    /// identifies the node in data packets and replies, generated (and kept in the state file)
+    /// if unset
+    pub node_id: Option<String>,
+    /// appends every message sent and received to this file, as JSON lines
+    pub capture_file: Option<String>,
+    pub capture_max_bytes: Option<u64>,
+    pub ack_repeat: Option<u32>,
+    pub ack_repeat_interval_ms: Option<u64>,
+    pub ack_confirm_max_attempts: Option<u32>,
+    pub quiet: Option<bool>,
+    /// print data packets instead of sending them, and ignore control messages
+    pub dry_run: Option<bool>,
+    /// reject suspicious configs (e.g. sockets sharing a port) instead of warning about them
+    pub strict: Option<bool>,
+    pub metrics_port: Option<u16>,
+    /// serves `/healthz` and `/readyz` for orchestrators
+    pub health_port: Option<u16>,
+    pub readiness_failure_ms: Option<u64>,
+    /// how often a panicked task is restarted before the node shuts down
+    pub task_max_restarts: Option<u32>,
+    pub ping_interval_ms: Option<u64>,
+    pub ping_report_every: Option<u32>,
+    pub heartbeat_interval_ms: Option<u64>,
+    pub controller_allowlist: Option<Vec<IpRange>>,
+    /// inbound messages per second accepted from every source IP
+    pub inbound_rate_limit: Option<u32>,
+    pub allowlist_pings: Option<bool>,
+    pub ping_reply_format: Option<PingReplyFormat>,
+    pub shared_secret: Option<SharedSecret>,
+    pub update_target_mode: Option<UpdateTargetMode>,
+    /// backup targets, in order of preference, used when the primary target stops answering pings
+    pub target_failover: Option<Vec<TargetAddress>>,
+    pub failover_check_interval_ms: Option<u64>,
+    pub failover_timeout_ms: Option<u64>,
+    pub failover_max_rtt_ms: Option<u64>,
+    pub failback_stable_ms: Option<u64>,
+}
+}
+
+config_struct! {
+/// A sensor of a multi-channel node. Unset options fall back to the top-level ones.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct ChannelConfig {
+    pub name: String,
+    #[serde(default, deserialize_with = "deserialize_duration_ms")]
+    pub interval: Option<u64>,
+    pub waveform: Option<Waveform>,
+    pub amplitude: Option<f64>,
+    pub offset: Option<f64>,
+    pub period_ms: Option<u64>,
+    pub noise_stddev: Option<f64>,
+    pub value_type: Option<ValueType>,
+    pub value_min: Option<f64>,
+    pub value_max: Option<f64>,
+}
+}
+
+/// A semantic problem with a [`Config`] that deserialized fine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// a text field that must not be empty is empty
+    Empty { field: &'static str },
+    /// a numeric field that must be positive is zero
+    Zero { field: &'static str },
+    /// a percentage is outside of 0 to 100
+    Percent { field: &'static str },
+    /// a numeric field that must not be negative is
+    Negative { field: &'static str },
+    /// two sockets are configured to use the same port
+    SamePort {
+        field: &'static str,
+        other: &'static str,
+        port: u16,
+    },
+    /// two list entries have the same name
+    Duplicate { field: &'static str, name: String },
+    /// the value of a field isn't supported together with another option
+    Conflict {
+        field: &'static str,
+        other: &'static str,
+    },
+    /// a map contains a key the node sets itself
+    Reserved { field: &'static str, key: String },
+    /// a key or value of a map contains a line break, which would end it early in the output
+    LineBreak { field: &'static str, key: String },
+    /// a field can only be set together with another one
+    Requires {
+        field: &'static str,
+        other: &'static str,
+    },
+    /// the lower bound of a range isn't below the upper one
+    Range {
+        field: &'static str,
+        other: &'static str,
+    },
+    /// a numeric field is larger than allowed
+    TooLarge { field: &'static str, max: u64 },
+    /// an option needs a cargo feature this binary was built without
+    MissingFeature {
+        field: &'static str,
+        feature: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Empty { field } => write!(f, "`{}` must not be empty", field),
+            ConfigError::Zero { field } => write!(f, "`{}` must be greater than 0", field),
+            ConfigError::Percent { field } => write!(f, "`{}` must be between 0 and 100", field),
+            ConfigError::Negative { field } => write!(f, "`{}` must not be negative", field),
+            ConfigError::SamePort { field, other, port } => {
+                write!(f, "`{}` and `{}` use the same port {}", field, other, port)
+            }
+            ConfigError::Duplicate { field, name } => {
+                write!(f, "`{}` contains `{}` more than once", field, name)
+            }
+            ConfigError::Conflict { field, other } => {
+                write!(f, "this `{}` can't be combined with `{}`", field, other)
+            }
+            ConfigError::Requires { field, other } => {
+                write!(f, "`{}` can only be set together with `{}`", field, other)
+            }
+            ConfigError::Range { field, other } => {
+                write!(f, "`{}` must be less than `{}`", field, other)
+            }
+            ConfigError::TooLarge { field, max } => {
+                write!(f, "`{}` must be at most {}", field, max)
+            }
+            ConfigError::MissingFeature { field, feature } => write!(
+                f,
+                "`{}` requires building with the `{}` feature",
+                field, feature
+            ),
+            ConfigError::Reserved { field, key } => {
+                write!(
+                    f,
+                    "`{}` can't contain `{}`, the node sets it itself",
+                    field, key
+                )
+            }
+            ConfigError::LineBreak { field, key } => {
+                write!(f, "`{}` entry `{}` contains a line break", field, key)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Reads a duration as milliseconds, either a bare number of them or a number with one of the
+/// units `ms`, `s`, `m` or `h`, e.g. `500ms` or `2m`
+pub fn parse_duration_ms(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let invalid = || {
+        format!(
+            "invalid duration `{}`, expected milliseconds or a number with a unit like `500ms`, `1s`, `2m` or `1h`",
+            s
+        )
+    };
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_start);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let factor = match unit.trim_start() {
+        "" | "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        _ => return Err(invalid()),
+    };
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| format!("duration `{}` is too long", s))
+}
+
+/// Deserializes an optional duration in milliseconds from a number, or from a string as read by
+/// [`parse_duration_ms`]
+fn deserialize_duration_ms<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Duration {
+        Millis(u64),
+        Text(String),
+    }
+
+    match <Option<Duration> as serde::Deserialize>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Duration::Millis(millis)) => Ok(Some(millis)),
+        Some(Duration::Text(text)) => parse_duration_ms(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+impl Config {
+    /// Fills every unset option that has a default with it
+    pub fn with_defaults(mut self) -> Self {
+        self.outbound_port_acks = Some(self.outbound_port_acks.unwrap_or(0));
+        self.bind_address = Some(
+            self.bind_address
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        );
+        self.reuse_address = Some(self.reuse_address.unwrap_or(false));
+        self.interval = Some(self.interval.unwrap_or(1000));
+        self.jitter_ms = Some(self.jitter_ms.unwrap_or(0));
+        self.startup_delay_ms = Some(self.startup_delay_ms.unwrap_or(0));
+        self.missed_tick_behavior = Some(self.missed_tick_behavior.unwrap_or_default());
+        self.inbound_poll_interval = Some(self.inbound_poll_interval.unwrap_or(10));
+        self.inbound_buffer_size = Some(self.inbound_buffer_size.unwrap_or(1024));
+        self.waveform = Some(self.waveform.unwrap_or_default());
+        self.amplitude = Some(self.amplitude.unwrap_or(32767.0));
+        self.offset = Some(self.offset.unwrap_or(32768.0));
+        self.period_ms = Some(self.period_ms.unwrap_or(10000));
+        self.noise_stddev = Some(self.noise_stddev.unwrap_or(0.0));
+        self.value_type = Some(self.value_type.unwrap_or_default());
+        self.calibration_scale = Some(self.calibration_scale.unwrap_or(1.0));
+        self.calibration_offset = Some(self.calibration_offset.unwrap_or(0.0));
+        self.calibration_round = Some(self.calibration_round.unwrap_or(false));
+        self.include_raw = Some(self.include_raw.unwrap_or(false));
+        self.aggregate = Some(self.aggregate.unwrap_or(false));
+        self.data_queue_size = Some(self.data_queue_size.unwrap_or(16));
+        self.overflow_policy = Some(self.overflow_policy.unwrap_or_default());
+        self.send_max_retries = Some(self.send_max_retries.unwrap_or(5));
+        self.simulate_loss_percent = Some(self.simulate_loss_percent.unwrap_or(0.0));
+        self.simulate_dup_percent = Some(self.simulate_dup_percent.unwrap_or(0.0));
+        self.simulate_delay_ms = Some(self.simulate_delay_ms.unwrap_or(0));
+        self.replay_loop = Some(self.replay_loop.unwrap_or(false));
+        self.timestamp_data = Some(self.timestamp_data.unwrap_or(true));
+        self.clock_source = Some(self.clock_source.unwrap_or_default());
+        self.checksum = Some(self.checksum.unwrap_or(false));
+        self.emit_message_as_number = Some(self.emit_message_as_number.unwrap_or(false));
+        self.encoding = Some(self.encoding.unwrap_or_default());
+        self.compression = Some(self.compression.unwrap_or_default());
+        self.transport = Some(self.transport.unwrap_or_default());
+        self.http_timeout_ms = Some(self.http_timeout_ms.unwrap_or(5000));
+        self.dtls_psk_identity = Some(
+            self.dtls_psk_identity
+                .unwrap_or_else(|| self.flow_name.clone()),
+        );
+        self.mqtt_topic = Some(
+            self.mqtt_topic
+                .unwrap_or_else(|| "vin/{area}/{flow}".to_string()),
+        );
+        self.mqtt_qos = Some(self.mqtt_qos.unwrap_or(0));
+        self.mqtt_only = Some(self.mqtt_only.unwrap_or(false));
+        self.batch_size = Some(self.batch_size.unwrap_or(1));
+        self.batch_timeout_ms = Some(self.batch_timeout_ms.unwrap_or(1000));
+        self.capture_max_bytes = Some(self.capture_max_bytes.unwrap_or(100_000_000));
+        self.ack_repeat = Some(self.ack_repeat.unwrap_or(10));
+        self.ack_repeat_interval_ms = Some(self.ack_repeat_interval_ms.unwrap_or(0));
+        self.ack_confirm_max_attempts = Some(self.ack_confirm_max_attempts.unwrap_or(10));
+        self.quiet = Some(self.quiet.unwrap_or(false));
+        self.dry_run = Some(self.dry_run.unwrap_or(false));
+        self.strict = Some(self.strict.unwrap_or(false));
+        self.ping_report_every = Some(self.ping_report_every.unwrap_or(10));
+        self.readiness_failure_ms = Some(self.readiness_failure_ms.unwrap_or(30000));
+        self.task_max_restarts = Some(self.task_max_restarts.unwrap_or(5));
+        self.allowlist_pings = Some(self.allowlist_pings.unwrap_or(false));
+        self.ping_reply_format = Some(self.ping_reply_format.unwrap_or_default());
+        self.update_target_mode = Some(self.update_target_mode.unwrap_or_default());
+        self.failover_check_interval_ms = Some(self.failover_check_interval_ms.unwrap_or(1000));
+        self.failover_timeout_ms = Some(self.failover_timeout_ms.unwrap_or(3000));
+        self.failback_stable_ms = Some(self.failback_stable_ms.unwrap_or(10000));
+        self
+    }
+
+    /// Checks the config for values that would only fail (or misbehave) at runtime
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.area.is_empty() {
+            return Err(ConfigError::Empty { field: "area" });
+        }
+        if self.flow_name.is_empty() {
+            return Err(ConfigError::Empty { field: "flow_name" });
+        }
+        if self.target_ip.is_empty() {
+            return Err(ConfigError::Empty { field: "target_ip" });
+        }
+        if self.node_id.as_deref() == Some("") {
+            return Err(ConfigError::Empty { field: "node_id" });
+        }
+        if self.target_port == 0 {
+            return Err(ConfigError::Zero {
+                field: "target_port",
+            });
+        }
+        // tokio panics on zero-length intervals
+        if self.interval == Some(0) {
+            return Err(ConfigError::Zero { field: "interval" });
+        }
+        if self.inbound_poll_interval == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "inbound_poll_interval",
+            });
+        }
+        if self.target_resolve_interval == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "target_resolve_interval",
+            });
+        }
+        if self.capture_file.as_deref() == Some("") {
+            return Err(ConfigError::Empty {
+                field: "capture_file",
+            });
+        }
+        if self.capture_max_bytes == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "capture_max_bytes",
+            });
+        }
+        if self.readiness_failure_ms == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "readiness_failure_ms",
+            });
+        }
+        if let (Some(health_port), Some(metrics_port)) = (self.health_port, self.metrics_port) {
+            // both are TCP ports, so binding the second one would fail
+            if health_port != 0 && health_port == metrics_port {
+                return Err(ConfigError::SamePort {
+                    field: "health_port",
+                    other: "metrics_port",
+                    port: health_port,
+                });
+            }
+        }
+        if self.inbound_rate_limit == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "inbound_rate_limit",
+            });
+        }
+        if self.ping_interval_ms == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "ping_interval_ms",
+            });
+        }
+        if self.heartbeat_interval_ms == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "heartbeat_interval_ms",
+            });
+        }
+        if self.ping_report_every == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "ping_report_every",
+            });
+        }
+        if self.failover_check_interval_ms == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "failover_check_interval_ms",
+            });
+        }
+        if self.failover_timeout_ms == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "failover_timeout_ms",
+            });
+        }
+        // tokio panics on zero-capacity channels
+        if self.data_queue_size == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "data_queue_size",
+            });
+        }
+        // the binary layout has a fixed 2-byte value
+        let channels = self.channels.iter().flatten();
+        if self.encoding == Some(Encoding::Binary)
+            && self
+                .value_type
+                .into_iter()
+                .chain(channels.clone().filter_map(|channel| channel.value_type))
+                .any(|value_type| value_type != ValueType::U16)
+        {
+            return Err(ConfigError::Conflict {
+                field: "value_type",
+                other: "encoding: binary",
+            });
+        }
+        // calibrated values are floats
+        for (field, calibrated) in [
+            (
+                "calibration_scale",
+                self.calibration_scale.is_some_and(|scale| scale != 1.0),
+            ),
+            (
+                "calibration_offset",
+                self.calibration_offset.is_some_and(|offset| offset != 0.0),
+            ),
+        ] {
+            if calibrated && self.encoding == Some(Encoding::Binary) {
+                return Err(ConfigError::Conflict {
+                    field,
+                    other: "encoding: binary",
+                });
+            }
+        }
+        if self.batch_size == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "batch_size",
+            });
+        }
+        if self.max_payload_bytes == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "max_payload_bytes",
+            });
+        }
+        // the largest payload of a UDP datagram
+        if self.max_payload_bytes.is_some_and(|max| max > 65507) {
+            return Err(ConfigError::TooLarge {
+                field: "max_payload_bytes",
+                max: 65507,
+            });
+        }
+        for (field, size) in [
+            ("socket_send_buffer", self.socket_send_buffer),
+            ("socket_recv_buffer", self.socket_recv_buffer),
+        ] {
+            if size == Some(0) {
+                return Err(ConfigError::Zero { field });
+            }
+        }
+        if self.inbound_buffer_size == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "inbound_buffer_size",
+            });
+        }
+
+        if self
+            .noise_stddev
+            .into_iter()
+            .chain(channels.clone().filter_map(|channel| channel.noise_stddev))
+            .any(|noise_stddev| noise_stddev.is_nan() || noise_stddev < 0.0)
+        {
+            return Err(ConfigError::Negative {
+                field: "noise_stddev",
+            });
+        }
+        // channels fall back to the top-level bounds one by one
+        let ranges = channels
+            .clone()
+            .map(|channel| {
+                (
+                    channel.value_min.or(self.value_min),
+                    channel.value_max.or(self.value_max),
+                )
+            })
+            .chain([(self.value_min, self.value_max)]);
+        for range in ranges {
+            match range {
+                (Some(_), None) => {
+                    return Err(ConfigError::Requires {
+                        field: "value_min",
+                        other: "value_max",
+                    })
+                }
+                (None, Some(_)) => {
+                    return Err(ConfigError::Requires {
+                        field: "value_max",
+                        other: "value_min",
+                    })
+                }
+                (Some(min), Some(max)) if min.is_nan() || max.is_nan() || min >= max => {
+                    return Err(ConfigError::Range {
+                        field: "value_min",
+                        other: "value_max",
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        if self.transport == Some(Transport::Http) && self.http_url.is_none() {
+            return Err(ConfigError::Empty { field: "http_url" });
+        }
+        // HTTP APIs expect a `Content-Encoding` instead
+        if self.transport == Some(Transport::Http)
+            && self
+                .compression
+                .is_some_and(|compression| compression != Compression::None)
+        {
+            return Err(ConfigError::Conflict {
+                field: "compression",
+                other: "transport: http",
+            });
+        }
+        // they would end the header early and start one of their own
+        if let Some((name, _)) = self
+            .http_headers
+            .iter()
+            .flatten()
+            .find(|(name, value)| [name, value].iter().any(|text| text.contains(['\r', '\n'])))
+        {
+            return Err(ConfigError::LineBreak {
+                field: "http_headers",
+                key: name.escape_debug().to_string(),
+            });
+        }
+        if self.http_timeout_ms == Some(0) {
+            return Err(ConfigError::Zero {
+                field: "http_timeout_ms",
+            });
+        }
+
+        if self.transport == Some(Transport::Dtls) && !cfg!(feature = "dtls") {
+            return Err(ConfigError::MissingFeature {
+                field: "transport",
+                feature: "dtls",
+            });
+        }
+        if let Some(identity) = &self.dtls_psk_identity {
+            if identity.is_empty() {
+                return Err(ConfigError::Empty {
+                    field: "dtls_psk_identity",
+                });
+            }
+            // OpenSSL's limit
+            if identity.len() > 128 {
+                return Err(ConfigError::TooLarge {
+                    field: "dtls_psk_identity",
+                    max: 128,
+                });
+            }
+        }
+        if self.dtls_server_name.as_deref() == Some("") {
+            return Err(ConfigError::Empty {
+                field: "dtls_server_name",
+            });
+        }
+        // a pre-shared key replaces the certificates
+        if self.dtls_psk.is_some() {
+            for (field, set) in [
+                ("dtls_ca_file", self.dtls_ca_file.is_some()),
+                ("dtls_server_name", self.dtls_server_name.is_some()),
+                ("dtls_cert_file", self.dtls_cert_file.is_some()),
+            ] {
+                if set {
+                    return Err(ConfigError::Conflict {
+                        field,
+                        other: "dtls_psk",
+                    });
+                }
+            }
+        }
+        match (&self.dtls_cert_file, &self.dtls_key_file) {
+            (Some(_), None) => {
+                return Err(ConfigError::Requires {
+                    field: "dtls_cert_file",
+                    other: "dtls_key_file",
+                })
+            }
+            (None, Some(_)) => {
+                return Err(ConfigError::Requires {
+                    field: "dtls_key_file",
+                    other: "dtls_cert_file",
+                })
+            }
+            _ => {}
+        }
+
+        if self.mqtt_url.is_some() && !cfg!(feature = "mqtt") {
+            return Err(ConfigError::MissingFeature {
+                field: "mqtt_url",
+                feature: "mqtt",
+            });
+        }
+        if self.mqtt_topic.as_deref() == Some("") {
+            return Err(ConfigError::Empty {
+                field: "mqtt_topic",
+            });
+        }
+        if self.mqtt_qos.is_some_and(|qos| qos > 2) {
+            return Err(ConfigError::TooLarge {
+                field: "mqtt_qos",
+                max: 2,
+            });
+        }
+        if self.mqtt_only == Some(true) && self.mqtt_url.is_none() {
+            return Err(ConfigError::Empty { field: "mqtt_url" });
+        }
+
+        if let Some(meta_extra) = &self.meta_extra {
+            if let Some(key) = meta_extra
+                .keys()
+                .find(|key| RESERVED_META_KEYS.contains(&key.as_str()))
+            {
+                return Err(ConfigError::Reserved {
+                    field: "meta_extra",
+                    key: key.clone(),
+                });
+            }
+            // binary packets don't have a `meta`
+            if !meta_extra.is_empty() && self.encoding == Some(Encoding::Binary) {
+                return Err(ConfigError::Conflict {
+                    field: "meta_extra",
+                    other: "encoding: binary",
+                });
+            }
+        }
+
+        if let Some(channels) = &self.channels {
+            if channels.is_empty() {
+                return Err(ConfigError::Empty { field: "channels" });
+            }
+            // channels generate their values, they can't replay a file
+            if self.replay_file.is_some() {
+                return Err(ConfigError::Conflict {
+                    field: "replay_file",
+                    other: "channels",
+                });
+            }
+            for (i, channel) in channels.iter().enumerate() {
+                if channel.name.is_empty() {
+                    return Err(ConfigError::Empty {
+                        field: "channels.name",
+                    });
+                }
+                if channel.interval == Some(0) {
+                    return Err(ConfigError::Zero {
+                        field: "channels.interval",
+                    });
+                }
+                if channels[..i].iter().any(|other| other.name == channel.name) {
+                    return Err(ConfigError::Duplicate {
+                        field: "channels",
+                        name: channel.name.clone(),
+                    });
+                }
+            }
+        }
+        for (field, percent) in [
+            ("simulate_loss_percent", self.simulate_loss_percent),
+            ("simulate_dup_percent", self.simulate_dup_percent),
+        ] {
+            if percent.is_some_and(|percent| !(0.0..=100.0).contains(&percent)) {
+                return Err(ConfigError::Percent { field });
+            }
+        }
+
+        // port 0 lets the OS pick a free port, so it can't collide
+        let ports = [
+            ("outbound_port_data", self.outbound_port_data),
+            ("outbound_port_acks", self.outbound_port_acks.unwrap_or(0)),
+            ("inbound_port", self.inbound_port),
+        ];
+        for (i, (name, port)) in ports.iter().enumerate() {
+            for (other_name, other_port) in &ports[i + 1..] {
+                if *port != 0 && port == other_port {
+                    let error = ConfigError::SamePort {
+                        field: name,
+                        other: other_name,
+                        port: *port,
+                    };
+                    // some OSes allow it, but the node then receives its own or unrelated traffic
+                    if self.strict == Some(true) {
+                        return Err(error);
+                    }
+                    warn!("{}", error);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Machine-readable description of every config key (name, JSON type, whether it's required and
+/// its default), printed by `--list-config-keys`. Keys of `channels` entries are listed as
+/// `channels.<key>`, their defaults are the top-level values.
+pub fn describe_keys() -> serde_json::Value {
+    // only optional keys have defaults, so the required ones can be anything
+    let defaults: Config = serde_json::from_value(serde_json::json!({
+        "area": "",
+        "flow_name": "",
+        "target_ip": "",
+        "target_port": 0,
+        "outbound_port_data": 0,
+        "inbound_port": 0,
+    }))
+    .expect("all required keys are given");
+    let defaults = serde_json::to_value(defaults.with_defaults()).expect("configs serialize");
+
+    let key = |name: String, ty: &str, default: &serde_json::Value| {
+        let required = !ty.starts_with("Option<");
+        KeySpec {
+            name,
+            ty: json_type(ty),
+            required,
+            default: if required {
+                serde_json::Value::Null
+            } else {
+                default.clone()
+            },
+        }
+    };
+    let keys: Vec<KeySpec> = Config::FIELDS
+        .iter()
+        .map(|(name, ty)| key(name.to_string(), ty, &defaults[name]))
+        .chain(
+            ChannelConfig::FIELDS
+                .iter()
+                .map(|(name, ty)| key(format!("channels.{}", name), ty, &serde_json::Value::Null)),
+        )
+        .collect();
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "keys": keys,
+    })
+}
+
+/// A key of the config file
+#[derive(serde::Serialize, Debug)]
+struct KeySpec {
+    name: String,
+    /// JSON type, e.g. `string`, `integer` or `boolean`
+    #[serde(rename = "type")]
+    ty: &'static str,
+    required: bool,
+    /// `null` if there is none
+    default: serde_json::Value,
+}
+
+/// The JSON type of a value of Rust type `ty` in a config file
+fn json_type(ty: &str) -> &'static str {
+    let ty = ty
+        .strip_prefix("Option<")
+        .and_then(|ty| ty.strip_suffix('>'))
+        .unwrap_or(ty);
+    match ty {
+        "u8" | "u16" | "u32" | "u64" | "usize" => "integer",
+        "f64" => "number",
+        "bool" => "boolean",
+        _ if ty.starts_with("Vec<") => "array",
+        _ if ty.starts_with("BTreeMap<") => "object",
+        // enums, addresses and secrets are written as strings
+        _ => "string",
+    }
+}
+
+/// Loads a config file, picking the format by file extension (YAML if there is none)
+pub fn load_config(path: &str) -> Result<Config, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path)?;
+
+    let extension = Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let config: Config = match extension.as_deref() {
+        None | Some("yaml") | Some("yml") => serde_yaml::from_str(&content)?,
+        Some("toml") => toml::from_str(&content)?,
+        Some("json") => serde_json::from_str(&content)?,
+        Some(extension) => {
+            return Err(format!(
+                "Unknown config file extension '.{}', expected .yaml, .yml, .toml or .json",
+                extension
+            )
+            .into())
+        }
+    };
+
+    Ok(config)
+}
+
+/// Loads every config file (`.yaml`, `.yml`, `.toml` or `.json`) in `dir`, ordered by path, for a
+/// node each. Other files, e.g. a README, and subdirectories are skipped.
+pub fn load_config_dir(dir: &str) -> Result<Vec<(String, Config)>, Box<dyn Error>> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_config = path.extension().is_some_and(|extension| {
+            matches!(
+                extension.to_string_lossy().to_lowercase().as_str(),
+                "yaml" | "yml" | "toml" | "json"
+            )
+        });
+        if is_config && path.is_file() {
+            paths.push(path.to_string_lossy().into_owned());
+        }
+    }
+    if paths.is_empty() {
+        return Err("no .yaml, .yml, .toml or .json files found".into());
+    }
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let config = load_config(&path)
+                .map_err(|e| format!("Couldn't load config from '{}': {}", path, e))?;
+            Ok((path, config))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `content` to a file in the temp dir that is unique to the calling test
+    fn temp_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("vin-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn loads_yaml() {
+        let path = temp_file(
+            "valid.yaml",
+            "
+area: room1
+flow_name: Flow 1
+target_ip: 127.0.0.1
+target_port: 33001
+outbound_port_data: 22001
+inbound_port: 21001
+interval: 500
+targets: [logger.local:6000, \"[fd00::5]:6000\"]
+",
+        );
+        let config = load_config(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.area, "room1");
+        assert_eq!(config.interval, Some(500));
+        assert_eq!(config.inbound_poll_interval, None);
+        let targets = config.targets.unwrap();
+        assert_eq!(targets[0].host, "logger.local");
+        assert_eq!(targets[1].host, "fd00::5");
+    }
+
+    #[test]
+    fn intervals_accept_durations_with_units() {
+        assert_eq!(parse_duration_ms("250"), Ok(250));
+        assert_eq!(parse_duration_ms("500ms"), Ok(500));
+        assert_eq!(parse_duration_ms("1s"), Ok(1000));
+        assert_eq!(parse_duration_ms("2m"), Ok(120_000));
+        assert_eq!(parse_duration_ms(" 1 h "), Ok(3_600_000));
+        for invalid in ["", "s", "1.5s", "-1s", "1d", "10 minutes"] {
+            assert!(parse_duration_ms(invalid).is_err(), "{}", invalid);
+        }
+        assert!(parse_duration_ms("99999999999999999h").is_err());
+
+        let yaml = "
+area: room1
+flow_name: Flow 1
+target_ip: 127.0.0.1
+target_port: 33001
+outbound_port_data: 22001
+inbound_port: 21001
+interval: 10m
+inbound_poll_interval: \"50ms\"
+channels:
+  - name: fast
+    interval: 100
+";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.interval, Some(600_000));
+        assert_eq!(config.inbound_poll_interval, Some(50));
+        assert_eq!(config.channels.unwrap()[0].interval, Some(100));
+
+        let toml = "
+area = \"room1\"
+flow_name = \"Flow 1\"
+target_ip = \"127.0.0.1\"
+target_port = 33001
+outbound_port_data = 22001
+inbound_port = 21001
+interval = 1500
+";
+        let config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(config.interval, Some(1500));
+        assert_eq!(config.inbound_poll_interval, None);
+        assert!(serde_yaml::from_str::<Config>(&yaml.replace("10m", "10 minutes")).is_err());
+    }
+
+    #[test]
+    fn malformed_yaml_is_an_error() {
+        let path = temp_file("malformed.yaml", "area: [room1\ntarget_port: -");
+        let config = load_config(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        assert!(load_config("/nonexistent/vin-config.yaml").is_err());
+    }
+
+    #[test]
+    fn loads_every_config_in_a_directory() {
+        let dir = std::env::temp_dir().join(format!("vin-{}-config-dir", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let node = |flow: &str| {
+            format!(
+                "area: room1\nflow_name: {}\ntarget_ip: 127.0.0.1\ntarget_port: 33001\noutbound_port_data: 0\ninbound_port: 0\n",
+                flow
+            )
+        };
+        std::fs::write(dir.join("b.yml"), node("Flow 2")).unwrap();
+        std::fs::write(dir.join("a.yaml"), node("Flow 1")).unwrap();
+        std::fs::write(dir.join("README.md"), "not a config").unwrap();
+        let configs = load_config_dir(&dir.to_string_lossy());
+
+        std::fs::write(dir.join("c.json"), "{").unwrap();
+        let error = load_config_dir(&dir.to_string_lossy()).err().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let flows: Vec<_> = configs
+            .unwrap()
+            .into_iter()
+            .map(|(_, config)| config.flow_name)
+            .collect();
+        assert_eq!(flows, ["Flow 1", "Flow 2"]);
+        assert!(error.to_string().contains("c.json"));
+        assert!(load_config_dir("/nonexistent/vin-configs").is_err());
+    }
+
+    #[test]
+    fn same_ports_are_only_rejected_when_strict() {
+        let mut config: Config = serde_yaml::from_str(
+            "
+area: room1
+flow_name: Flow 1
+target_ip: 127.0.0.1
+target_port: 33001
+outbound_port_data: 22001
+inbound_port: 22001
+",
+        )
+        .unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.strict = Some(true);
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::SamePort {
+                field: "outbound_port_data",
+                other: "inbound_port",
+                port: 22001,
+            })
+        );
+
+        // port 0 is picked by the OS and can't collide
+        config.outbound_port_data = 0;
+        config.inbound_port = 0;
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn meta_extra_must_not_override_meta() {
+        let mut config: Config = serde_yaml::from_str(
+            "
+area: room1
+flow_name: Flow 1
+target_ip: 127.0.0.1
+target_port: 33001
+outbound_port_data: 22001
+inbound_port: 21001
+meta_extra:
+  site: berlin
+  firmware: 1.2.0
+",
+        )
+        .unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config
+            .meta_extra
+            .as_mut()
+            .unwrap()
+            .insert("execution_area".to_string(), "room2".into());
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Reserved {
+                field: "meta_extra",
+                key: "execution_area".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn http_headers_must_not_contain_line_breaks() {
+        let mut config: Config = serde_yaml::from_str(
+            "
+area: room1
+flow_name: Flow 1
+target_ip: 127.0.0.1
+target_port: 33001
+outbound_port_data: 22001
+inbound_port: 21001
+transport: http
+http_url: http://collector/ingest
+http_headers:
+  Authorization: Bearer 0123
+",
+        )
+        .unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        let headers = config.http_headers.as_mut().unwrap();
+        headers.insert("X-Area".to_string(), "room1\r\nX-Injected: 1".to_string());
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::LineBreak {
+                field: "http_headers",
+                key: "X-Area".to_string(),
+            })
+        );
+        let headers = config.http_headers.as_mut().unwrap();
+        headers.remove("X-Area");
+        headers.insert("X-Area\n".to_string(), "room1".to_string());
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::LineBreak {
+                field: "http_headers",
+                key: "X-Area\\n".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn lists_every_config_key() {
+        let description = describe_keys();
+        let keys = description["keys"].as_array().unwrap();
+        let key = |name: &str| {
+            keys.iter()
+                .find(|key| key["name"] == name)
+                .unwrap_or_else(|| panic!("`{}` is missing", name))
+        };
+        assert_eq!(
+            *key("area"),
+            serde_json::json!({"name": "area", "type": "string", "required": true, "default": null})
+        );
+        assert_eq!(key("interval")["type"], "integer");
+        assert_eq!(key("interval")["default"], 1000);
+        assert_eq!(key("noise_stddev")["type"], "number");
+        assert_eq!(key("dry_run")["type"], "boolean");
+        assert_eq!(key("encoding")["default"], "json");
+        assert_eq!(key("meta_extra")["type"], "object");
+        assert_eq!(key("targets")["type"], "array");
+        assert_eq!(key("metrics_port")["default"], serde_json::Value::Null);
+        assert_eq!(key("channels.name")["required"], true);
+        assert_eq!(key("channels.interval")["required"], false);
+        assert_eq!(
+            keys.len(),
+            Config::FIELDS.len() + ChannelConfig::FIELDS.len()
+        );
+    }
+
+    #[test]
+    fn value_range_must_be_complete_and_ordered() {
+        let mut config: Config = serde_yaml::from_str(
+            "
+area: room1
+flow_name: Flow 1
+target_ip: 127.0.0.1
+target_port: 33001
+outbound_port_data: 22001
+inbound_port: 21001
+value_min: 0
+value_max: 4095
+channels:
+  - name: temperature
+  - name: humidity
+    value_max: 1023
+",
+        )
+        .unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.channels.as_mut().unwrap()[1].value_max = Some(0.0);
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Range {
+                field: "value_min",
+                other: "value_max",
+            })
+        );
+
+        config.channels = None;
+        config.value_max = None;
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Requires {
+                field: "value_min",
+                other: "value_max",
+            })
+        );
+    }
+
+    #[test]
+    fn dtls_psk_replaces_certificates() {
+        let mut config: Config = serde_yaml::from_str(
+            "
+area: room1
+flow_name: Flow 1
+target_ip: 127.0.0.1
+target_port: 33001
+outbound_port_data: 22001
+inbound_port: 21001
+dtls_psk: c0ffee
+dtls_ca_file: ca.pem
+",
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Conflict {
+                field: "dtls_ca_file",
+                other: "dtls_psk",
+            })
+        );
+
+        config.dtls_psk = None;
+        config.dtls_cert_file = Some("node.pem".to_string());
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Requires {
+                field: "dtls_cert_file",
+                other: "dtls_key_file",
+            })
+        );
+
+        config.dtls_key_file = Some("node.key".to_string());
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(
+            config.with_defaults().dtls_psk_identity.as_deref(),
+            Some("Flow 1")
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use crate::config::Config;
+use crate::sink::OutputSink;
+use crate::transport::Connection;
+use futures::future::BoxFuture;
+use openssl::error::ErrorStack;
+use openssl::ssl::{Ssl, SslContext, SslFiletype, SslMethod, SslOptions, SslVerifyMode};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tokio_openssl::SslStream;
+use tracing::warn;
+
+/// Handshake messages larger than this are fragmented. OpenSSL can't ask the socket for the path
+/// MTU, so this is the usual Ethernet MTU minus the IP and UDP headers.
+const MTU: u32 = 1472;
+
+/// A DTLS session per target, each (re)established when a packet is sent to it. Every packet is
+/// sent as a single record, so it still arrives as a single datagram.
+pub(crate) struct DtlsSink {
+    context: SslContext,
+    /// only set when the targets authenticate with certificates
+    verify: Option<Verify>,
+    bind_address: IpAddr,
+    sessions: std::sync::Mutex<HashMap<SocketAddr, Arc<Mutex<Connection<Session>>>>>,
+}
+
+/// What the targets' certificates are issued for
+enum Verify {
+    ServerName(String),
+    Address,
+}
+
+type Session = SslStream<Datagrams>;
+
+impl DtlsSink {
+    /// Loads the key or certificates configured for `transport: dtls`, so broken files fail the
+    /// start instead of every packet
+    pub fn new(config: &Config) -> Result<Self, ErrorStack> {
+        let mut context = SslContext::builder(SslMethod::dtls())?;
+        context.set_options(SslOptions::NO_QUERY_MTU);
+        let verify = match &config.dtls_psk {
+            Some(psk) => {
+                let psk = psk.clone();
+                let mut identity = config.dtls_psk_identity.clone().unwrap().into_bytes();
+                identity.push(0);
+                context.set_cipher_list("PSK")?;
+                // both fit, the lengths are checked by `Config::validate`
+                context.set_psk_client_callback(move |_, _hint, identity_buf, psk_buf| {
+                    identity_buf[..identity.len()].copy_from_slice(&identity);
+                    psk_buf[..psk.as_bytes().len()].copy_from_slice(psk.as_bytes());
+                    Ok(psk.as_bytes().len())
+                });
+                None
+            }
+            None => {
+                context.set_verify(SslVerifyMode::PEER);
+                match &config.dtls_ca_file {
+                    Some(path) => context.set_ca_file(path)?,
+                    None => context.set_default_verify_paths()?,
+                }
+                if let (Some(cert), Some(key)) = (&config.dtls_cert_file, &config.dtls_key_file) {
+                    context.set_certificate_chain_file(cert)?;
+                    context.set_private_key_file(key, SslFiletype::PEM)?;
+                    context.check_private_key()?;
+                }
+                Some(match &config.dtls_server_name {
+                    Some(name) => Verify::ServerName(name.clone()),
+                    None => Verify::Address,
+                })
+            }
+        };
+        Ok(DtlsSink {
+            context: context.build(),
+            verify,
+            bind_address: config.bind_address.unwrap(),
+            sessions: Default::default(),
+        })
+    }
+
+    /// Sends a packet to `target`, with a handshake first if needed
+    async fn send_record(&self, payload: &[u8], target: SocketAddr) -> io::Result<()> {
+        let connection = self
+            .sessions
+            .lock()
+            .unwrap()
+            .entry(target)
+            .or_default()
+            .clone();
+        let mut connection = connection.lock().await;
+        let session = connection.connect(target, self.handshake(target)).await?;
+        // a single write, so the packet isn't split up into several records
+        let result = session.write_all(payload).await;
+        if let Err(e) = &result {
+            warn!(%target, error = %e, "Lost data connection");
+            connection.stream = None;
+        }
+        result
+    }
+
+    /// Opens a session from a new socket on `bind_address`
+    async fn handshake(&self, target: SocketAddr) -> io::Result<Session> {
+        let bind_address = match (self.bind_address, target) {
+            (address, _) if !address.is_unspecified() => address,
+            (_, SocketAddr::V4(_)) => Ipv4Addr::UNSPECIFIED.into(),
+            (_, SocketAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(bind_address, 0)).await?;
+        socket.connect(target).await?;
+
+        let mut ssl = Ssl::new(&self.context)?;
+        ssl.set_mtu(MTU)?;
+        match &self.verify {
+            Some(Verify::ServerName(name)) => {
+                ssl.set_hostname(name)?;
+                ssl.param_mut().set_host(name)?;
+            }
+            Some(Verify::Address) => ssl.param_mut().set_ip(target.ip())?,
+            None => {}
+        }
+        let mut session = SslStream::new(ssl, Datagrams(socket))?;
+        Pin::new(&mut session)
+            .connect()
+            .await
+            .map_err(|e| match e.into_io_error() {
+                Ok(e) => e,
+                Err(e) => io::Error::new(io::ErrorKind::ConnectionRefused, e.to_string()),
+            })?;
+        Ok(session)
+    }
+}
+
+impl OutputSink for DtlsSink {
+    /// Closes the sessions with addresses that aren't targets anymore
+    fn destinations(&self, targets: &[SocketAddr]) -> Vec<SocketAddr> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|address, _| targets.contains(address));
+        targets.to_vec()
+    }
+
+    fn send<'a>(&'a self, payload: &'a [u8], target: SocketAddr) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.send_record(payload, target))
+    }
+}
+
+/// A connected UDP socket as the stream OpenSSL reads records from and writes them to, one
+/// datagram per read or write
+struct Datagrams(UdpSocket);
+
+impl AsyncRead for Datagrams {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.0.poll_recv(cx, buf)
+    }
+}
+
+impl AsyncWrite for Datagrams {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.poll_send(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
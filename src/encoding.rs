@@ -0,0 +1,152 @@
+use crate::generator::{DataSample, SampleValue};
+use serde_json::{json, Value};
+
+/// How data packets are put on the wire. Control messages and ACKs are always JSON.
+#[derive(
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    // the JSON object described by `--dump-protocol`
+    #[default]
+    Json,
+    // the fixed layout of `encode_binary`
+    Binary,
+    // the same object as `json`, serialized as MessagePack
+    Msgpack,
+}
+
+/// Version byte at the start of every binary data packet, bumped whenever the layout changes
+pub const BINARY_VERSION: u8 = 1;
+/// Size of a binary data packet in bytes
+pub const BINARY_PACKET_SIZE: usize = 19;
+
+/// Encodes a sample as a binary data packet (all integers big-endian):
+///
+/// | offset | size | field                                         |
+/// |--------|------|-----------------------------------------------|
+/// | 0      | 1    | version, [`BINARY_VERSION`]                   |
+/// | 1      | 8    | seq                                           |
+/// | 9      | 8    | send time (µs since the UNIX epoch), 0 if off |
+/// | 17     | 2    | value                                         |
+pub fn encode_binary(sample: &DataSample, sent_at_micros: u64) -> [u8; BINARY_PACKET_SIZE] {
+    let mut packet = [0; BINARY_PACKET_SIZE];
+    packet[0] = BINARY_VERSION;
+    packet[1..9].copy_from_slice(&sample.seq.to_be_bytes());
+    packet[9..17].copy_from_slice(&sent_at_micros.to_be_bytes());
+    let SampleValue::U16(value) = sample.value else {
+        unreachable!("binary encoding is only allowed for u16 values");
+    };
+    packet[17..19].copy_from_slice(&value.to_be_bytes());
+    packet
+}
+
+/// Serializes a JSON data packet as a MessagePack map with the same keys and values
+pub fn encode_msgpack(packet: &Value) -> Vec<u8> {
+    rmp_serde::to_vec_named(packet).expect("JSON values can always be encoded as MessagePack")
+}
+
+/// Machine-readable description of the binary layout, included in `--dump-protocol`
+pub fn describe_binary() -> Value {
+    json!({
+        "version": BINARY_VERSION,
+        "size": BINARY_PACKET_SIZE,
+        "byte_order": "big-endian",
+        "fields": [
+            {"name": "version", "offset": 0, "type": "u8"},
+            {"name": "seq", "offset": 1, "type": "u64"},
+            {"name": "sent_at_micros", "offset": 9, "type": "u64"},
+            {"name": "value", "offset": 17, "type": "u16"},
+        ],
+    })
+}
+
+/// A binary data packet as decoded by a receiver
+#[cfg(feature = "decoder")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BinarySample {
+    pub seq: u64,
+    /// 0 if the node doesn't send timestamps
+    pub sent_at_micros: u64,
+    pub value: u16,
+}
+
+/// Decodes a binary data packet, for receivers written in Rust
+#[cfg(feature = "decoder")]
+pub fn decode_binary(packet: &[u8]) -> Result<BinarySample, String> {
+    let packet: &[u8; BINARY_PACKET_SIZE] = packet.try_into().map_err(|_| {
+        format!(
+            "expected {} bytes, got {}",
+            BINARY_PACKET_SIZE,
+            packet.len()
+        )
+    })?;
+    if packet[0] != BINARY_VERSION {
+        return Err(format!("unsupported version {}", packet[0]));
+    }
+    Ok(BinarySample {
+        seq: u64::from_be_bytes(packet[1..9].try_into().unwrap()),
+        sent_at_micros: u64::from_be_bytes(packet[9..17].try_into().unwrap()),
+        value: u16::from_be_bytes(packet[17..19].try_into().unwrap()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: DataSample = DataSample {
+        seq: 0x0102030405060708,
+        channel: None,
+        value: SampleValue::U16(0xabcd),
+        raw: None,
+        generated_at_micros: 1,
+    };
+
+    #[test]
+    fn binary_layout() {
+        let packet = encode_binary(&SAMPLE, 0x1112131415161718);
+        assert_eq!(
+            packet,
+            [
+                1, 1, 2, 3, 4, 5, 6, 7, 8, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0xab,
+                0xcd
+            ]
+        );
+    }
+
+    #[test]
+    fn msgpack_decodes_to_the_json_packet() {
+        let mut packet = crate::protocol::data_packet(&SAMPLE, "Flow 1", "room1", true, true);
+        packet["sent_at_micros"] = 2.into();
+        let decoded: Value = rmp_serde::from_slice(&encode_msgpack(&packet)).unwrap();
+        assert_eq!(decoded, packet);
+    }
+
+    #[cfg(feature = "decoder")]
+    #[test]
+    fn binary_round_trip() {
+        let packet = encode_binary(&SAMPLE, 42);
+        assert_eq!(
+            decode_binary(&packet),
+            Ok(BinarySample {
+                seq: SAMPLE.seq,
+                sent_at_micros: 42,
+                value: 0xabcd,
+            })
+        );
+        assert!(decode_binary(&packet[1..]).is_err());
+
+        let mut packet = packet;
+        packet[0] = 2;
+        assert!(decode_binary(&packet).is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use crate::metrics::Metrics;
+use crate::ping::reply_ip;
+use crate::receive_errors::ReceiveErrors;
+use crate::target::{Target, Targets};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, watch};
+use tokio::time::{self, Instant};
+use tracing::{debug, info, warn};
+
+/// When a failover candidate counts as reachable, and how long a preferred one has to be
+/// reachable before the node fails back to it
+#[derive(Debug, Clone, Copy)]
+pub struct HealthPolicy {
+    /// a candidate without a ping reply for this long is unreachable
+    pub timeout: Duration,
+    /// replies slower than this count as unreachable, too
+    pub max_rtt: Option<Duration>,
+    pub failback_after: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct CandidateHealth {
+    last_reply: Option<Instant>,
+    /// start of the current streak of good replies
+    healthy_since: Option<Instant>,
+}
+
+/// Decides which entry of the failover list should be the active target, based on the ping
+/// replies of all entries
+#[derive(Debug)]
+pub struct FailoverState {
+    policy: HealthPolicy,
+    candidates: Vec<CandidateHealth>,
+}
+
+impl FailoverState {
+    /// `active` is given a full `timeout` to answer its first ping, all other candidates are
+    /// unreachable until they reply
+    pub fn new(policy: HealthPolicy, candidates: usize, active: usize, now: Instant) -> Self {
+        let mut candidates = vec![CandidateHealth::default(); candidates];
+        candidates[active] = CandidateHealth {
+            last_reply: Some(now),
+            healthy_since: Some(now),
+        };
+        FailoverState { policy, candidates }
+    }
+
+    pub fn reply(&mut self, candidate: usize, rtt: Duration, now: Instant) {
+        let health = &mut self.candidates[candidate];
+        if self.policy.max_rtt.is_some_and(|max_rtt| rtt > max_rtt) {
+            health.healthy_since = None;
+            return;
+        }
+        health.last_reply = Some(now);
+        health.healthy_since.get_or_insert(now);
+    }
+
+    pub fn is_healthy(&mut self, candidate: usize, now: Instant) -> bool {
+        let health = &mut self.candidates[candidate];
+        let healthy = health.healthy_since.is_some()
+            && health
+                .last_reply
+                .is_some_and(|last_reply| now.duration_since(last_reply) < self.policy.timeout);
+        if !healthy {
+            health.healthy_since = None;
+        }
+        healthy
+    }
+
+    /// Returns the candidate to switch to, if any. An unreachable active target is replaced by
+    /// the first reachable candidate, a reachable one only by a preferred candidate that has
+    /// been reachable for `failback_after`.
+    pub fn evaluate(&mut self, active: usize, now: Instant) -> Option<usize> {
+        if !self.is_healthy(active, now) {
+            return (0..self.candidates.len())
+                .filter(|candidate| *candidate != active)
+                .find(|candidate| self.is_healthy(*candidate, now));
+        }
+        (0..active).find(|candidate| {
+            self.is_healthy(*candidate, now)
+                && self.candidates[*candidate]
+                    .healthy_since
+                    .is_some_and(|since| now.duration_since(since) >= self.policy.failback_after)
+        })
+    }
+}
+
+/// Pings every entry of the failover list (`candidates`, in order of preference) and makes the
+/// best reachable one the primary target.
+///
+/// Only a primary target that is part of the list is switched, so once a controller sends
+/// its own target, failover stays out of the way until the list's entries are targeted again.
+/// Errors receiving replies are backed off from, and returned once they keep coming (see
+/// [`ReceiveErrors`]).
+pub async fn run_failover(
+    socket: &UdpSocket,
+    candidates: Vec<Target>,
+    targets: Arc<watch::Sender<Targets>>,
+    interval: Duration,
+    policy: HealthPolicy,
+    metrics: Arc<Metrics>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> io::Result<()> {
+    let local_address = match socket.local_addr() {
+        Ok(address) => address,
+        Err(e) => {
+            warn!(error = %e, "Couldn't get failover socket address, not checking targets");
+            return Ok(());
+        }
+    };
+    let active_candidate = |targets: &Targets| {
+        let primary = targets.primary();
+        candidates
+            .iter()
+            .position(|candidate| candidate.host == primary.host && candidate.port == primary.port)
+    };
+
+    let mut state = FailoverState::new(
+        policy,
+        candidates.len(),
+        active_candidate(&targets.borrow()).unwrap_or(0),
+        Instant::now(),
+    );
+    let mut reply_addresses: Vec<Option<SocketAddr>> = vec![None; candidates.len()];
+    // sent pings by nonce, with the candidate they went to
+    let mut outstanding: HashMap<u64, (usize, Instant)> = HashMap::new();
+    let mut interval = time::interval(interval);
+    let mut nonce: u64 = 0;
+    let mut buf = [0; 1024];
+    let mut receive_errors = ReceiveErrors::default();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let now = Instant::now();
+                outstanding.retain(|_, (_, sent_at)| now.duration_since(*sent_at) < policy.timeout);
+
+                for (index, candidate) in candidates.iter().enumerate() {
+                    let reply_address = match reply_addresses[index] {
+                        Some(reply_address) => reply_address,
+                        None => match reply_ip(local_address, candidate.address).await {
+                            Ok(ip) => {
+                                let reply_address = SocketAddr::new(ip, local_address.port());
+                                reply_addresses[index] = Some(reply_address);
+                                reply_address
+                            }
+                            Err(e) => {
+                                debug!(target = %candidate, error = %e, "Couldn't determine reply address for health check");
+                                continue;
+                            }
+                        },
+                    };
+                    let ping = json!({
+                        "type": "udpPing",
+                        "replyTo": reply_address.to_string(),
+                        "nonce": nonce,
+                    });
+                    outstanding.insert(nonce, (index, Instant::now()));
+                    nonce += 1;
+                    if let Err(e) = socket.send_to(ping.to_string().as_bytes(), candidate.address).await {
+                        debug!(target = %candidate, error = %e, "Couldn't send health check ping");
+                    }
+                }
+
+                let Some(active) = active_candidate(&targets.borrow()) else {
+                    continue;
+                };
+                let Some(next) = state.evaluate(active, now) else {
+                    continue;
+                };
+                let switched = targets.send_if_modified(|targets| {
+                    // a controller might have changed the targets in the meantime
+                    let (from, to) = (&candidates[active], &candidates[next]);
+                    targets.substitute(&from.host, from.port, to.clone())
+                });
+                if switched {
+                    metrics.failovers.fetch_add(1, Ordering::Relaxed);
+                    if next < active {
+                        info!(from = %candidates[active], to = %candidates[next], "Preferred target is reachable again, failing back");
+                    } else {
+                        warn!(from = %candidates[active], to = %candidates[next], "Target unreachable, failing over");
+                    }
+                }
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (length, src) = match received {
+                    Ok(received) => {
+                        receive_errors.reset();
+                        received
+                    }
+                    Err(e) => match receive_errors.record(&e, std::time::Instant::now()) {
+                        // e.g. the ICMP port unreachable of a target that is down
+                        Some(backoff) => {
+                            debug!(error = %e, retry_in = ?backoff, "Couldn't receive health check reply");
+                            tokio::select! {
+                                _ = time::sleep(backoff) => continue,
+                                _ = shutdown.recv() => break,
+                            }
+                        }
+                        None => return Err(e),
+                    },
+                };
+                let now = Instant::now();
+                let echoed = serde_json::from_slice::<serde_json::Value>(&buf[..length])
+                    .ok()
+                    .and_then(|reply| reply["echo"].as_u64());
+                match echoed.and_then(|nonce| outstanding.remove(&nonce)) {
+                    Some((index, sent_at)) => {
+                        let rtt = now.duration_since(sent_at);
+                        debug!(%src, target = %candidates[index], rtt_micros = rtt.as_micros() as u64, "Health check reply");
+                        state.reply(index, rtt, now);
+                    }
+                    None => debug!(%src, "Ignoring late or unexpected health check reply"),
+                }
+            }
+            _ = shutdown.recv() => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: HealthPolicy = HealthPolicy {
+        timeout: Duration::from_secs(3),
+        max_rtt: Some(Duration::from_millis(100)),
+        failback_after: Duration::from_secs(10),
+    };
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn fails_over_to_the_first_reachable_candidate() {
+        let start = Instant::now();
+        let mut state = FailoverState::new(POLICY, 3, 0, start);
+        assert_eq!(state.evaluate(0, start + secs(1)), None);
+
+        state.reply(2, Duration::from_millis(5), start + secs(1));
+        // the primary hasn't answered for too long
+        assert_eq!(state.evaluate(0, start + secs(3)), Some(2));
+
+        state.reply(1, Duration::from_millis(5), start + secs(3));
+        assert_eq!(state.evaluate(0, start + secs(3)), Some(1));
+    }
+
+    #[test]
+    fn stays_if_no_candidate_is_reachable() {
+        let start = Instant::now();
+        let mut state = FailoverState::new(POLICY, 2, 0, start);
+        // too slow to count
+        state.reply(1, Duration::from_millis(500), start + secs(1));
+        assert_eq!(state.evaluate(0, start + secs(5)), None);
+    }
+
+    #[test]
+    fn fails_back_after_the_stable_period() {
+        let start = Instant::now();
+        let mut state = FailoverState::new(POLICY, 2, 1, start);
+        for s in 0..=12 {
+            state.reply(1, Duration::from_millis(5), start + secs(s));
+            if s >= 2 {
+                state.reply(0, Duration::from_millis(5), start + secs(s));
+            }
+            let expected = if s >= 12 { Some(0) } else { None };
+            assert_eq!(state.evaluate(1, start + secs(s)), expected, "after {}s", s);
+        }
+    }
+
+    #[test]
+    fn slow_replies_restart_the_stable_period() {
+        let start = Instant::now();
+        let mut state = FailoverState::new(POLICY, 2, 1, start);
+        state.reply(1, Duration::from_millis(5), start + secs(9));
+        state.reply(0, Duration::from_millis(5), start);
+        state.reply(0, Duration::from_millis(5), start + secs(2));
+        state.reply(0, Duration::from_millis(500), start + secs(4));
+        state.reply(0, Duration::from_millis(5), start + secs(6));
+        state.reply(0, Duration::from_millis(5), start + secs(8));
+        assert_eq!(state.evaluate(1, start + secs(10)), None);
+    }
+}
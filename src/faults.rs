@@ -0,0 +1,79 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Deliberate misbehavior of the data sender, for testing how receivers cope with unreliable
+/// networks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FaultInjection {
+    /// chance (0 to 100) that a packet isn't sent at all
+    pub loss_percent: f64,
+    /// chance (0 to 100) that a packet is sent twice
+    pub dup_percent: f64,
+    /// packets are held back by a random duration up to this
+    pub max_delay: Duration,
+}
+
+/// What happens to a single packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Fault {
+    pub drop: bool,
+    pub duplicate: bool,
+    pub delay: Duration,
+}
+
+impl FaultInjection {
+    pub fn is_active(&self) -> bool {
+        self.loss_percent > 0.0 || self.dup_percent > 0.0 || !self.max_delay.is_zero()
+    }
+
+    /// Rolls the dice for the next packet
+    pub fn next(&self) -> Fault {
+        if !self.is_active() {
+            return Fault::default();
+        }
+        let mut rng = rand::thread_rng();
+        Fault {
+            drop: rng.gen_bool(self.loss_percent / 100.0),
+            duplicate: rng.gen_bool(self.dup_percent / 100.0),
+            delay: if self.max_delay.is_zero() {
+                Duration::ZERO
+            } else {
+                rng.gen_range(Duration::ZERO..=self.max_delay)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inactive_by_default() {
+        let faults = FaultInjection::default();
+        assert!(!faults.is_active());
+        assert_eq!(faults.next(), Fault::default());
+    }
+
+    #[test]
+    fn faults_follow_the_configured_chances() {
+        let faults = FaultInjection {
+            loss_percent: 100.0,
+            dup_percent: 0.0,
+            max_delay: Duration::from_millis(5),
+        };
+        for _ in 0..100 {
+            let fault = faults.next();
+            assert!(fault.drop);
+            assert!(!fault.duplicate);
+            assert!(fault.delay <= Duration::from_millis(5));
+        }
+
+        let faults = FaultInjection {
+            dup_percent: 50.0,
+            ..FaultInjection::default()
+        };
+        let duplicated = (0..10000).filter(|_| faults.next().duplicate).count();
+        assert!((4500..5500).contains(&duplicated));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::config::Config;
+use crate::node::Node;
+use futures::future;
+use std::error::Error;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time;
+use tracing::{error, info, warn};
+
+/// How long a crashed node stays down before it's started again, so a node that crashes right
+/// away doesn't spin
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Runs a node per config in `nodes` (named after their config file) in the same process until
+/// `shutdown` completes. Every node has its own sockets and tasks. A node that crashes is logged
+/// and, with `restart`, bound and started again, while the others keep running. Fails if a node
+/// can't be bound on startup, or once every node stopped.
+pub async fn run(
+    nodes: Vec<(String, Config)>,
+    restart: bool,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error>> {
+    // bind all nodes first, so e.g. two configs sharing a port fail the start
+    let mut bound = vec![];
+    for (name, config) in nodes {
+        let node = Node::bind(config.clone())
+            .await
+            .map_err(|e| format!("Couldn't start node of '{}': {}", name, e))?;
+        bound.push((name, config, node));
+    }
+    info!(nodes = bound.len(), "Started all nodes");
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let supervisors = future::join_all(bound.into_iter().map(|(name, config, node)| {
+        tokio::spawn(supervise(name, config, node, restart, shutdown_rx.clone()))
+    }));
+    tokio::pin!(supervisors);
+    tokio::select! {
+        _ = shutdown => {
+            let _ = shutdown_tx.send(true);
+            supervisors.await;
+            Ok(())
+        }
+        _ = &mut supervisors => Err("Every node stopped".into()),
+    }
+}
+
+/// Runs `node` in a task of its own, so a panic only takes down this node, and starts it again
+/// after a panic with `restart`. Returns once `shutdown` is set or the node is down for good.
+async fn supervise(
+    name: String,
+    config: Config,
+    mut node: Node,
+    restart: bool,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let mut stop = shutdown.clone();
+        let stopped = async move {
+            let _ = stop.wait_for(|&stop| stop).await;
+        };
+        let error = match tokio::spawn(node.run(stopped)).await {
+            Ok(Ok(())) => return,
+            // a task that kept panicking stopped the node, which counts as a crash as well
+            Ok(Err(e)) => e.to_string(),
+            Err(e) => e.to_string(),
+        };
+        if !restart {
+            error!(config = %name, %error, "Node crashed, the other nodes keep running");
+            return;
+        }
+        error!(config = %name, %error, "Node crashed, restarting it");
+        // binding can fail as well, e.g. if another process took a port in the meantime
+        node = loop {
+            tokio::select! {
+                _ = shutdown.wait_for(|&stop| stop) => return,
+                _ = time::sleep(RESTART_DELAY) => {}
+            }
+            match Node::bind(config.clone()).await {
+                Ok(node) => break node,
+                Err(e) => warn!(config = %name, error = %e, "Couldn't restart node, trying again"),
+            }
+        };
+        info!(config = %name, "Restarted node");
+    }
+}
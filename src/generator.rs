@@ -0,0 +1,581 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The numeric type of the emitted values, e.g. to emulate a 24-bit ADC with `u32`
+#[derive(
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    #[default]
+    U16,
+    U32,
+    I32,
+    F64,
+}
+
+impl ValueType {
+    /// Converts a computed value to this type, rounding integers and clamping to their range
+    pub fn convert(self, value: f64) -> SampleValue {
+        match self {
+            ValueType::U16 => SampleValue::U16(value.round().clamp(0.0, u16::MAX as f64) as u16),
+            ValueType::U32 => SampleValue::U32(value.round().clamp(0.0, u32::MAX as f64) as u32),
+            ValueType::I32 => {
+                SampleValue::I32(value.round().clamp(i32::MIN as f64, i32::MAX as f64) as i32)
+            }
+            ValueType::F64 => SampleValue::F64(value),
+        }
+    }
+
+    /// Parses a value of this type, rejecting values outside of its range
+    pub fn parse(self, value: &str) -> Result<SampleValue, String> {
+        let parsed = match self {
+            ValueType::U16 => value
+                .parse()
+                .map(SampleValue::U16)
+                .map_err(|e| e.to_string()),
+            ValueType::U32 => value
+                .parse()
+                .map(SampleValue::U32)
+                .map_err(|e| e.to_string()),
+            ValueType::I32 => value
+                .parse()
+                .map(SampleValue::I32)
+                .map_err(|e| e.to_string()),
+            ValueType::F64 => value
+                .parse()
+                .map(SampleValue::F64)
+                .map_err(|e| e.to_string()),
+        };
+        parsed.map_err(|e| format!("invalid value '{}': {}", value, e))
+    }
+}
+
+/// A single emitted value, serialized as a JSON integer or float depending on its type
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum SampleValue {
+    U16(u16),
+    U32(u32),
+    I32(i32),
+    F64(f64),
+}
+
+impl SampleValue {
+    pub fn as_f64(self) -> f64 {
+        match self {
+            SampleValue::U16(value) => value as f64,
+            SampleValue::U32(value) => value as f64,
+            SampleValue::I32(value) => value as f64,
+            SampleValue::F64(value) => value,
+        }
+    }
+}
+
+impl fmt::Display for SampleValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SampleValue::U16(value) => value.fmt(f),
+            SampleValue::U32(value) => value.fmt(f),
+            SampleValue::I32(value) => value.fmt(f),
+            SampleValue::F64(value) => value.fmt(f),
+        }
+    }
+}
+
+/// The shape of the signal produced by the input node
+#[derive(
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    // uniformly distributed random values over the full range of integer value types, or within
+    // `offset` ± `amplitude` for `f64`, or within `value_min` and `value_max` if they are set
+    #[default]
+    Random,
+    Sine,
+    Triangle,
+    Sawtooth,
+    Square,
+    // always emits `offset`
+    Constant,
+}
+
+/// Where a channel's values come from. The generator task asks its source for the next value on
+/// every tick, so adding a new sensor model only takes a new implementation.
+pub trait DataSource: Send {
+    /// The value `elapsed` after the node started, or `None` once the source is exhausted
+    fn next(&mut self, elapsed: Duration) -> Option<SampleValue>;
+}
+
+/// Computes input values for a waveform based on the time elapsed since the node started
+#[derive(Debug, Clone)]
+pub struct Generator {
+    pub waveform: Waveform,
+    pub amplitude: f64,
+    pub offset: f64,
+    pub period: Duration,
+    /// standard deviation of the Gaussian noise added to every value, 0 for none
+    pub noise_stddev: f64,
+    pub value_type: ValueType,
+    /// `[min, max]` of the values, replacing `offset` ± `amplitude` and clamping the noise
+    pub range: Option<(f64, f64)>,
+    /// source of the `random` waveform and the noise, seeded if the output has to be reproducible
+    pub rng: StdRng,
+}
+
+impl Generator {
+    /// The RNG for [`rng`](Self::rng): reproducible with the same `seed`, nondeterministic
+    /// without one
+    pub fn rng(seed: Option<u64>) -> StdRng {
+        match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }
+    }
+
+    /// The generator as a [`DataSource`], or a [`Constant`] if it would only ever emit `offset`
+    pub fn into_source(self) -> Box<dyn DataSource> {
+        if self.waveform == Waveform::Constant && self.noise_stddev == 0.0 {
+            Box::new(Constant(
+                self.value_type.convert(self.offset_and_amplitude().0),
+            ))
+        } else {
+            Box::new(self)
+        }
+    }
+
+    pub fn value_at(&mut self, elapsed: Duration) -> SampleValue {
+        let phase = self.phase(elapsed);
+        let (offset, amplitude) = self.offset_and_amplitude();
+        let shape = match self.waveform {
+            // random integers don't depend on time or the waveform parameters
+            Waveform::Random => {
+                let value = match (self.range, self.value_type) {
+                    // every integer in the range equally likely, rounding would halve the odds
+                    // of the bounds
+                    (Some((min, max)), value_type)
+                        if value_type != ValueType::F64 && min.ceil() <= max.floor() =>
+                    {
+                        self.rng.gen_range(min.ceil() as i64..=max.floor() as i64) as f64
+                    }
+                    (Some((min, max)), _) => self.rng.gen_range(min..=max),
+                    (None, ValueType::U16) => self.rng.gen::<u16>() as f64,
+                    (None, ValueType::U32) => self.rng.gen::<u32>() as f64,
+                    (None, ValueType::I32) => self.rng.gen::<i32>() as f64,
+                    (None, ValueType::F64) => offset + amplitude * self.rng.gen_range(-1.0..=1.0),
+                };
+                return self.with_noise(value);
+            }
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Constant => 0.0,
+        };
+
+        self.with_noise(offset + amplitude * shape)
+    }
+
+    /// The center and amplitude of the waveform, spanning the range if there is one
+    fn offset_and_amplitude(&self) -> (f64, f64) {
+        match self.range {
+            Some((min, max)) => ((min + max) / 2.0, (max - min) / 2.0),
+            None => (self.offset, self.amplitude),
+        }
+    }
+
+    /// Adds the noise to `value` and converts it to the value type, clamped to the range
+    fn with_noise(&mut self, value: f64) -> SampleValue {
+        let noise = match Normal::new(0.0, self.noise_stddev) {
+            Ok(normal) if self.noise_stddev > 0.0 => normal.sample(&mut self.rng),
+            _ => 0.0,
+        };
+        let value = match self.range {
+            Some((min, max)) => (value + noise).clamp(min, max),
+            None => value + noise,
+        };
+        self.value_type.convert(value)
+    }
+
+    /// Position within the current period, in the range `[0, 1)`
+    fn phase(&self, elapsed: Duration) -> f64 {
+        let period = self.period.as_secs_f64();
+        if period == 0.0 {
+            return 0.0;
+        }
+        (elapsed.as_secs_f64() % period) / period
+    }
+}
+
+impl DataSource for Generator {
+    fn next(&mut self, elapsed: Duration) -> Option<SampleValue> {
+        Some(self.value_at(elapsed))
+    }
+}
+
+/// Always the same value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Constant(pub SampleValue);
+
+impl DataSource for Constant {
+    fn next(&mut self, _elapsed: Duration) -> Option<SampleValue> {
+        Some(self.0)
+    }
+}
+
+/// Values replayed from a recorded series, one per tick
+#[derive(Debug, Clone)]
+pub struct Replay {
+    values: Vec<SampleValue>,
+    position: usize,
+    /// start over at the end instead of stopping
+    looping: bool,
+}
+
+impl Replay {
+    pub fn new(values: Vec<SampleValue>, looping: bool) -> Self {
+        Replay {
+            values,
+            position: 0,
+            looping,
+        }
+    }
+
+    /// Loads the values of `value_type` separated by newlines and/or commas from a file
+    pub fn from_file(
+        path: &str,
+        looping: bool,
+        value_type: ValueType,
+    ) -> Result<Self, Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read replay file '{}': {}", path, e))?;
+        let values = parse_replay_values(&content, value_type)
+            .map_err(|e| format!("Couldn't parse replay file '{}': {}", path, e))?;
+        if values.is_empty() {
+            return Err(format!("Replay file '{}' doesn't contain any values", path).into());
+        }
+        Ok(Replay::new(values, looping))
+    }
+}
+
+impl DataSource for Replay {
+    /// Ignores `elapsed`, the values are replayed at the node's interval
+    fn next(&mut self, _elapsed: Duration) -> Option<SampleValue> {
+        if self.position >= self.values.len() {
+            if !self.looping {
+                return None;
+            }
+            self.position = 0;
+        }
+        let value = self.values.get(self.position).copied();
+        self.position += 1;
+        value
+    }
+}
+
+/// A sensor's calibration curve, turning raw values into engineering units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub scale: f64,
+    pub offset: f64,
+    /// round the calibrated value to an integer
+    pub round: bool,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            scale: 1.0,
+            offset: 0.0,
+            round: false,
+        }
+    }
+}
+
+impl Calibration {
+    /// Whether the calibration leaves values as they are
+    pub fn is_identity(&self) -> bool {
+        self.scale == 1.0 && self.offset == 0.0
+    }
+
+    /// `raw * scale + offset` as a float, or `raw` itself for the identity calibration
+    pub fn apply(&self, raw: SampleValue) -> SampleValue {
+        if self.is_identity() {
+            return raw;
+        }
+        let value = raw.as_f64() * self.scale + self.offset;
+        SampleValue::F64(if self.round { value.round() } else { value })
+    }
+}
+
+fn parse_replay_values(content: &str, value_type: ValueType) -> Result<Vec<SampleValue>, String> {
+    content
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty())
+        .map(|value| value_type.parse(value))
+        .collect()
+}
+
+/// What to do with a new sample when the queue between generating and sending data is full
+#[derive(
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    // make room by dropping the oldest queued sample, so the freshest data gets sent
+    #[default]
+    DropOldest,
+    // drop the new sample and keep the queue as it is
+    DropNewest,
+}
+
+/// A generated value waiting to be sent
+#[derive(Debug, Clone)]
+pub struct DataSample {
+    pub seq: u64,
+    /// the channel that generated the value, `None` for single-channel nodes
+    pub channel: Option<Arc<str>>,
+    /// the calibrated value
+    pub value: SampleValue,
+    /// the value before calibration, if it should be sent as well
+    pub raw: Option<SampleValue>,
+    /// generation time in microseconds since the UNIX epoch
+    pub generated_at_micros: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_generator(seed: Option<u64>) -> Generator {
+        Generator {
+            waveform: Waveform::Random,
+            amplitude: 32767.0,
+            offset: 32768.0,
+            period: Duration::from_secs(10),
+            noise_stddev: 0.0,
+            value_type: ValueType::U16,
+            range: None,
+            rng: Generator::rng(seed),
+        }
+    }
+
+    fn values(generator: &mut Generator) -> Vec<SampleValue> {
+        (0..16)
+            .map(|i| generator.value_at(Duration::from_millis(i)))
+            .collect()
+    }
+
+    #[test]
+    fn seeded_random_values_are_reproducible() {
+        let first = values(&mut random_generator(Some(42)));
+        assert_eq!(first, values(&mut random_generator(Some(42))));
+        assert_ne!(first, values(&mut random_generator(Some(43))));
+    }
+
+    #[test]
+    fn noise_is_added_to_the_waveform() {
+        let noisy = |seed| Generator {
+            waveform: Waveform::Constant,
+            amplitude: 0.0,
+            offset: 1000.0,
+            period: Duration::from_secs(10),
+            noise_stddev: 10.0,
+            value_type: ValueType::U16,
+            range: None,
+            rng: Generator::rng(Some(seed)),
+        };
+        let first = values(&mut noisy(42));
+        assert_eq!(first, values(&mut noisy(42)));
+        assert!(first.iter().any(|value| *value != SampleValue::U16(1000)));
+        // 16 samples within 6 standard deviations
+        assert!(first
+            .iter()
+            .all(|value| matches!(value, SampleValue::U16(940..=1060))));
+
+        // clamped instead of wrapping around
+        let mut generator = noisy(42);
+        generator.offset = 0.0;
+        generator.noise_stddev = 1000.0;
+        values(&mut generator);
+    }
+
+    #[test]
+    fn values_are_converted_to_the_value_type() {
+        assert_eq!(ValueType::U16.convert(70000.4), SampleValue::U16(u16::MAX));
+        assert_eq!(ValueType::U32.convert(70000.4), SampleValue::U32(70000));
+        assert_eq!(ValueType::I32.convert(-1.6), SampleValue::I32(-2));
+        assert_eq!(ValueType::U32.convert(-1.6), SampleValue::U32(0));
+        assert_eq!(ValueType::F64.convert(-1.6), SampleValue::F64(-1.6));
+
+        assert_eq!(ValueType::I32.parse("-5"), Ok(SampleValue::I32(-5)));
+        assert!(ValueType::U16.parse("70000").is_err());
+        assert_eq!(
+            serde_json::to_value([SampleValue::U32(70000), SampleValue::F64(0.5)]).unwrap(),
+            serde_json::json!([70000, 0.5])
+        );
+        assert_eq!(SampleValue::F64(2.0).to_string(), "2");
+    }
+
+    #[test]
+    fn calibration_converts_to_engineering_units() {
+        // 0..65535 to 0..100 °C
+        let celsius = Calibration {
+            scale: 100.0 / 65535.0,
+            offset: 0.0,
+            round: false,
+        };
+        assert_eq!(
+            celsius.apply(SampleValue::U16(65535)),
+            SampleValue::F64(100.0)
+        );
+        assert_eq!(
+            Calibration {
+                round: true,
+                ..celsius
+            }
+            .apply(SampleValue::U16(20000)),
+            SampleValue::F64(31.0)
+        );
+        assert_eq!(
+            Calibration {
+                scale: 0.5,
+                offset: -10.0,
+                round: false,
+            }
+            .apply(SampleValue::I32(5)),
+            SampleValue::F64(-7.5)
+        );
+        assert_eq!(
+            Calibration::default().apply(SampleValue::U16(7)),
+            SampleValue::U16(7)
+        );
+    }
+
+    #[test]
+    fn replay_stops_or_starts_over_at_the_end() {
+        let values = vec![SampleValue::U16(1), SampleValue::U16(2)];
+        let mut once = Replay::new(values.clone(), false);
+        let mut looping = Replay::new(values, true);
+        let replayed = |source: &mut Replay| -> Vec<Option<SampleValue>> {
+            (0..3).map(|_| source.next(Duration::ZERO)).collect()
+        };
+        assert_eq!(
+            replayed(&mut once),
+            vec![Some(SampleValue::U16(1)), Some(SampleValue::U16(2)), None]
+        );
+        assert_eq!(
+            replayed(&mut looping),
+            vec![
+                Some(SampleValue::U16(1)),
+                Some(SampleValue::U16(2)),
+                Some(SampleValue::U16(1))
+            ]
+        );
+        assert_eq!(Replay::new(vec![], true).next(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn noiseless_constant_waveform_becomes_a_constant_source() {
+        let generator = Generator {
+            waveform: Waveform::Constant,
+            offset: 1000.0,
+            ..random_generator(None)
+        };
+        let mut source = generator.clone().into_source();
+        assert_eq!(
+            source.next(Duration::from_secs(3)),
+            Some(SampleValue::U16(1000))
+        );
+        let mut noisy = Generator {
+            noise_stddev: 10.0,
+            ..generator
+        }
+        .into_source();
+        assert!(noisy.next(Duration::ZERO).is_some());
+    }
+
+    #[test]
+    fn random_floats_stay_within_the_amplitude() {
+        let mut generator = random_generator(Some(42));
+        generator.value_type = ValueType::F64;
+        generator.amplitude = 1.0;
+        generator.offset = 10.0;
+        assert!(values(&mut generator)
+            .iter()
+            .all(|value| matches!(value, SampleValue::F64(value) if (9.0..=11.0).contains(value))));
+    }
+
+    #[test]
+    fn values_stay_within_the_range() {
+        // a 12-bit ADC
+        let mut generator = Generator {
+            range: Some((0.0, 4095.0)),
+            ..random_generator(Some(42))
+        };
+        let random: Vec<u16> = (0..1000)
+            .map(|_| match generator.value_at(Duration::ZERO) {
+                SampleValue::U16(value) => value,
+                value => panic!("unexpected {:?}", value),
+            })
+            .collect();
+        assert!(random.iter().all(|value| *value <= 4095));
+        assert!(random.iter().any(|value| *value > 3900));
+
+        generator.waveform = Waveform::Sine;
+        generator.period = Duration::from_secs(4);
+        assert_eq!(
+            generator.value_at(Duration::from_secs(1)),
+            SampleValue::U16(4095)
+        );
+        assert_eq!(
+            generator.value_at(Duration::from_secs(3)),
+            SampleValue::U16(0)
+        );
+
+        // noise is clamped to the range as well
+        generator.waveform = Waveform::Constant;
+        generator.noise_stddev = 10000.0;
+        assert!(values(&mut generator)
+            .iter()
+            .all(|value| matches!(value, SampleValue::U16(0..=4095))));
+    }
+}
@@ -0,0 +1,598 @@
+use crate::ack::AckSender;
+use crate::capture::Capture;
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::node::{configured_targets, resolve_targets, BoundPorts};
+use crate::protocol::{
+    pong, Direction, NoFields, PingReplyFormat, QueryStatus, SetInterval, UdpPing,
+    UpdateTargetConfirmed,
+};
+use crate::state::PersistedState;
+use crate::target::{
+    parse_update_target, resolve_address_from, Target, TargetOp, Targets, UpdateTarget,
+};
+use futures::future::BoxFuture;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+/// How long a repeat of the last applied `updateTarget` counts as a retransmission
+const DUPLICATE_UPDATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// The state of the node the handlers of control messages read and change
+pub(crate) struct Inbound {
+    /// the config the node was started with
+    pub config: Config,
+    /// the current config, replaced when it is reloaded
+    pub current_config: watch::Receiver<Arc<Config>>,
+    pub targets: Arc<watch::Sender<Targets>>,
+    pub interval: Arc<watch::Sender<Duration>>,
+    pub metrics: Arc<Metrics>,
+    pub acks: AckSender,
+    /// sends status and ping replies
+    pub socket: Arc<UdpSocket>,
+    pub capture: Capture,
+    pub started: Instant,
+    /// recorded in the state file, so it's 0 without one
+    pub restart_count: u64,
+    pub ports: BoundPorts,
+    /// to recognize retransmissions
+    pub last_update: Option<AppliedUpdate>,
+    /// the `issued_at` of the newest applied `updateTarget`
+    pub last_issued_at: Option<u64>,
+}
+
+/// Where a control message came from and what applies to all of them
+pub(crate) struct Request {
+    pub src: SocketAddr,
+    pub request_id: Option<Value>,
+    pub quiet: bool,
+    pub node_id: Arc<str>,
+}
+
+impl Request {
+    /// `reply` with the `node_id` of the node, and the `request_id` of the request echoed if it
+    /// has one
+    fn answer(&self, mut reply: Value) -> Value {
+        reply["node_id"] = self.node_id.as_ref().into();
+        if let Some(request_id) = &self.request_id {
+            reply["request_id"] = request_id.clone();
+        }
+        reply
+    }
+}
+
+/// Handles a control message parsed as `M`
+pub(crate) type Handler<M> = for<'a> fn(&'a mut Inbound, M, &'a Request) -> BoxFuture<'a, ()>;
+
+/// Parses a control message and hands it to its handler, failing if it doesn't fit its type
+type Dispatch = Box<
+    dyn for<'a> Fn(&'a mut Inbound, &Value, &'a Request) -> serde_json::Result<BoxFuture<'a, ()>>
+        + Send
+        + Sync,
+>;
+
+/// The handlers of the control messages, by `type`
+pub(crate) struct Dispatcher {
+    handlers: HashMap<&'static str, Dispatch>,
+}
+
+impl Dispatcher {
+    /// A dispatcher with a handler for every control message of the protocol
+    pub fn new() -> Self {
+        let mut dispatcher = Dispatcher {
+            handlers: HashMap::new(),
+        };
+        dispatcher.register("updateTarget", update_target);
+        dispatcher.register("resetTarget", reset_target);
+        dispatcher.register("setInterval", set_interval);
+        dispatcher.register("pauseData", pause_data);
+        dispatcher.register("resumeData", resume_data);
+        dispatcher.register("queryStatus", query_status);
+        dispatcher.register("getTarget", get_target);
+        dispatcher.register("updateTargetConfirmed", update_target_confirmed);
+        dispatcher.register("udpPing", udp_ping);
+        dispatcher
+    }
+
+    /// Handles messages of `message_type` with `handler`, replacing any previous one. The fields
+    /// are checked while parsing the message as `M`, so one that doesn't fit is rejected as a
+    /// whole.
+    pub fn register<M: DeserializeOwned + 'static>(
+        &mut self,
+        message_type: &'static str,
+        handler: Handler<M>,
+    ) {
+        self.handlers.insert(
+            message_type,
+            Box::new(move |inbound, json, request| {
+                Ok(handler(inbound, M::deserialize(json)?, request))
+            }),
+        );
+    }
+
+    /// Handles `json` with the handler of its `type`. Messages of unknown types are logged and
+    /// ignored, ones without a `type` are invalid.
+    pub async fn dispatch(
+        &self,
+        inbound: &mut Inbound,
+        json: &Value,
+        request: &Request,
+    ) -> serde_json::Result<()> {
+        let Some(message_type) = json["type"].as_str() else {
+            return Err(serde::de::Error::missing_field("type"));
+        };
+        let Some(dispatch) = self.handlers.get(message_type) else {
+            warn!(src = %request.src, message_type, "Ignoring message of unknown type");
+            return Ok(());
+        };
+        dispatch(inbound, json, request)?.await;
+        Ok(())
+    }
+}
+
+fn update_target<'a>(
+    inbound: &'a mut Inbound,
+    update: UpdateTarget,
+    request: &'a Request,
+) -> BoxFuture<'a, ()> {
+    Box::pin(async move {
+        let src = request.src;
+        let op = update.op;
+        // controllers behind NAT can't receive at the address they send from
+        let reply_address = update.reply_to.unwrap_or(src);
+        let parsed = parse_update_target(
+            &update,
+            inbound.config.update_target_mode.unwrap(),
+            inbound.config.target_port,
+        );
+        // controllers resend updates until they get an ACK, applying those again would only log
+        // and persist the same targets once more
+        if let Ok((host, port)) = &parsed {
+            if inbound.last_update.as_ref().is_some_and(|last| {
+                last.is_repeated_by(request.request_id.as_ref(), op, host, *port)
+            }) {
+                if !request.quiet {
+                    debug!(%src, %op, %host, port, "Ignoring repeated target update");
+                }
+                let ack = request.answer(json!({
+                    "type": "updateTarget",
+                    "success": true,
+                }));
+                inbound.acks.send(ack, reply_address).await;
+                return;
+            }
+        }
+        // reordered on the wire, applying it would revert a newer update
+        if let (Some(issued_at), Some(last_issued_at)) = (update.issued_at, inbound.last_issued_at)
+        {
+            if issued_at < last_issued_at {
+                warn!(
+                    %src,
+                    issued_at,
+                    last_issued_at,
+                    "Ignoring target update older than the last applied one"
+                );
+                let nack = request.answer(json!({
+                    "type": "updateTarget",
+                    "success": false,
+                    "error": format!(
+                        "`issued_at` {} is older than the last applied update ({})",
+                        issued_at, last_issued_at
+                    ),
+                }));
+                inbound.acks.send(nack, reply_address).await;
+                return;
+            }
+        }
+        let applied = match parsed {
+            Ok((host, port)) => apply_target_update(
+                &inbound.targets,
+                inbound.config.bind_address.unwrap(),
+                op,
+                &host,
+                port,
+            )
+            .await
+            .map(|()| (host, port)),
+            Err(e) => Err(e),
+        };
+        let (host, port) = match applied {
+            Ok(applied) => applied,
+            // keep the old targets, the controller learns about it from the NACK
+            Err(e) => {
+                warn!(%src, error = %e, "Rejected invalid target update");
+                let nack = request.answer(json!({
+                    "type": "updateTarget",
+                    "success": false,
+                    "error": e,
+                }));
+                inbound.acks.send(nack, reply_address).await;
+                return;
+            }
+        };
+        inbound
+            .metrics
+            .target_updates
+            .fetch_add(1, Ordering::Relaxed);
+        inbound.last_issued_at = update.issued_at.or(inbound.last_issued_at);
+        inbound.last_update = Some(AppliedUpdate {
+            request_id: request.request_id.clone(),
+            op,
+            host: host.clone(),
+            port,
+            applied_at: Instant::now(),
+        });
+        let targets = inbound.targets.borrow().clone();
+        info!(%src, %op, %host, port, %targets, "Targets updated");
+        save_targets(&inbound.config, inbound.restart_count, &targets).await;
+
+        let ack = request.answer(json!({
+            "type": "updateTarget",
+            "success": true,
+        }));
+        inbound.acks.send(ack, reply_address).await;
+    })
+}
+
+fn reset_target<'a>(
+    inbound: &'a mut Inbound,
+    _: NoFields,
+    request: &'a Request,
+) -> BoxFuture<'a, ()> {
+    Box::pin(async move {
+        let src = request.src;
+        // the configured targets, which a reload may have changed since startup
+        let configured = configured_targets(&inbound.current_config.borrow());
+        // the error isn't `Send`, so it mustn't be held across the state file write
+        let resolved = resolve_targets(&configured, inbound.config.bind_address.unwrap())
+            .await
+            .map_err(|e| e.to_string());
+        let ack = match resolved {
+            Ok(targets) => {
+                inbound
+                    .metrics
+                    .target_updates
+                    .fetch_add(1, Ordering::Relaxed);
+                // an `updateTarget` repeated after this is a new one
+                inbound.last_update = None;
+                info!(%src, %targets, "Targets reset to the configured ones");
+                save_targets(&inbound.config, inbound.restart_count, &targets).await;
+                inbound.targets.send_replace(targets);
+                json!({
+                    "type": "resetTarget",
+                    "success": true,
+                })
+            }
+            Err(e) => {
+                warn!(%src, error = %e, "Couldn't reset targets, keeping the current ones");
+                json!({
+                    "type": "resetTarget",
+                    "success": false,
+                    "error": e,
+                })
+            }
+        };
+        inbound.acks.send(request.answer(ack), src).await;
+    })
+}
+
+fn set_interval<'a>(
+    inbound: &'a mut Inbound,
+    SetInterval { interval_ms }: SetInterval,
+    request: &'a Request,
+) -> BoxFuture<'a, ()> {
+    Box::pin(async move {
+        let src = request.src;
+        let ack = match interval_ms {
+            // tokio panics on zero-length intervals
+            0 => {
+                warn!(%src, interval_ms, "Ignoring invalid interval");
+                json!({
+                    "type": "setInterval",
+                    "success": false,
+                    "error": "`interval_ms` must be greater than 0",
+                })
+            }
+            interval_ms => {
+                inbound
+                    .interval
+                    .send_replace(Duration::from_millis(interval_ms));
+                info!(%src, interval_ms, "Data interval updated");
+                json!({
+                    "type": "setInterval",
+                    "success": true,
+                    "interval_ms": interval_ms,
+                })
+            }
+        };
+        inbound.acks.send(request.answer(ack), src).await;
+    })
+}
+
+fn pause_data<'a>(
+    inbound: &'a mut Inbound,
+    _: NoFields,
+    request: &'a Request,
+) -> BoxFuture<'a, ()> {
+    Box::pin(set_paused(inbound, true, request))
+}
+
+fn resume_data<'a>(
+    inbound: &'a mut Inbound,
+    _: NoFields,
+    request: &'a Request,
+) -> BoxFuture<'a, ()> {
+    Box::pin(set_paused(inbound, false, request))
+}
+
+async fn set_paused(inbound: &mut Inbound, paused: bool, request: &Request) {
+    let src = request.src;
+    let was_paused = inbound.metrics.paused.swap(paused, Ordering::Relaxed);
+    if paused != was_paused {
+        info!(%src, paused, "Data stream {}", if paused { "paused" } else { "resumed" });
+    }
+    let ack = request.answer(json!({
+        "type": if paused { "pauseData" } else { "resumeData" },
+        "success": true,
+        "paused": paused,
+    }));
+    inbound.acks.send(ack, src).await;
+}
+
+fn query_status<'a>(
+    inbound: &'a mut Inbound,
+    QueryStatus { reply_to }: QueryStatus,
+    request: &'a Request,
+) -> BoxFuture<'a, ()> {
+    Box::pin(async move {
+        let reply_address = reply_to.unwrap_or(request.src);
+        let stats = inbound.metrics.stats();
+        let status = request.answer(json!({
+            "type": "status",
+            "flow_name": inbound.config.flow_name,
+            "area": inbound.config.area,
+            "target": inbound.targets.borrow().primary().to_string(),
+            "targets": inbound
+                .targets
+                .borrow()
+                .iter()
+                .map(|target| target.to_string())
+                .collect::<Vec<_>>(),
+            "interval_ms": inbound.interval.borrow().as_millis() as u64,
+            "paused": inbound.metrics.paused.load(Ordering::Relaxed),
+            "packets_sent": stats.packets_sent,
+            "packets_dropped": stats.packets_dropped,
+            "packets_failed": stats.packets_failed,
+            "target_updates": stats.target_updates,
+            "rejected_messages": stats.rejected_messages,
+            "rate_limited_messages": stats.rate_limited_messages,
+            "invalid_messages": stats.invalid_messages,
+            "inbound_messages": stats.inbound_messages,
+            "uptime_ms": inbound.started.elapsed().as_millis() as u64,
+            "uptime_seconds": inbound.started.elapsed().as_secs(),
+            "restart_count": inbound.restart_count,
+            "ports": inbound.ports,
+            "time_micros": inbound.config.clock_source.unwrap().now_micros(),
+        }));
+        send_reply(inbound, status, reply_address, request).await;
+    })
+}
+
+/// Only the primary target, cheaper than `queryStatus` for a quick look
+fn get_target<'a>(
+    inbound: &'a mut Inbound,
+    _: NoFields,
+    request: &'a Request,
+) -> BoxFuture<'a, ()> {
+    Box::pin(async move {
+        let target = inbound.targets.borrow().primary().to_string();
+        let reply = request.answer(json!({
+            "type": "currentTarget",
+            "target": target,
+        }));
+        send_reply(inbound, reply, request.src, request).await;
+    })
+}
+
+/// Tells the sender of a message that failed [`Dispatcher::dispatch`] what's wrong with it and
+/// counts it. `updateTarget` and `setInterval` are answered with a failed ACK, which their
+/// controllers wait for (at the `replyTo` of `updateTarget` if it is a valid address), everything
+/// else with an `error` message.
+pub(crate) async fn reject_invalid(
+    inbound: &mut Inbound,
+    json: &Value,
+    request: &Request,
+    error: &serde_json::Error,
+) {
+    let src = request.src;
+    warn!(%src, message_type = %json["type"], %error, "Rejected invalid message");
+    inbound
+        .metrics
+        .invalid_messages
+        .fetch_add(1, Ordering::Relaxed);
+    match json["type"].as_str() {
+        Some(message_type @ ("updateTarget" | "setInterval")) => {
+            let nack = request.answer(json!({
+                "type": message_type,
+                "success": false,
+                "error": error.to_string(),
+            }));
+            let reply_address = match message_type {
+                "updateTarget" => json["replyTo"].as_str().and_then(|s| s.parse().ok()),
+                _ => None,
+            };
+            inbound.acks.send(nack, reply_address.unwrap_or(src)).await;
+        }
+        _ => {
+            let nack = request.answer(json!({
+                "type": "error",
+                "reason": error.to_string(),
+            }));
+            send_reply(inbound, nack, src, request).await;
+        }
+    }
+}
+
+/// Sends the answer to a query once, signed if a shared secret is configured
+async fn send_reply(inbound: &Inbound, mut reply: Value, address: SocketAddr, request: &Request) {
+    if let Some(secret) = &inbound.config.shared_secret {
+        secret.sign(&mut reply);
+    }
+    let reply_type = reply["type"].clone();
+    let reply = reply.to_string();
+    match inbound.socket.send_to(reply.as_bytes(), address).await {
+        Ok(_) => {
+            inbound
+                .capture
+                .record(Direction::Outbound, address, reply.as_bytes());
+            if !request.quiet {
+                debug!(reply_address = %address, %reply_type, "Sent reply");
+            }
+        }
+        Err(e) => warn!(reply_address = %address, %reply_type, error = %e, "Couldn't send reply"),
+    }
+}
+
+fn update_target_confirmed<'a>(
+    inbound: &'a mut Inbound,
+    UpdateTargetConfirmed { request_id }: UpdateTargetConfirmed,
+    _: &'a Request,
+) -> BoxFuture<'a, ()> {
+    Box::pin(async move {
+        if inbound.acks.confirm(&request_id) {
+            debug!(%request_id, "ACK confirmed");
+        } else {
+            debug!(%request_id, "Ignoring confirmation for unknown request");
+        }
+    })
+}
+
+fn udp_ping<'a>(
+    inbound: &'a mut Inbound,
+    UdpPing { reply_to, nonce }: UdpPing,
+    request: &'a Request,
+) -> BoxFuture<'a, ()> {
+    Box::pin(async move {
+        // pings with a nonce (like the ones sent by `ping_interval_ms`) get a `udpPong` echoing
+        // it, so the sender can match out-of-order replies
+        let now_micros = inbound.config.clock_source.unwrap().now_micros();
+        let return_buf = match (inbound.config.ping_reply_format.unwrap(), nonce) {
+            (PingReplyFormat::Raw, None) => now_micros.to_be_bytes().to_vec(),
+            (_, nonce) => pong(nonce, now_micros, &request.node_id)
+                .to_string()
+                .into_bytes(),
+        };
+        // like an echo service, answer the sender unless told otherwise
+        let return_address = reply_to.unwrap_or(request.src);
+        // send current system time back to sender
+        match inbound.socket.send_to(&return_buf, return_address).await {
+            Ok(_) => {
+                inbound.metrics.pings.fetch_add(1, Ordering::Relaxed);
+                inbound
+                    .capture
+                    .record(Direction::Outbound, return_address, &return_buf);
+                if !request.quiet {
+                    debug!(%return_address, "Sent UDP ping response");
+                }
+            }
+            Err(e) => warn!(%return_address, error = %e, "Couldn't send UDP ping response"),
+        }
+    })
+}
+
+/// Stores `targets` in the state file, if there is one
+async fn save_targets(config: &Config, restart_count: u64, targets: &Targets) {
+    if let Some(state_file) = &config.state_file {
+        let state = PersistedState::from_targets(targets, restart_count, config.node_id.clone());
+        if let Err(e) = state.save(state_file).await {
+            warn!(state_file, error = %e, "Couldn't write state file");
+        }
+    }
+}
+
+/// The last `updateTarget` the node applied, to recognize retransmissions of it
+pub(crate) struct AppliedUpdate {
+    request_id: Option<Value>,
+    op: TargetOp,
+    host: String,
+    port: u16,
+    applied_at: Instant,
+}
+
+impl AppliedUpdate {
+    /// Whether an update with these fields is the same one, arriving again shortly after
+    fn is_repeated_by(
+        &self,
+        request_id: Option<&Value>,
+        op: TargetOp,
+        host: &str,
+        port: u16,
+    ) -> bool {
+        self.request_id.as_ref() == request_id
+            && self.op == op
+            && self.host == host
+            && self.port == port
+            && self.applied_at.elapsed() < DUPLICATE_UPDATE_WINDOW
+    }
+}
+
+/// Applies an `updateTarget` message to the current targets. Added targets are resolved first,
+/// removed ones are matched by host and port, so they don't need to resolve anymore.
+async fn apply_target_update(
+    targets: &watch::Sender<Targets>,
+    bind_address: IpAddr,
+    op: TargetOp,
+    host: &str,
+    port: u16,
+) -> Result<(), String> {
+    let target = match op {
+        TargetOp::Remove => None,
+        TargetOp::Replace | TargetOp::Add => Some(Target {
+            host: host.to_string(),
+            port,
+            address: resolve_address_from(host, port, bind_address)
+                .await
+                .map_err(|e| e.to_string())?,
+        }),
+    };
+    let mut result = Ok(());
+    targets.send_if_modified(|targets| match target {
+        None => {
+            result = targets.remove(host, port);
+            result.is_ok()
+        }
+        Some(target) if op == TargetOp::Add => targets.add(target),
+        Some(target) => {
+            targets.replace(target);
+            true
+        }
+    });
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::inbound_message_types;
+
+    #[test]
+    fn every_inbound_message_has_a_handler() {
+        let dispatcher = Dispatcher::new();
+        for message_type in inbound_message_types() {
+            assert!(
+                dispatcher.handlers.contains_key(message_type),
+                "no handler for {}",
+                message_type
+            );
+        }
+        assert_eq!(dispatcher.handlers.len(), inbound_message_types().count());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::http_server;
+use crate::metrics::Metrics;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+
+/// Serves `GET /healthz` (liveness) and `GET /readyz` (readiness) until a shutdown signal
+/// arrives. The node isn't ready once sending data has been failing for `failure_threshold`.
+pub async fn serve(
+    listener: &TcpListener,
+    metrics: Arc<Metrics>,
+    failure_threshold: Duration,
+    shutdown: broadcast::Receiver<()>,
+) {
+    let respond = move |path: &str| {
+        let status = match path {
+            "/healthz" => liveness(&metrics),
+            "/readyz" => readiness(&metrics, failure_threshold, Instant::now()),
+            _ => return None,
+        };
+        Some(match status {
+            Ok(()) => ("200 OK", "ok".to_string()),
+            Err(reason) => ("503 Service Unavailable", reason.to_string()),
+        })
+    };
+    http_server::serve(listener, "health", "text/plain", respond, shutdown).await
+}
+
+/// `Err` with the reason if a task of the node panicked and hasn't been restarted yet
+fn liveness(metrics: &Metrics) -> Result<(), &'static str> {
+    if metrics.tasks_down.load(Ordering::Relaxed) > 0 {
+        return Err("a task panicked");
+    }
+    Ok(())
+}
+
+/// `Err` with the reason if the node can't deliver data: nothing was sent and no ping was
+/// answered yet, or sending has been failing for at least `failure_threshold`
+fn readiness(
+    metrics: &Metrics,
+    failure_threshold: Duration,
+    now: Instant,
+) -> Result<(), &'static str> {
+    liveness(metrics)?;
+    if metrics
+        .send_failing_for(now)
+        .is_some_and(|failing_for| failing_for >= failure_threshold)
+    {
+        return Err("sending data keeps failing");
+    }
+    if metrics.data_packets_sent.load(Ordering::Relaxed) == 0
+        && metrics.ping_replies.load(Ordering::Relaxed) == 0
+    {
+        return Err("no data sent and no ping answered yet");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn not_ready_until_sent_or_while_failing() {
+        let metrics = Metrics::new();
+        let threshold = Duration::from_secs(30);
+        let target: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        assert!(readiness(&metrics, threshold, Instant::now()).is_err());
+
+        metrics.ping_replies.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(readiness(&metrics, threshold, Instant::now()), Ok(()));
+        metrics.count_sent();
+
+        // a short failure is tolerated
+        metrics.count_send_error(target);
+        assert_eq!(readiness(&metrics, threshold, Instant::now()), Ok(()));
+        assert!(readiness(&metrics, threshold, Instant::now() + threshold).is_err());
+        metrics.count_sent();
+        assert_eq!(
+            readiness(&metrics, threshold, Instant::now() + threshold),
+            Ok(())
+        );
+
+        metrics.tasks_down.fetch_add(1, Ordering::Relaxed);
+        assert!(liveness(&metrics).is_err());
+        assert!(readiness(&metrics, threshold, Instant::now()).is_err());
+        // restarted
+        metrics.tasks_down.fetch_sub(1, Ordering::Relaxed);
+        assert_eq!(liveness(&metrics), Ok(()));
+    }
+}
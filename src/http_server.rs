@@ -0,0 +1,110 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::time;
+use tracing::{debug, warn};
+
+/// How long a client may take to send its request before the connection is closed
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answers `GET` requests on `listener` until a shutdown signal arrives. `respond` maps a path to
+/// the status line (e.g. `200 OK`) and body of the response, `None` for a 404. `name` tells the
+/// server apart in the logs. Every connection is handled in its own task, so slow clients can't
+/// hold up anything else, and closed if it doesn't send a request within [`READ_TIMEOUT`].
+pub(crate) async fn serve<F>(
+    listener: &TcpListener,
+    name: &'static str,
+    content_type: &'static str,
+    respond: F,
+    mut shutdown: broadcast::Receiver<()>,
+) where
+    F: Fn(&str) -> Option<(&'static str, String)> + Send + Sync + 'static,
+{
+    let respond = Arc::new(respond);
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!(server = name, error = %e, "Couldn't accept connection");
+                    continue;
+                }
+            },
+            _ = shutdown.recv() => break,
+        };
+
+        let respond = respond.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, content_type, &*respond).await {
+                debug!(server = name, error = %e, "Connection failed");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    content_type: &str,
+    respond: &(dyn Fn(&str) -> Option<(&'static str, String)> + Send + Sync),
+) -> std::io::Result<()> {
+    // only the request line matters, so a single read is enough
+    let mut buf = [0; 1024];
+    let length = time::timeout(READ_TIMEOUT, stream.read(&mut buf))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "no request"))??;
+    let request = String::from_utf8_lossy(&buf[..length]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let response = match request.starts_with("GET ").then(|| respond(path)).flatten() {
+        Some((status_line, body)) => format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status_line,
+            content_type,
+            body.len(),
+            body
+        ),
+        None => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(address: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn answers_get_requests_for_known_paths() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let respond = |path: &str| (path == "/hello").then(|| ("200 OK", "hi".to_string()));
+        let server = tokio::spawn(async move {
+            serve(&listener, "test", "text/plain", respond, shutdown_rx).await
+        });
+
+        let response = get(address, "GET /hello HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhi"));
+        let response = get(address, "GET /other HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get(address, "POST /hello HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,51 @@
+//! A virtual input node for decentralized automation networks: it sends generated (or replayed)
+//! sensor values to flow processors over UDP and follows control messages telling it where to
+//! send them.
+//!
+//! The binary is a thin CLI wrapper around this crate. Other tools can use it to
+//! - run a node in-process, with [`run`] or [`Node`] (e.g. in tests), or several with [`fleet`],
+//! - build and parse compatible packets with [`protocol`], [`encoding`], [`compression`] and
+//!   [`target`],
+//! - sign and verify control messages with [`auth`],
+//! - check the sequence numbers of received data packets with [`verify`].
+
+mod ack;
+pub mod allowlist;
+pub mod auth;
+mod capture;
+pub mod clock;
+pub mod compression;
+pub mod config;
+#[cfg(feature = "dtls")]
+mod dtls;
+pub mod encoding;
+mod failover;
+mod faults;
+pub mod fleet;
+pub mod generator;
+mod handlers;
+mod health;
+mod http_server;
+pub mod logging;
+mod metrics;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod node;
+mod ping;
+pub mod protocol;
+mod ratelimit;
+mod receive_errors;
+pub mod reload;
+mod sink;
+mod state;
+mod supervisor;
+pub mod target;
+pub mod ticker;
+pub mod transport;
+mod url;
+pub mod verify;
+
+pub use config::Config;
+pub use node::{run, BoundPorts, Node};
+pub use reload::Reloader;
+pub use supervisor::TaskFailed;
@@ -0,0 +1,72 @@
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+use tracing_subscriber::EnvFilter;
+
+/// stdout, written by a background thread, once [`init`] was called
+static STDOUT: OnceLock<NonBlocking> = OnceLock::new();
+/// stderr, written by a background thread, if [`init`] was told to log there
+static STDERR: OnceLock<NonBlocking> = OnceLock::new();
+/// stdout for [`print_line`], written by a background thread without dropping lines
+static DATA: OnceLock<NonBlocking> = OnceLock::new();
+
+/// Flushes the buffered output when dropped
+pub struct LogGuard {
+    _guards: Vec<WorkerGuard>,
+}
+
+/// Logs to stdout, or to stderr if `to_stderr` is set, through a buffer written by a background
+/// thread, so a slow consumer (e.g. a stalled pipe) can't block the node's tasks. Lines that don't
+/// fit in the buffer are dropped and counted by [`dropped_lines`]. The buffer is flushed when the
+/// returned guard is dropped.
+///
+/// Output meant for other programs (see [`print_line`]) always goes to stdout, so logging to
+/// stderr keeps it free of diagnostics. It has a buffer of its own that is never dropped from.
+pub fn init(filter: EnvFilter, to_stderr: bool) -> LogGuard {
+    let (stdout, stdout_guard) = lossy(io::stdout());
+    let _ = STDOUT.set(stdout.clone());
+    let (data, data_guard) = NonBlockingBuilder::default()
+        .lossy(false)
+        .finish(io::stdout());
+    let _ = DATA.set(data);
+    let mut guards = vec![stdout_guard, data_guard];
+    let writer = if to_stderr {
+        let (stderr, stderr_guard) = lossy(io::stderr());
+        let _ = STDERR.set(stderr.clone());
+        guards.push(stderr_guard);
+        stderr
+    } else {
+        stdout
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .init();
+    LogGuard { _guards: guards }
+}
+
+fn lossy<W: Write + Send + 'static>(writer: W) -> (NonBlocking, WorkerGuard) {
+    NonBlockingBuilder::default().lossy(true).finish(writer)
+}
+
+/// Lines dropped so far because stdout (or stderr) couldn't keep up
+pub fn dropped_lines() -> u64 {
+    [&STDOUT, &STDERR]
+        .into_iter()
+        .filter_map(OnceLock::get)
+        .map(|writer| writer.error_counter().dropped_lines() as u64)
+        .sum()
+}
+
+/// Prints `line` to stdout through a background thread, waiting for room in its buffer instead of
+/// dropping the line, or directly if logging wasn't [initialized](init), e.g. when the node runs
+/// in tests
+pub(crate) fn print_line(line: &str) {
+    match DATA.get() {
+        // a single write, so the line isn't split up between log lines
+        Some(writer) => {
+            let _ = writer.clone().write_all(format!("{}\n", line).as_bytes());
+        }
+        None => println!("{}", line),
+    }
+}
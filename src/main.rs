@@ -1,268 +1,965 @@
 use clap::Parser;
-use futures::future;
-use serde_json::json;
-use std::sync::Mutex;
-use std::{error::Error, net::SocketAddr, sync::Arc, time::Duration};
-use tokio::{net::UdpSocket, time};
-
-#[derive(serde::Deserialize, Debug, Clone)]
-pub struct Config {
-    pub area: String,
-    pub flow_name: String,
-    pub target_ip: String,
-    pub target_port: u16,
-    pub outbound_port_data: u16,
-    pub outbound_port_acks: Option<u16>,
-    pub inbound_port: u16,
-    pub interval: Option<u64>,
-    pub inbound_poll_interval: Option<u64>,
-}
+use decentralized_automation_networks_virtual_input_node::{
+    allowlist::IpRange,
+    auth::{PresharedKey, SharedSecret},
+    clock::ClockSource,
+    compression::Compression,
+    config::{self, load_config, load_config_dir, parse_duration_ms, Config},
+    encoding::Encoding,
+    fleet,
+    generator::{OverflowPolicy, ValueType, Waveform},
+    logging::{self, LogGuard},
+    protocol::{self, PingReplyFormat},
+    target::{TargetAddress, UpdateTargetMode},
+    ticker::MissedTickBehavior,
+    transport::Transport,
+    verify, Node, Reloader,
+};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    process::ExitCode,
+    time::Duration,
+};
+use tracing::{debug, error, info};
+use tracing_subscriber::EnvFilter;
 
 /// A simple application emulating a physical input node
-#[derive(Parser, Debug)]
+///
+/// Every option can also be set via an environment variable (`VIN_<OPTION>`). Values are
+/// taken from CLI flags first, then environment variables, then the config file, then defaults.
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// area name
-    #[arg(short, long)]
+    #[arg(short, long, env = "VIN_AREA")]
     area: Option<String>,
     /// flow name
-    #[arg(short, long)]
-    flow: Option<String>,
-    /// The initial target ip
-    #[arg(short = 't', long)]
+    #[arg(short = 'f', long = "flow", env = "VIN_FLOW")]
+    flow_name: Option<String>,
+    /// The initial target ip or hostname
+    #[arg(short = 't', long, env = "VIN_TARGET_IP")]
     target_ip: Option<String>,
     /// The initial target port
-    #[arg(short = 'p', long)]
+    #[arg(short = 'p', long, env = "VIN_TARGET_PORT")]
     target_port: Option<u16>,
+    /// additional targets (`<host>:<port>`, comma-separated) that receive every data packet as well
+    #[arg(long, env = "VIN_TARGETS", value_delimiter = ',')]
+    targets: Option<Vec<TargetAddress>>,
     /// The outgoing port for sending data
-    #[arg(short, long)]
+    #[arg(short, long, env = "VIN_OUTBOUND_PORT_DATA")]
     outbound_port_data: Option<u16>,
-    /// The outgoing port for sending ACKs
-    #[arg(long, default_value = "0")]
-    outbound_port_acks: u16,
+    /// The outgoing port for sending ACKs [default: 0]
+    #[arg(long, env = "VIN_OUTBOUND_PORT_ACKS")]
+    outbound_port_acks: Option<u16>,
+    /// local IP address all sockets are bound to [default: 0.0.0.0]
+    #[arg(long, env = "VIN_BIND_ADDRESS")]
+    bind_address: Option<IpAddr>,
+    /// set `SO_REUSEADDR` on the UDP sockets, to rebind the ports right after a restart [default: false]
+    #[arg(long, env = "VIN_REUSE_ADDRESS", num_args = 0..=1, default_missing_value = "true")]
+    reuse_address: Option<bool>,
+    /// send buffer size (bytes) of the UDP sockets (OS default if unset)
+    #[arg(long, env = "VIN_SOCKET_SEND_BUFFER")]
+    socket_send_buffer: Option<usize>,
+    /// receive buffer size (bytes) of the UDP sockets (OS default if unset)
+    #[arg(long, env = "VIN_SOCKET_RECV_BUFFER")]
+    socket_recv_buffer: Option<usize>,
     /// The incoming port
-    #[arg(short = 'i', long)]
+    #[arg(short = 'i', long, env = "VIN_INBOUND_PORT")]
     inbound_port: Option<u16>,
-    /// data interval (ms)
-    #[arg(long, default_value = "1000")]
-    interval: u64,
-    /// inbound poll interval (ms)
-    #[arg(long, default_value = "10")]
-    inbound_poll_interval: u64,
+    /// data interval (ms, or with a unit like `2s` or `1m`) [default: 1000]
+    #[arg(long, env = "VIN_INTERVAL", value_parser = parse_duration_ms)]
+    interval: Option<u64>,
+    /// vary every data interval randomly by up to ± this much (ms) [default: 0]
+    #[arg(long, env = "VIN_JITTER_MS")]
+    jitter_ms: Option<u64>,
+    /// wait this long (ms) before generating the first sample, e.g. for receivers starting at the same time [default: 0]
+    #[arg(long, env = "VIN_STARTUP_DELAY_MS")]
+    startup_delay_ms: Option<u64>,
+    /// what the data interval does after missing ticks because the node was busy [default: skip]
+    #[arg(long, value_enum, env = "VIN_MISSED_TICK_BEHAVIOR")]
+    missed_tick_behavior: Option<MissedTickBehavior>,
+    /// inbound poll interval (ms, or with a unit like `50ms`) [default: 10]
+    #[arg(long, env = "VIN_INBOUND_POLL_INTERVAL", value_parser = parse_duration_ms)]
+    inbound_poll_interval: Option<u64>,
+    /// inbound receive buffer size (bytes) [default: 1024]
+    #[arg(long, env = "VIN_INBOUND_BUFFER_SIZE")]
+    inbound_buffer_size: Option<usize>,
+    /// shape of the generated data [default: random]
+    #[arg(long, value_enum, env = "VIN_WAVEFORM")]
+    waveform: Option<Waveform>,
+    /// waveform amplitude (ignored for `random`) [default: 32767]
+    #[arg(long, env = "VIN_AMPLITUDE")]
+    amplitude: Option<f64>,
+    /// waveform offset, i.e. the center value (ignored for `random`) [default: 32768]
+    #[arg(long, env = "VIN_OFFSET")]
+    offset: Option<f64>,
+    /// waveform period (ms, ignored for `random` and `constant`) [default: 10000]
+    #[arg(long, env = "VIN_PERIOD_MS")]
+    period_ms: Option<u64>,
+    /// standard deviation of Gaussian noise added to every value [default: 0]
+    #[arg(long, env = "VIN_NOISE_STDDEV")]
+    noise_stddev: Option<f64>,
+    /// numeric type of the emitted values [default: u16]
+    #[arg(long, value_enum, env = "VIN_VALUE_TYPE")]
+    value_type: Option<ValueType>,
+    /// lowest value, e.g. 0 for a 12-bit ADC (with `value_max`, replaces `offset` and `amplitude`)
+    #[arg(long, env = "VIN_VALUE_MIN", allow_negative_numbers = true)]
+    value_min: Option<f64>,
+    /// highest value, e.g. 4095 for a 12-bit ADC (with `value_min`, replaces `offset` and `amplitude`)
+    #[arg(long, env = "VIN_VALUE_MAX", allow_negative_numbers = true)]
+    value_max: Option<f64>,
+    /// factor applied to every value, as `value * scale + offset` [default: 1]
+    #[arg(long, env = "VIN_CALIBRATION_SCALE")]
+    calibration_scale: Option<f64>,
+    /// offset added to every scaled value [default: 0]
+    #[arg(long, env = "VIN_CALIBRATION_OFFSET", allow_negative_numbers = true)]
+    calibration_offset: Option<f64>,
+    /// round calibrated values to integers [default: false]
+    #[arg(long, env = "VIN_CALIBRATION_ROUND", num_args = 0..=1, default_missing_value = "true")]
+    calibration_round: Option<bool>,
+    /// include the value before calibration as `meta.raw` [default: false]
+    #[arg(long, env = "VIN_INCLUDE_RAW", num_args = 0..=1, default_missing_value = "true")]
+    include_raw: Option<bool>,
+    /// with `channels` in the config file, send the latest values of all channels together every interval [default: false]
+    #[arg(long, env = "VIN_AGGREGATE", num_args = 0..=1, default_missing_value = "true")]
+    aggregate: Option<bool>,
+    /// seed for random values, so runs can be reproduced exactly (nondeterministic if unset)
+    #[arg(long, env = "VIN_SEED")]
+    seed: Option<u64>,
+    /// how many generated samples can wait to be sent [default: 16]
+    #[arg(long, env = "VIN_DATA_QUEUE_SIZE")]
+    data_queue_size: Option<usize>,
+    /// which sample to drop when the send queue is full [default: drop_oldest]
+    #[arg(long, value_enum, env = "VIN_OVERFLOW_POLICY")]
+    overflow_policy: Option<OverflowPolicy>,
+    /// how often sending a data packet is retried before it is dropped [default: 5]
+    #[arg(long, env = "VIN_SEND_MAX_RETRIES")]
+    send_max_retries: Option<u32>,
+    /// for testing receivers: chance (%) that a data packet is dropped on purpose [default: 0]
+    #[arg(long, env = "VIN_SIMULATE_LOSS_PERCENT")]
+    simulate_loss_percent: Option<f64>,
+    /// for testing receivers: chance (%) that a data packet is sent twice [default: 0]
+    #[arg(long, env = "VIN_SIMULATE_DUP_PERCENT")]
+    simulate_dup_percent: Option<f64>,
+    /// for testing receivers: delay data packets by a random duration up to this (ms) [default: 0]
+    #[arg(long, env = "VIN_SIMULATE_DELAY_MS")]
+    simulate_delay_ms: Option<u64>,
+    /// replay values from a file (separated by newlines or commas) instead of generating them
+    #[arg(long, env = "VIN_REPLAY_FILE")]
+    replay_file: Option<String>,
+    /// start over once the end of the replay file is reached [default: false]
+    #[arg(long, env = "VIN_REPLAY_LOOP", num_args = 0..=1, default_missing_value = "true")]
+    replay_loop: Option<bool>,
+    /// include the send time (`sent_at_micros`) in data packets [default: true]
+    #[arg(long, env = "VIN_TIMESTAMP_DATA", num_args = 0..=1, default_missing_value = "true")]
+    timestamp_data: Option<bool>,
+    /// where timestamps come from: the system time, or the system time at startup advanced by the monotonic clock, unaffected by clock adjustments [default: wall]
+    #[arg(long, value_enum, env = "VIN_CLOCK_SOURCE")]
+    clock_source: Option<ClockSource>,
+    /// include a CRC-32 of the value (`crc32`) in data packets [default: false]
+    #[arg(long, env = "VIN_CHECKSUM", num_args = 0..=1, default_missing_value = "true")]
+    checksum: Option<bool>,
+    /// send `message` in JSON and MessagePack packets as a number instead of a string [default: false]
+    #[arg(long, env = "VIN_EMIT_MESSAGE_AS_NUMBER", num_args = 0..=1, default_missing_value = "true")]
+    emit_message_as_number: Option<bool>,
+    /// how data packets are encoded [default: json]
+    #[arg(long, value_enum, env = "VIN_ENCODING")]
+    encoding: Option<Encoding>,
+    /// how data packets are compressed, behind a byte telling the codec (no header with `none`) [default: none]
+    #[arg(long, value_enum, env = "VIN_COMPRESSION")]
+    compression: Option<Compression>,
+    /// how data packets are sent to the targets [default: udp]
+    #[arg(long, value_enum, env = "VIN_TRANSPORT")]
+    transport: Option<Transport>,
+    /// where data packets are POSTed with `--transport http`, `http://<host>[:<port>][/<path>]`
+    #[arg(long, env = "VIN_HTTP_URL")]
+    http_url: Option<String>,
+    /// how long a POST with `--transport http` may take (ms) [default: 5000]
+    #[arg(long, env = "VIN_HTTP_TIMEOUT_MS")]
+    http_timeout_ms: Option<u64>,
+    /// hex-encoded pre-shared key for `--transport dtls`, instead of certificates
+    #[arg(long, env = "VIN_DTLS_PSK", hide_env_values = true)]
+    dtls_psk: Option<PresharedKey>,
+    /// the identity sent along with `--dtls-psk` [default: the flow name]
+    #[arg(long, env = "VIN_DTLS_PSK_IDENTITY")]
+    dtls_psk_identity: Option<String>,
+    /// CA certificates (PEM) to check the targets' certificates against with `--transport dtls` (the system's if unset)
+    #[arg(long, env = "VIN_DTLS_CA_FILE")]
+    dtls_ca_file: Option<String>,
+    /// the name the targets' certificates must be issued for (their IP addresses if unset)
+    #[arg(long, env = "VIN_DTLS_SERVER_NAME")]
+    dtls_server_name: Option<String>,
+    /// client certificate (PEM) presented to the targets with `--transport dtls`
+    #[arg(long, env = "VIN_DTLS_CERT_FILE")]
+    dtls_cert_file: Option<String>,
+    /// private key (PEM) of `--dtls-cert-file`
+    #[arg(long, env = "VIN_DTLS_KEY_FILE")]
+    dtls_key_file: Option<String>,
+    /// also publish data packets to this MQTT broker, `mqtt://<host>[:<port>]` (needs the `mqtt` feature)
+    #[arg(long, env = "VIN_MQTT_URL")]
+    mqtt_url: Option<String>,
+    /// MQTT topic, `{area}` and `{flow}` are replaced by the node's area and flow name [default: vin/{area}/{flow}]
+    #[arg(long, env = "VIN_MQTT_TOPIC")]
+    mqtt_topic: Option<String>,
+    /// MQTT QoS level (0, 1 or 2) [default: 0]
+    #[arg(long, env = "VIN_MQTT_QOS")]
+    mqtt_qos: Option<u8>,
+    /// only publish to MQTT, without sending to the targets [default: false]
+    #[arg(long, env = "VIN_MQTT_ONLY", num_args = 0..=1, default_missing_value = "true")]
+    mqtt_only: Option<bool>,
+    /// how many samples are sent together in one packet [default: 1]
+    #[arg(long, env = "VIN_BATCH_SIZE")]
+    batch_size: Option<usize>,
+    /// send an incomplete batch once its first sample is this old (ms) [default: 1000]
+    #[arg(long, env = "VIN_BATCH_TIMEOUT_MS")]
+    batch_timeout_ms: Option<u64>,
+    /// split batches into packets of at most this many bytes, e.g. to stay below the MTU (no limit if unset)
+    #[arg(long, env = "VIN_MAX_PAYLOAD_BYTES")]
+    max_payload_bytes: Option<usize>,
+    /// re-resolve the target hostname every <TARGET_RESOLVE_INTERVAL> ms (off if unset)
+    #[arg(long, env = "VIN_TARGET_RESOLVE_INTERVAL")]
+    target_resolve_interval: Option<u64>,
+    /// file used to remember the current target across restarts
+    #[arg(long, env = "VIN_STATE_FILE")]
+    state_file: Option<String>,
+    /// identifies the node in data packets and replies, generated and kept in the state file if unset
+    #[arg(long, env = "VIN_NODE_ID")]
+    node_id: Option<String>,
+    /// append every message sent and received to this file, as JSON lines (off if unset)
+    #[arg(long, env = "VIN_CAPTURE_FILE")]
+    capture_file: Option<String>,
+    /// rotate the capture file once it would grow beyond this size (bytes) [default: 100000000]
+    #[arg(long, env = "VIN_CAPTURE_MAX_BYTES")]
+    capture_max_bytes: Option<u64>,
+    /// how often each ACK is sent [default: 10]
+    #[arg(long, env = "VIN_ACK_REPEAT")]
+    ack_repeat: Option<u32>,
+    /// delay between repeated ACKs (ms) [default: 0]
+    #[arg(long, env = "VIN_ACK_REPEAT_INTERVAL_MS")]
+    ack_repeat_interval_ms: Option<u64>,
+    /// how often an ACK with a `request_id` is sent at most while waiting for confirmation [default: 10]
+    #[arg(long, env = "VIN_ACK_CONFIRM_MAX_ATTEMPTS")]
+    ack_confirm_max_attempts: Option<u32>,
+    /// serve Prometheus metrics over HTTP on this port (off if unset)
+    #[arg(long, env = "VIN_METRICS_PORT")]
+    metrics_port: Option<u16>,
+    /// serve `/healthz` and `/readyz` over HTTP on this port (off if unset)
+    #[arg(long, env = "VIN_HEALTH_PORT")]
+    health_port: Option<u16>,
+    /// report not ready on `/readyz` once sending data has failed for this long (ms) [default: 30000]
+    #[arg(long, env = "VIN_READINESS_FAILURE_MS")]
+    readiness_failure_ms: Option<u64>,
+    /// restart a task of the node that panicked up to this many times, then shut down the node [default: 5]
+    #[arg(long, env = "VIN_TASK_MAX_RESTARTS")]
+    task_max_restarts: Option<u32>,
+    /// ping the current target every <PING_INTERVAL_MS> ms and log RTT statistics (off if unset)
+    #[arg(long, env = "VIN_PING_INTERVAL_MS")]
+    ping_interval_ms: Option<u64>,
+    /// log RTT statistics after this many ping replies [default: 10]
+    #[arg(long, env = "VIN_PING_REPORT_EVERY")]
+    ping_report_every: Option<u32>,
+    /// send a heartbeat to the current targets every <HEARTBEAT_INTERVAL_MS> ms, even while data is paused (off if unset)
+    #[arg(long, env = "VIN_HEARTBEAT_INTERVAL_MS")]
+    heartbeat_interval_ms: Option<u64>,
+    /// only accept control messages from these IPs or CIDR ranges (comma-separated, any source if unset)
+    #[arg(long, env = "VIN_CONTROLLER_ALLOWLIST", value_delimiter = ',')]
+    controller_allowlist: Option<Vec<IpRange>>,
+    /// drop inbound messages beyond this many per second from a single source IP, without parsing them (unlimited if unset)
+    #[arg(long, env = "VIN_INBOUND_RATE_LIMIT")]
+    inbound_rate_limit: Option<u32>,
+    /// apply `controller_allowlist` to UDP pings too [default: false]
+    #[arg(long, env = "VIN_ALLOWLIST_PINGS", num_args = 0..=1, default_missing_value = "true")]
+    allowlist_pings: Option<bool>,
+    /// how to answer `udpPing`: `raw` bytes (`udpPong` JSON if the ping has a nonce) or always `json` [default: raw]
+    #[arg(long, value_enum, env = "VIN_PING_REPLY_FORMAT")]
+    ping_reply_format: Option<PingReplyFormat>,
+    /// require control messages to carry a valid `hmac` computed with this secret, and sign ACKs with it
+    #[arg(long, env = "VIN_SHARED_SECRET", hide_env_values = true)]
+    shared_secret: Option<SharedSecret>,
+    /// how `updateTarget` specifies the new port [default: base_offset]
+    #[arg(long, value_enum, env = "VIN_UPDATE_TARGET_MODE")]
+    update_target_mode: Option<UpdateTargetMode>,
+    /// backup targets (`<host>:<port>`, comma-separated, in order of preference) to fail over to when the primary target stops answering pings
+    #[arg(long, env = "VIN_TARGET_FAILOVER", value_delimiter = ',')]
+    target_failover: Option<Vec<TargetAddress>>,
+    /// how often every failover target is pinged (ms) [default: 1000]
+    #[arg(long, env = "VIN_FAILOVER_CHECK_INTERVAL_MS")]
+    failover_check_interval_ms: Option<u64>,
+    /// a failover target that hasn't answered a ping for this long is unreachable (ms) [default: 3000]
+    #[arg(long, env = "VIN_FAILOVER_TIMEOUT_MS")]
+    failover_timeout_ms: Option<u64>,
+    /// ping replies slower than this count as unreachable (ms, off if unset)
+    #[arg(long, env = "VIN_FAILOVER_MAX_RTT_MS")]
+    failover_max_rtt_ms: Option<u64>,
+    /// how long a preferred target has to be reachable before failing back to it (ms) [default: 10000]
+    #[arg(long, env = "VIN_FAILBACK_STABLE_MS")]
+    failback_stable_ms: Option<u64>,
+    /// don't log every sent packet and received message [default: false]
+    #[arg(short, long, env = "VIN_QUIET", num_args = 0..=1, default_missing_value = "true")]
+    quiet: Option<bool>,
+    /// print every data packet instead of sending it, without listening for control messages [default: false]
+    #[arg(long, env = "VIN_DRY_RUN", num_args = 0..=1, default_missing_value = "true")]
+    dry_run: Option<bool>,
+    /// treat suspicious config values (e.g. two sockets on the same port) as errors instead of warnings [default: false]
+    #[arg(long, env = "VIN_STRICT", num_args = 0..=1, default_missing_value = "true")]
+    strict: Option<bool>,
     /// config file
-    #[arg(short, long)]
+    #[arg(short, long, env = "VIN_CONFIG")]
     config: Option<String>,
+    /// directory of config files, running a node for each of them in this process
+    #[arg(long, env = "VIN_CONFIG_DIR")]
+    config_dir: Option<String>,
+    /// restart a node of --config-dir that crashed instead of leaving it stopped [default: false]
+    #[arg(long, env = "VIN_RESTART_CRASHED_NODES", num_args = 0..=1, default_missing_value = "true")]
+    restart_crashed_nodes: Option<bool>,
+    /// print the effective config as JSON and exit
+    #[arg(long)]
+    print_config: bool,
+    /// print a JSON description of all messages the node sends and accepts and exit
+    #[arg(long)]
+    dump_protocol: bool,
+    /// print a JSON description of all config keys (type, whether required, default) and exit
+    #[arg(long)]
+    list_config_keys: bool,
+    /// send a single data packet and exit, with a non-zero code if sending failed
+    #[arg(long)]
+    once: bool,
+    /// log level or filter directives, overrides RUST_LOG [default: info]
+    #[arg(long, env = "VIN_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// log to stderr, leaving stdout to dry-run packets and the JSON printed by --print-config etc. [default: false]
+    #[arg(long, env = "VIN_LOG_STDERR", num_args = 0..=1, default_missing_value = "true")]
+    log_stderr: Option<bool>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Command {
+    /// Send data to a listener on this host for a while and report lost, duplicated and reordered
+    /// samples, exiting with a non-zero code if there were any. Node options go before `verify`.
+    Verify {
+        /// how long to send data (s)
+        #[arg(long, default_value_t = 10)]
+        duration: u64,
+        /// port of the listener checking the data packets, on `bind_address` [default: target_port]
+        #[arg(long)]
+        listen_port: Option<u16>,
+    },
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let args = Args::parse();
-    let config: Config;
-
-    // load config form file and apply overwrites if given
-    match args.config {
-        Some(config_path) => match load_config(config_path.as_str()).as_mut() {
-            Ok(loaded_config) => {
-                if loaded_config.outbound_port_acks.is_none() {
-                    loaded_config.outbound_port_acks = Some(args.outbound_port_acks);
-                }
-                if loaded_config.interval.is_none() {
-                    loaded_config.interval = Some(args.interval);
-                }
-                if loaded_config.inbound_poll_interval.is_none() {
-                    loaded_config.inbound_poll_interval = Some(args.inbound_poll_interval);
-                }
-
-                config = loaded_config.clone();
-                println!("Config loaded: {:?}", config);
-            }
-            Err(e) => {
-                panic!("Couldn't load config: {}", e);
-            }
-        },
-        None => {
-            // create config from args
-            config = Config {
-                area: args
-                    .area
-                    .expect("Argument `area` is required unless a config file is specified!"),
-                flow_name: args
-                    .flow
-                    .expect("Argument `flow` is required unless a config file is specified!"),
-                target_ip: args
-                    .target_ip
-                    .expect("Argument `target_ip` is required unless a config file is specified!"),
-                target_port: args.target_port.expect(
-                    "Argument `target_port` is required unless a config file is specified!",
-                ),
-                outbound_port_data: args.outbound_port_data.expect(
-                    "Argument `outbound_port_data` is required unless a config file is specified!",
-                ),
-                outbound_port_acks: Some(args.outbound_port_acks),
-                inbound_port: args.inbound_port.expect(
-                    "Argument `inbound_port` is required unless a config file is specified!",
-                ),
-                interval: Some(args.interval),
-                inbound_poll_interval: Some(args.inbound_poll_interval),
-            };
+    let _log_guard = match init_logging(args.log_level.as_deref(), args.log_stderr.unwrap_or(false))
+    {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // a readable message instead of the debug output of returning the error from `main`
+    let exit_code = match try_main(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
         }
+    };
+    // written directly, the buffered output is what couldn't keep up
+    let dropped_lines = logging::dropped_lines();
+    if dropped_lines > 0 {
+        eprintln!(
+            "Dropped {} log lines because the output couldn't keep up",
+            dropped_lines
+        );
     }
+    exit_code
+}
 
-    println!(
-        "Starting input node for flow '{}' @ area '{}'",
-        config.flow_name, config.area
-    );
-
-    let target: Arc<Mutex<SocketAddr>> = Arc::new(Mutex::new(
-        format!("{}:{}", config.target_ip, config.target_port)
-            .parse::<SocketAddr>()
-            .expect("No valid target address given. Use format: <ip>:<port>"),
-    ));
-
-    let outbound_socket_data = UdpSocket::bind(format!("0.0.0.0:{}", config.outbound_port_data))
-        .await
-        .expect("Couldn't bind outbound socket");
-    let outbound_socket_acks =
-        UdpSocket::bind(format!("0.0.0.0:{}", config.outbound_port_acks.unwrap()))
-            .await
-            .expect("Couldn't bind outbound socket");
-    let inbound_socket = UdpSocket::bind(format!("0.0.0.0:{}", config.inbound_port))
-        .await
-        .expect("Couldn't bind inbound socket");
+async fn try_main(args: Args) -> Result<(), Box<dyn Error>> {
+    if args.dump_protocol {
+        println!("{}", serde_json::to_string_pretty(&protocol::describe())?);
+        return Ok(());
+    }
+    if args.list_config_keys {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&config::describe_keys())?
+        );
+        return Ok(());
+    }
 
-    let mut buf = [0; 1024];
+    if let Some(config_dir) = args.config_dir.clone() {
+        return run_config_dir(args, &config_dir).await;
+    }
 
-    let mut tasks: Vec<tokio::task::JoinHandle<()>> = vec![];
+    let file_config = match &args.config {
+        Some(config_path) => {
+            let loaded_config = load_config(config_path.as_str())
+                .map_err(|e| format!("Couldn't load config from '{}': {}", config_path, e))?;
+            Some(loaded_config)
+        }
+        None => None,
+    };
+    let print_config = args.print_config;
+    let once = args.once;
+    let command = args.command.clone();
+    let config = resolve_config(args.clone(), file_config)?;
 
-    let target_data_clone = target.clone();
+    config
+        .validate()
+        .map_err(|e| format!("Invalid config: {}", e))?;
 
-    // generate and send input data
-    tasks.push(tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_millis(config.interval.unwrap()));
+    if print_config {
+        println!("{}", serde_json::to_string_pretty(&config)?);
+        return Ok(());
+    }
+    debug!(?config, "Config loaded");
 
-        loop {
-            interval.tick().await;
+    if once {
+        return Node::bind(config).await?.send_once().await;
+    }
+    if let Some(Command::Verify {
+        duration,
+        listen_port,
+    }) = command
+    {
+        let config = config.with_defaults();
+        let listen_address = SocketAddr::new(
+            config.bind_address.unwrap(),
+            listen_port.unwrap_or(config.target_port),
+        );
+        let report = verify::run(config, Duration::from_secs(duration), listen_address).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if report.received == 0 {
+            return Err(format!(
+                "No data packets arrived at {}, does the node's target lead there?",
+                listen_address
+            )
+            .into());
+        }
+        if !report.passed() {
+            return Err(format!(
+                "Verification failed: {} samples lost, {} duplicated, {} reordered, {} invalid packets",
+                report.lost, report.duplicated, report.reordered, report.invalid
+            )
+            .into());
+        }
+        return Ok(());
+    }
+    let node = Node::bind(config).await?;
+    #[cfg(unix)]
+    if let Some(config_path) = args.config.clone() {
+        tokio::spawn(reload_on_sighup(args, config_path, node.reloader()));
+    }
+    node.run(shutdown_signal()).await?;
+    Ok(())
+}
 
-            let data = generate_input_data();
+/// Runs a node for every config file in `config_dir`. CLI flags and environment variables apply to
+/// all of them.
+async fn run_config_dir(args: Args, config_dir: &str) -> Result<(), Box<dyn Error>> {
+    if args.config.is_some() || args.once || args.command.is_some() {
+        return Err("--config-dir can't be combined with --config, --once or verify".into());
+    }
+    let mut nodes = vec![];
+    for (path, file_config) in load_config_dir(config_dir)
+        .map_err(|e| format!("Couldn't load configs from '{}': {}", config_dir, e))?
+    {
+        let config = resolve_config(args.clone(), Some(file_config))
+            .map_err(|e| format!("Invalid config in '{}': {}", path, e))?;
+        config
+            .validate()
+            .map_err(|e| format!("Invalid config in '{}': {}", path, e))?;
+        nodes.push((path, config));
+    }
 
-            let json = json!({
-                "message": data.to_string(),
-                "meta": {
-                    "flow_name": config.flow_name,
-                    "execution_area": config.area
-                }
-            });
+    if args.print_config {
+        let configs: BTreeMap<_, _> = nodes.into_iter().collect();
+        println!("{}", serde_json::to_string_pretty(&configs)?);
+        return Ok(());
+    }
+    fleet::run(
+        nodes,
+        args.restart_crashed_nodes.unwrap_or(false),
+        shutdown_signal(),
+    )
+    .await
+}
 
-            let target = *target_data_clone.lock().unwrap();
-            println!("Sending data to {}: {}", target, data);
-            outbound_socket_data
-                .send_to(json.to_string().as_bytes(), target)
-                .await
-                .expect("Couldn't send data");
+/// Reloads the config file whenever the process receives SIGHUP. CLI flags and environment
+/// variables still take precedence over it. An invalid config is logged and ignored.
+#[cfg(unix)]
+async fn reload_on_sighup(args: Args, config_path: String, reloader: Reloader) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!(error = %e, "Couldn't install SIGHUP handler, the config can't be reloaded");
+            return;
         }
-    }));
-
-    let target_updates_clone = target.clone();
-
-    // receive target updates
-    tasks.push(tokio::spawn(async move {
-        loop {
-            // check socket for incoming data
-            if let Ok((message_length, src)) = inbound_socket.recv_from(&mut buf).await {
-                // convert to string
-                let message = String::from_utf8(buf[..message_length].into())
-                    .expect("Couldn't convert to String");
-                println!("Received data from {}: {}", src, message);
-
-                // parse json
-                let json: serde_json::Value =
-                    serde_json::from_str(&message).expect("Couldn't parse JSON");
-                if let Some(message_type) = json["type"].as_str() {
-                    match message_type {
-                        "updateTarget" => {
-                            // take 10k part from the new target port and fill the rest with the old one
-                            let new_target_port_base = json["target_port_base"]
-                                .as_u64()
-                                .expect("No target base port given")
-                                as u16;
-                            let new_target_port =
-                                new_target_port_base + (config.target_port % 10000);
-                            println!("New target port: {}", new_target_port);
-
-                            let new_target_address_string = format!(
-                                "{}:{}",
-                                json["target"].as_str().expect("No target ip given"),
-                                new_target_port
-                            );
-                            let new_target_address = new_target_address_string
-                                .parse::<SocketAddr>()
-                                .unwrap_or_else(|_| {
-                                    panic!(
-                                        "Target not updated because target address was invalid: {}",
-                                        new_target_address_string
-                                    )
-                                });
-                            {
-                                let mut target = target_updates_clone.lock().unwrap();
-                                *target = new_target_address;
-                            }
-
-                            // acknowledge
-                            let json = json!({
-                                "type": "updateTarget",
-                                "success": true,
-                            });
-                            println!("Sending ACK to {}: {}", src, json);
-                            // send 10 times to "make sure" it arrives
-                            for _ in 0..10 {
-                                outbound_socket_acks
-                                    .send_to(json.to_string().as_bytes(), src)
-                                    .await
-                                    .expect("Couldn't send ACK");
-                            }
-                        }
-                        "udpPing" => {
-                            let start = std::time::SystemTime::now();
-                            let time = start
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .expect("Couldn't get system time");
-                            let return_buf = (time.as_micros() as u64).to_be_bytes();
-                            let return_address = json["replyTo"]
-                                .as_str()
-                                .unwrap()
-                                .parse::<SocketAddr>()
-                                .expect("No return address given");
-                            // send current system time back to sender
-                            outbound_socket_acks
-                                .send_to(&return_buf, &return_address)
-                                .await
-                                .unwrap();
-                            println!("Sent UDP ping response to {}", return_address);
-                        }
-                        _ => {}
-                    }
-                }
-            } else {
-                // no data received
-                // println!("No data received")
-            }
+    };
+    while hangup.recv().await.is_some() {
+        info!(config_path, "SIGHUP received, reloading config");
+        let config = load_config(&config_path)
+            .map_err(|e| format!("Couldn't load config from '{}': {}", config_path, e))
+            .and_then(|file_config| {
+                resolve_config(args.clone(), Some(file_config)).map_err(|e| e.to_string())
+            })
+            .and_then(|config| {
+                config
+                    .validate()
+                    .map_err(|e| format!("Invalid config: {}", e))?;
+                Ok(config)
+            });
+        match config {
+            Ok(config) => reloader.reload(config),
+            Err(e) => error!("Couldn't reload config, keeping the current one: {}", e),
         }
-    }));
+    }
+}
+
+/// Merges CLI args (which already include environment variables) with the config file and
+/// fills in defaults for anything that is still unset
+fn resolve_config(args: Args, file_config: Option<Config>) -> Result<Config, Box<dyn Error>> {
+    let file = file_config.as_ref();
+
+    let config = Config {
+        area: args
+            .area
+            .or_else(|| file.map(|f| f.area.clone()))
+            .ok_or_else(|| missing_value_error("area", "--area", "VIN_AREA"))?,
+        flow_name: args
+            .flow_name
+            .or_else(|| file.map(|f| f.flow_name.clone()))
+            .ok_or_else(|| missing_value_error("flow_name", "--flow", "VIN_FLOW"))?,
+        target_ip: args
+            .target_ip
+            .or_else(|| file.map(|f| f.target_ip.clone()))
+            .ok_or_else(|| missing_value_error("target_ip", "--target-ip", "VIN_TARGET_IP"))?,
+        target_port: args
+            .target_port
+            .or_else(|| file.map(|f| f.target_port))
+            .ok_or_else(|| {
+                missing_value_error("target_port", "--target-port", "VIN_TARGET_PORT")
+            })?,
+        targets: args
+            .targets
+            .or_else(|| file.and_then(|f| f.targets.clone())),
+        outbound_port_data: args
+            .outbound_port_data
+            .or_else(|| file.map(|f| f.outbound_port_data))
+            .ok_or_else(|| {
+                missing_value_error(
+                    "outbound_port_data",
+                    "--outbound-port-data",
+                    "VIN_OUTBOUND_PORT_DATA",
+                )
+            })?,
+        outbound_port_acks: args
+            .outbound_port_acks
+            .or(file.and_then(|f| f.outbound_port_acks)),
+        bind_address: args.bind_address.or(file.and_then(|f| f.bind_address)),
+        reuse_address: args.reuse_address.or(file.and_then(|f| f.reuse_address)),
+        socket_send_buffer: args
+            .socket_send_buffer
+            .or(file.and_then(|f| f.socket_send_buffer)),
+        socket_recv_buffer: args
+            .socket_recv_buffer
+            .or(file.and_then(|f| f.socket_recv_buffer)),
+        inbound_port: args
+            .inbound_port
+            .or_else(|| file.map(|f| f.inbound_port))
+            .ok_or_else(|| {
+                missing_value_error("inbound_port", "--inbound-port", "VIN_INBOUND_PORT")
+            })?,
+        interval: args.interval.or(file.and_then(|f| f.interval)),
+        jitter_ms: args.jitter_ms.or(file.and_then(|f| f.jitter_ms)),
+        startup_delay_ms: args
+            .startup_delay_ms
+            .or(file.and_then(|f| f.startup_delay_ms)),
+        missed_tick_behavior: args
+            .missed_tick_behavior
+            .or(file.and_then(|f| f.missed_tick_behavior)),
+        inbound_poll_interval: args
+            .inbound_poll_interval
+            .or(file.and_then(|f| f.inbound_poll_interval)),
+        inbound_buffer_size: args
+            .inbound_buffer_size
+            .or(file.and_then(|f| f.inbound_buffer_size)),
+        waveform: args.waveform.or(file.and_then(|f| f.waveform)),
+        amplitude: args.amplitude.or(file.and_then(|f| f.amplitude)),
+        offset: args.offset.or(file.and_then(|f| f.offset)),
+        period_ms: args.period_ms.or(file.and_then(|f| f.period_ms)),
+        noise_stddev: args.noise_stddev.or(file.and_then(|f| f.noise_stddev)),
+        value_type: args.value_type.or(file.and_then(|f| f.value_type)),
+        value_min: args.value_min.or(file.and_then(|f| f.value_min)),
+        value_max: args.value_max.or(file.and_then(|f| f.value_max)),
+        calibration_scale: args
+            .calibration_scale
+            .or(file.and_then(|f| f.calibration_scale)),
+        calibration_offset: args
+            .calibration_offset
+            .or(file.and_then(|f| f.calibration_offset)),
+        calibration_round: args
+            .calibration_round
+            .or(file.and_then(|f| f.calibration_round)),
+        include_raw: args.include_raw.or(file.and_then(|f| f.include_raw)),
+        // only in the config file, there is no sensible CLI syntax for a list of generators
+        channels: file.and_then(|f| f.channels.clone()),
+        aggregate: args.aggregate.or(file.and_then(|f| f.aggregate)),
+        seed: args.seed.or(file.and_then(|f| f.seed)),
+        data_queue_size: args
+            .data_queue_size
+            .or(file.and_then(|f| f.data_queue_size)),
+        overflow_policy: args
+            .overflow_policy
+            .or(file.and_then(|f| f.overflow_policy)),
+        send_max_retries: args
+            .send_max_retries
+            .or(file.and_then(|f| f.send_max_retries)),
+        simulate_loss_percent: args
+            .simulate_loss_percent
+            .or(file.and_then(|f| f.simulate_loss_percent)),
+        simulate_dup_percent: args
+            .simulate_dup_percent
+            .or(file.and_then(|f| f.simulate_dup_percent)),
+        simulate_delay_ms: args
+            .simulate_delay_ms
+            .or(file.and_then(|f| f.simulate_delay_ms)),
+        replay_file: args
+            .replay_file
+            .or_else(|| file.and_then(|f| f.replay_file.clone())),
+        replay_loop: args.replay_loop.or(file.and_then(|f| f.replay_loop)),
+        timestamp_data: args.timestamp_data.or(file.and_then(|f| f.timestamp_data)),
+        clock_source: args.clock_source.or(file.and_then(|f| f.clock_source)),
+        checksum: args.checksum.or(file.and_then(|f| f.checksum)),
+        emit_message_as_number: args
+            .emit_message_as_number
+            .or(file.and_then(|f| f.emit_message_as_number)),
+        // only in the config file, like `channels`
+        meta_extra: file.and_then(|f| f.meta_extra.clone()),
+        encoding: args.encoding.or(file.and_then(|f| f.encoding)),
+        compression: args.compression.or(file.and_then(|f| f.compression)),
+        transport: args.transport.or(file.and_then(|f| f.transport)),
+        http_url: args
+            .http_url
+            .or_else(|| file.and_then(|f| f.http_url.clone())),
+        // only in the config file, like `channels`
+        http_headers: file.and_then(|f| f.http_headers.clone()),
+        http_timeout_ms: args
+            .http_timeout_ms
+            .or(file.and_then(|f| f.http_timeout_ms)),
+        dtls_psk: args
+            .dtls_psk
+            .or_else(|| file.and_then(|f| f.dtls_psk.clone())),
+        dtls_psk_identity: args
+            .dtls_psk_identity
+            .or_else(|| file.and_then(|f| f.dtls_psk_identity.clone())),
+        dtls_ca_file: args
+            .dtls_ca_file
+            .or_else(|| file.and_then(|f| f.dtls_ca_file.clone())),
+        dtls_server_name: args
+            .dtls_server_name
+            .or_else(|| file.and_then(|f| f.dtls_server_name.clone())),
+        dtls_cert_file: args
+            .dtls_cert_file
+            .or_else(|| file.and_then(|f| f.dtls_cert_file.clone())),
+        dtls_key_file: args
+            .dtls_key_file
+            .or_else(|| file.and_then(|f| f.dtls_key_file.clone())),
+        mqtt_url: args
+            .mqtt_url
+            .or_else(|| file.and_then(|f| f.mqtt_url.clone())),
+        mqtt_topic: args
+            .mqtt_topic
+            .or_else(|| file.and_then(|f| f.mqtt_topic.clone())),
+        mqtt_qos: args.mqtt_qos.or(file.and_then(|f| f.mqtt_qos)),
+        mqtt_only: args.mqtt_only.or(file.and_then(|f| f.mqtt_only)),
+        batch_size: args.batch_size.or(file.and_then(|f| f.batch_size)),
+        batch_timeout_ms: args
+            .batch_timeout_ms
+            .or(file.and_then(|f| f.batch_timeout_ms)),
+        max_payload_bytes: args
+            .max_payload_bytes
+            .or(file.and_then(|f| f.max_payload_bytes)),
+        target_resolve_interval: args
+            .target_resolve_interval
+            .or(file.and_then(|f| f.target_resolve_interval)),
+        state_file: args
+            .state_file
+            .or_else(|| file.and_then(|f| f.state_file.clone())),
+        node_id: args
+            .node_id
+            .or_else(|| file.and_then(|f| f.node_id.clone())),
+        capture_file: args
+            .capture_file
+            .or_else(|| file.and_then(|f| f.capture_file.clone())),
+        capture_max_bytes: args
+            .capture_max_bytes
+            .or(file.and_then(|f| f.capture_max_bytes)),
+        ack_repeat: args.ack_repeat.or(file.and_then(|f| f.ack_repeat)),
+        ack_repeat_interval_ms: args
+            .ack_repeat_interval_ms
+            .or(file.and_then(|f| f.ack_repeat_interval_ms)),
+        ack_confirm_max_attempts: args
+            .ack_confirm_max_attempts
+            .or(file.and_then(|f| f.ack_confirm_max_attempts)),
+        quiet: args.quiet.or(file.and_then(|f| f.quiet)),
+        dry_run: args.dry_run.or(file.and_then(|f| f.dry_run)),
+        strict: args.strict.or(file.and_then(|f| f.strict)),
+        metrics_port: args.metrics_port.or(file.and_then(|f| f.metrics_port)),
+        health_port: args.health_port.or(file.and_then(|f| f.health_port)),
+        readiness_failure_ms: args
+            .readiness_failure_ms
+            .or(file.and_then(|f| f.readiness_failure_ms)),
+        task_max_restarts: args
+            .task_max_restarts
+            .or(file.and_then(|f| f.task_max_restarts)),
+        ping_interval_ms: args
+            .ping_interval_ms
+            .or(file.and_then(|f| f.ping_interval_ms)),
+        heartbeat_interval_ms: args
+            .heartbeat_interval_ms
+            .or(file.and_then(|f| f.heartbeat_interval_ms)),
+        ping_report_every: args
+            .ping_report_every
+            .or(file.and_then(|f| f.ping_report_every)),
+        controller_allowlist: args
+            .controller_allowlist
+            .or_else(|| file.and_then(|f| f.controller_allowlist.clone())),
+        inbound_rate_limit: args
+            .inbound_rate_limit
+            .or(file.and_then(|f| f.inbound_rate_limit)),
+        allowlist_pings: args
+            .allowlist_pings
+            .or(file.and_then(|f| f.allowlist_pings)),
+        ping_reply_format: args
+            .ping_reply_format
+            .or(file.and_then(|f| f.ping_reply_format)),
+        shared_secret: args
+            .shared_secret
+            .or_else(|| file.and_then(|f| f.shared_secret.clone())),
+        update_target_mode: args
+            .update_target_mode
+            .or(file.and_then(|f| f.update_target_mode)),
+        target_failover: args
+            .target_failover
+            .or_else(|| file.and_then(|f| f.target_failover.clone())),
+        failover_check_interval_ms: args
+            .failover_check_interval_ms
+            .or(file.and_then(|f| f.failover_check_interval_ms)),
+        failover_timeout_ms: args
+            .failover_timeout_ms
+            .or(file.and_then(|f| f.failover_timeout_ms)),
+        failover_max_rtt_ms: args
+            .failover_max_rtt_ms
+            .or(file.and_then(|f| f.failover_max_rtt_ms)),
+        failback_stable_ms: args
+            .failback_stable_ms
+            .or(file.and_then(|f| f.failback_stable_ms)),
+    };
+    Ok(config.with_defaults())
+}
+
+fn missing_value_error(name: &str, flag: &str, env: &str) -> String {
+    format!(
+        "`{}` is required: pass {}, set {} or add it to the config file",
+        name, flag, env
+    )
+}
 
-    future::join_all(tasks).await;
+/// Sets up log output, using `log_level` if given and otherwise `RUST_LOG` (falling back to `info`).
+/// Logs go to stderr if `to_stderr` is set and are flushed when the returned guard is dropped.
+fn init_logging(log_level: Option<&str>, to_stderr: bool) -> Result<LogGuard, Box<dyn Error>> {
+    let filter = match log_level {
+        Some(log_level) => EnvFilter::try_new(log_level)
+            .map_err(|e| format!("Invalid log level '{}': {}", log_level, e))?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    Ok(logging::init(filter, to_stderr))
 }
 
-fn generate_input_data() -> u16 {
-    // generate a random number
-    rand::random::<u16>()
+/// Resolves once the process receives Ctrl-C (SIGINT) or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Couldn't install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Couldn't install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
-fn load_config(path: &str) -> Result<Config, Box<dyn Error>> {
-    let config = std::fs::read_to_string(path)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Args are parsed from the process environment too, so tests touching it must not overlap
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    const FILE_CONFIG: &str = "
+area: room1
+flow_name: Flow 1
+target_ip: 127.0.0.1
+target_port: 33001
+outbound_port_data: 22001
+inbound_port: 21001
+interval: 500
+amplitude: 50
+";
+
+    fn file_config() -> Option<Config> {
+        Some(serde_yaml::from_str(FILE_CONFIG).unwrap())
+    }
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("vin").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn cli_overrides_file_and_file_overrides_default() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let config = resolve_config(parse(&["--interval", "200"]).unwrap(), file_config()).unwrap();
+        assert_eq!(config.interval, Some(200));
+
+        let config = resolve_config(parse(&[]).unwrap(), file_config()).unwrap();
+        assert_eq!(config.interval, Some(500));
+        assert_eq!(config.inbound_poll_interval, Some(10));
+    }
+
+    #[test]
+    fn env_overrides_file_but_not_cli() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        std::env::set_var("VIN_AMPLITUDE", "100");
+
+        let from_env = resolve_config(parse(&[]).unwrap(), file_config());
+        let from_cli = resolve_config(parse(&["--amplitude", "7"]).unwrap(), file_config());
+
+        std::env::remove_var("VIN_AMPLITUDE");
+        assert_eq!(from_env.unwrap().amplitude, Some(100.0));
+        assert_eq!(from_cli.unwrap().amplitude, Some(7.0));
+    }
+
+    #[test]
+    fn invalid_env_value_is_an_error() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        std::env::set_var("VIN_TARGET_PORT", "not-a-port");
+
+        let parsed = parse(&[]);
+
+        std::env::remove_var("VIN_TARGET_PORT");
+        assert!(parsed.is_err());
+    }
+
+    #[test]
+    fn required_values_must_be_given_without_file() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        assert!(resolve_config(parse(&["--area", "room1"]).unwrap(), None).is_err());
+    }
 
-    let config = serde_yaml::from_str::<Config>(&config);
+    #[test]
+    fn config_file_only() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
 
-    println!("config: {:?}", config);
+        let config = resolve_config(parse(&[]).unwrap(), file_config()).unwrap();
+        assert_eq!(config.area, "room1");
+        assert_eq!(config.flow_name, "Flow 1");
+        assert_eq!(config.target_port, 33001);
+        assert_eq!(config.outbound_port_data, 22001);
+        assert_eq!(config.inbound_port, 21001);
+        assert_eq!(config.outbound_port_acks, Some(0));
+        assert_eq!(config.amplitude, Some(50.0));
+    }
+
+    #[test]
+    fn args_only() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let args = parse(&[
+            "--area",
+            "room2",
+            "--flow",
+            "Flow 2",
+            "--target-ip",
+            "10.0.0.1",
+            "--target-port",
+            "33002",
+            "--outbound-port-data",
+            "22002",
+            "--inbound-port",
+            "21002",
+        ])
+        .unwrap();
+        let config = resolve_config(args, None).unwrap();
+        assert_eq!(config.area, "room2");
+        assert_eq!(config.flow_name, "Flow 2");
+        assert_eq!(config.target_ip, "10.0.0.1");
+        assert_eq!(config.target_port, 33002);
+        assert_eq!(config.outbound_port_data, 22002);
+        assert_eq!(config.inbound_port, 21002);
+        assert_eq!(config.outbound_port_acks, Some(0));
+        assert_eq!(config.interval, Some(1000));
+        assert_eq!(config.inbound_poll_interval, Some(10));
+    }
 
-    config.map_err(|err| err.into())
+    #[test]
+    fn args_partially_override_file() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let file = "
+area: room1
+flow_name: Flow 1
+target_ip: 127.0.0.1
+target_port: 33001
+outbound_port_data: 22001
+outbound_port_acks: 23001
+inbound_port: 21001
+interval: 500
+inbound_poll_interval: 20
+";
+        let file_config = || Some(serde_yaml::from_str::<Config>(file).unwrap());
+
+        let config = resolve_config(
+            parse(&["--outbound-port-acks", "23002"]).unwrap(),
+            file_config(),
+        )
+        .unwrap();
+        assert_eq!(config.outbound_port_acks, Some(23002));
+        assert_eq!(config.interval, Some(500));
+        assert_eq!(config.inbound_poll_interval, Some(20));
+
+        let config = resolve_config(parse(&["--interval", "250"]).unwrap(), file_config()).unwrap();
+        assert_eq!(config.outbound_port_acks, Some(23001));
+        assert_eq!(config.interval, Some(250));
+        assert_eq!(config.inbound_poll_interval, Some(20));
+
+        let config = resolve_config(
+            parse(&["--inbound-poll-interval", "5"]).unwrap(),
+            file_config(),
+        )
+        .unwrap();
+        assert_eq!(config.outbound_port_acks, Some(23001));
+        assert_eq!(config.interval, Some(500));
+        assert_eq!(config.inbound_poll_interval, Some(5));
+    }
 }
@@ -0,0 +1,404 @@
+use crate::http_server;
+use crate::logging;
+use crate::protocol::inbound_message_types;
+use crate::target::Targets;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write as _};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, watch};
+
+/// Counters and runtime state shared between the node's tasks, updated without locking
+pub struct Metrics {
+    pub data_packets_sent: AtomicU64,
+    pub data_packets_dropped: AtomicU64,
+    pub data_packets_failed: AtomicU64,
+    pub target_updates: AtomicU64,
+    pub pings: AtomicU64,
+    pub rejected_messages: AtomicU64,
+    /// inbound messages dropped unparsed because their source exceeded `inbound_rate_limit`
+    pub rate_limited_messages: AtomicU64,
+    /// inbound JSON messages without a `type`, or missing or mistyping fields of theirs
+    pub invalid_messages: AtomicU64,
+    /// errors receiving on the inbound socket
+    pub receive_errors: AtomicU64,
+    /// switches between entries of `target_failover`
+    pub failovers: AtomicU64,
+    /// whether sending data is paused via `pauseData`
+    pub paused: AtomicBool,
+    /// panics of the node's tasks, each restarted unless it panicked too often
+    pub panicked_tasks: AtomicU64,
+    /// tasks that panicked and wait for their restart, the node isn't live while there is one
+    pub tasks_down: AtomicU64,
+    /// replies to the node's own pings
+    pub ping_replies: AtomicU64,
+    /// counted individually for every message type of the protocol, everything else is `unknown`
+    inbound_messages: HashMap<&'static str, AtomicU64>,
+    inbound_messages_unknown: AtomicU64,
+    /// data packets that couldn't be sent, by target
+    send_errors: Mutex<BTreeMap<SocketAddr, u64>>,
+    /// when the first data packet since the last successful send couldn't be sent, in µs since
+    /// `created`, 0 if the last packet was sent
+    send_failing_since: AtomicU64,
+    created: Instant,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            data_packets_sent: AtomicU64::new(0),
+            data_packets_dropped: AtomicU64::new(0),
+            data_packets_failed: AtomicU64::new(0),
+            target_updates: AtomicU64::new(0),
+            pings: AtomicU64::new(0),
+            rejected_messages: AtomicU64::new(0),
+            rate_limited_messages: AtomicU64::new(0),
+            invalid_messages: AtomicU64::new(0),
+            receive_errors: AtomicU64::new(0),
+            failovers: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            panicked_tasks: AtomicU64::new(0),
+            tasks_down: AtomicU64::new(0),
+            ping_replies: AtomicU64::new(0),
+            inbound_messages: inbound_message_types()
+                .map(|message_type| (message_type, AtomicU64::new(0)))
+                .collect(),
+            inbound_messages_unknown: AtomicU64::new(0),
+            send_errors: Mutex::new(BTreeMap::new()),
+            send_failing_since: AtomicU64::new(0),
+            created: Instant::now(),
+        }
+    }
+
+    /// Counts a data packet that was sent
+    pub fn count_sent(&self) {
+        self.data_packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.send_failing_since.store(0, Ordering::Relaxed);
+    }
+
+    /// Counts a data packet that couldn't be sent to `target`
+    pub fn count_send_error(&self, target: SocketAddr) {
+        self.data_packets_failed.fetch_add(1, Ordering::Relaxed);
+        *self.send_errors.lock().unwrap().entry(target).or_default() += 1;
+        let now = (self.created.elapsed().as_micros() as u64).max(1);
+        let _ =
+            self.send_failing_since
+                .compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// How long sending data has been failing at `now`, `None` if the last packet was sent
+    pub fn send_failing_for(&self, now: Instant) -> Option<Duration> {
+        match self.send_failing_since.load(Ordering::Relaxed) {
+            0 => None,
+            since => {
+                Some(now.saturating_duration_since(self.created + Duration::from_micros(since)))
+            }
+        }
+    }
+
+    /// Counts an inbound message, using the `unknown` bucket for missing or unrecognized types
+    pub fn count_inbound(&self, message_type: Option<&str>) {
+        message_type
+            .and_then(|message_type| self.inbound_messages.get(message_type))
+            .unwrap_or(&self.inbound_messages_unknown)
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters at this moment, for `queryStatus` and the summary on shutdown
+    pub fn stats(&self) -> Stats {
+        let mut inbound_messages: BTreeMap<&'static str, u64> = self
+            .inbound_messages
+            .iter()
+            .map(|(message_type, count)| (*message_type, count.load(Ordering::Relaxed)))
+            .collect();
+        inbound_messages.insert(
+            "unknown",
+            self.inbound_messages_unknown.load(Ordering::Relaxed),
+        );
+        Stats {
+            packets_sent: self.data_packets_sent.load(Ordering::Relaxed),
+            packets_dropped: self.data_packets_dropped.load(Ordering::Relaxed),
+            packets_failed: self.data_packets_failed.load(Ordering::Relaxed),
+            target_updates: self.target_updates.load(Ordering::Relaxed),
+            rejected_messages: self.rejected_messages.load(Ordering::Relaxed),
+            rate_limited_messages: self.rate_limited_messages.load(Ordering::Relaxed),
+            invalid_messages: self.invalid_messages.load(Ordering::Relaxed),
+            inbound_messages,
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self, targets: &Targets) -> String {
+        let mut output = String::new();
+
+        write_header(
+            &mut output,
+            "vin_data_packets_sent_total",
+            "counter",
+            "Data packets sent, counting every target separately",
+        );
+        let _ = writeln!(
+            output,
+            "vin_data_packets_sent_total {}",
+            self.data_packets_sent.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_data_packets_dropped_total",
+            "counter",
+            "Data packets dropped because the send queue was full",
+        );
+        let _ = writeln!(
+            output,
+            "vin_data_packets_dropped_total {}",
+            self.data_packets_dropped.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_data_packets_failed_total",
+            "counter",
+            "Data packets dropped because sending failed even after retrying",
+        );
+        let _ = writeln!(
+            output,
+            "vin_data_packets_failed_total {}",
+            self.data_packets_failed.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_inbound_messages_total",
+            "counter",
+            "Inbound messages by type",
+        );
+        for message_type in inbound_message_types() {
+            let _ = writeln!(
+                output,
+                "vin_inbound_messages_total{{type=\"{}\"}} {}",
+                message_type,
+                self.inbound_messages[message_type].load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(
+            output,
+            "vin_inbound_messages_total{{type=\"unknown\"}} {}",
+            self.inbound_messages_unknown.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_target_updates_total",
+            "counter",
+            "Applied target updates",
+        );
+        let _ = writeln!(
+            output,
+            "vin_target_updates_total {}",
+            self.target_updates.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_pings_total",
+            "counter",
+            "Answered UDP pings",
+        );
+        let _ = writeln!(
+            output,
+            "vin_pings_total {}",
+            self.pings.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_rejected_messages_total",
+            "counter",
+            "Inbound messages rejected by `controller_allowlist` or HMAC verification",
+        );
+        let _ = writeln!(
+            output,
+            "vin_rejected_messages_total {}",
+            self.rejected_messages.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_inbound_rate_limited_total",
+            "counter",
+            "Inbound messages dropped without parsing because their source exceeded `inbound_rate_limit`",
+        );
+        let _ = writeln!(
+            output,
+            "vin_inbound_rate_limited_total {}",
+            self.rate_limited_messages.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_invalid_messages_total",
+            "counter",
+            "Inbound messages rejected because their `type` or fields of it were missing or invalid",
+        );
+        let _ = writeln!(
+            output,
+            "vin_invalid_messages_total {}",
+            self.invalid_messages.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_inbound_receive_errors_total",
+            "counter",
+            "Errors receiving on the inbound socket",
+        );
+        let _ = writeln!(
+            output,
+            "vin_inbound_receive_errors_total {}",
+            self.receive_errors.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_failovers_total",
+            "counter",
+            "Switches between entries of the failover list, including failbacks",
+        );
+        let _ = writeln!(
+            output,
+            "vin_failovers_total {}",
+            self.failovers.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_task_panics_total",
+            "counter",
+            "Panics of the node's tasks, each restarted unless it panicked too often",
+        );
+        let _ = writeln!(
+            output,
+            "vin_task_panics_total {}",
+            self.panicked_tasks.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut output,
+            "vin_log_lines_dropped_total",
+            "counter",
+            "Log lines dropped because stdout or stderr couldn't keep up",
+        );
+        let _ = writeln!(
+            output,
+            "vin_log_lines_dropped_total {}",
+            logging::dropped_lines()
+        );
+
+        write_header(
+            &mut output,
+            "vin_data_paused",
+            "gauge",
+            "Whether sending data is paused (1) or not (0)",
+        );
+        let _ = writeln!(
+            output,
+            "vin_data_paused {}",
+            self.paused.load(Ordering::Relaxed) as u8
+        );
+
+        write_header(
+            &mut output,
+            "vin_target_info",
+            "gauge",
+            "The current targets of the data stream",
+        );
+        for target in targets.iter() {
+            let _ = writeln!(output, "vin_target_info{{target=\"{}\"}} 1", target);
+        }
+
+        write_header(
+            &mut output,
+            "vin_target_send_errors_total",
+            "counter",
+            "Data packets that couldn't be sent, by target",
+        );
+        for (target, errors) in self.send_errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                output,
+                "vin_target_send_errors_total{{target=\"{}\"}} {}",
+                target, errors
+            );
+        }
+
+        output
+    }
+}
+
+/// A snapshot of the counters of [`Metrics`]
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub packets_sent: u64,
+    pub packets_dropped: u64,
+    pub packets_failed: u64,
+    pub target_updates: u64,
+    pub rejected_messages: u64,
+    pub rate_limited_messages: u64,
+    pub invalid_messages: u64,
+    /// by type, including `unknown`
+    pub inbound_messages: BTreeMap<&'static str, u64>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} packets, dropped {}, failed to send {}, received {} updates, rejected {} messages, rate-limited {}, invalid {}",
+            self.packets_sent,
+            self.packets_dropped,
+            self.packets_failed,
+            self.target_updates,
+            self.rejected_messages,
+            self.rate_limited_messages,
+            self.invalid_messages
+        )
+    }
+}
+
+fn write_header(output: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serves `GET /metrics` until a shutdown signal arrives
+pub async fn serve(
+    listener: &TcpListener,
+    metrics: Arc<Metrics>,
+    targets: watch::Receiver<Targets>,
+    shutdown: broadcast::Receiver<()>,
+) {
+    let respond = move |path: &str| {
+        (path == "/metrics").then(|| {
+            // cloned so the lock isn't held while rendering
+            let targets = targets.borrow().clone();
+            ("200 OK", metrics.render(&targets))
+        })
+    };
+    http_server::serve(
+        listener,
+        "metrics",
+        "text/plain; version=0.0.4",
+        respond,
+        shutdown,
+    )
+    .await
+}
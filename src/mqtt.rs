@@ -0,0 +1,183 @@
+use crate::sink::OutputSink;
+use crate::target::resolve_address;
+use crate::url::{parse_url, Url};
+use futures::future::{self, BoxFuture};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Outgoing, Packet, QoS};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time;
+use tracing::{info, warn};
+
+/// Delay before reconnecting to the broker after the connection failed, doubled after every
+/// attempt
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Upper bound for the delay between reconnection attempts
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Publishes waiting for the connection before new ones are dropped
+const PUBLISH_QUEUE_SIZE: usize = 64;
+/// How long [`flush`] may take to connect and send the queued publishes
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Publishes data packets to an MQTT broker
+pub struct MqttSink {
+    client: AsyncClient,
+    /// the broker, as the destination of the data packets
+    address: SocketAddr,
+    topic: String,
+    qos: QoS,
+}
+
+impl MqttSink {
+    /// Prepares a client for the broker at `url` (`mqtt://<host>[:<port>]`). `topic` may contain
+    /// `{area}` and `{flow}`. The connection is only established once the returned event loop
+    /// is [run](run_event_loop).
+    pub async fn new(
+        url: &str,
+        topic: &str,
+        qos: u8,
+        flow_name: &str,
+        area: &str,
+    ) -> Result<(MqttSink, EventLoop), String> {
+        let Url {
+            host, port, path, ..
+        } = parse_url(url, "mqtt", 1883)?;
+        if path != "/" {
+            return Err(format!("`{}`: MQTT URLs have no path", url));
+        }
+        let address = resolve_address(&host, port)
+            .await
+            .map_err(|e| e.to_string())?;
+        let client_id = format!("vin-{}-{:08x}", area, rand::random::<u32>());
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        let (client, event_loop) = AsyncClient::new(options, PUBLISH_QUEUE_SIZE);
+        Ok((
+            MqttSink {
+                client,
+                address,
+                topic: topic.replace("{area}", area).replace("{flow}", flow_name),
+                qos: rumqttc::qos(qos).map_err(|e| e.to_string())?,
+            },
+            event_loop,
+        ))
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// A handle for [`run_event_loop`] to disconnect on shutdown
+    pub fn client(&self) -> AsyncClient {
+        self.client.clone()
+    }
+}
+
+impl OutputSink for MqttSink {
+    fn destinations(&self, _targets: &[SocketAddr]) -> Vec<SocketAddr> {
+        vec![self.address]
+    }
+
+    /// Queues a packet for publishing without waiting, failing if the queue is full (e.g. while
+    /// the broker is unreachable)
+    fn send<'a>(&'a self, payload: &'a [u8], _broker: SocketAddr) -> BoxFuture<'a, io::Result<()>> {
+        let result = self
+            .client
+            .try_publish(&self.topic, self.qos, false, payload)
+            .map_err(io::Error::other);
+        Box::pin(future::ready(result))
+    }
+}
+
+/// Drives the connection to the broker until `shutdown` fires, reconnecting with backoff when it
+/// is lost
+pub async fn run_event_loop(
+    event_loop: &mut EventLoop,
+    client: AsyncClient,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let mut backoff = RECONNECT_INITIAL_BACKOFF;
+    loop {
+        tokio::select! {
+            event = event_loop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker");
+                    backoff = RECONNECT_INITIAL_BACKOFF;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(error = %e, retry_in = ?backoff, "MQTT connection failed");
+                    tokio::select! {
+                        _ = time::sleep(backoff) => {}
+                        _ = shutdown.recv() => break,
+                    }
+                    backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+                }
+            },
+            _ = shutdown.recv() => {
+                let _ = client.try_disconnect();
+                break;
+            }
+        }
+    }
+}
+
+/// Connects, sends the publishes queued so far and disconnects, for one-shot sends that don't
+/// [run](run_event_loop) the event loop. Fails if the broker can't be reached.
+pub async fn flush(event_loop: &mut EventLoop, client: &AsyncClient) -> io::Result<()> {
+    // requests are handled in order, so the disconnect goes out after the publishes
+    client.try_disconnect().map_err(io::Error::other)?;
+    let flushed = time::timeout(FLUSH_TIMEOUT, async {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => return Ok(()),
+                Ok(_) => {}
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+    });
+    flushed
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "MQTT broker didn't respond"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fills_in_the_topic() {
+        let (sink, _) = MqttSink::new(
+            "mqtt://127.0.0.1",
+            "vin/{area}/{flow}",
+            1,
+            "Flow 1",
+            "room1",
+        )
+        .await
+        .unwrap();
+        assert_eq!(sink.topic(), "vin/room1/Flow 1");
+        assert_eq!(sink.qos, QoS::AtLeastOnce);
+    }
+
+    #[tokio::test]
+    async fn fails_to_flush_without_a_broker() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (sink, mut event_loop) = MqttSink::new(
+            &format!("mqtt://127.0.0.1:{}", port),
+            "vin/{area}/{flow}",
+            0,
+            "Flow 1",
+            "room1",
+        )
+        .await
+        .unwrap();
+        sink.send(b"{}", sink.address).await.unwrap();
+        assert!(flush(&mut event_loop, &sink.client).await.is_err());
+    }
+}
@@ -0,0 +1,1617 @@
+use crate::ack::AckSender;
+use crate::capture::{Capture, CaptureWriter};
+use crate::clock::{self, ClockSource};
+use crate::compression::{self, Compression};
+use crate::config::Config;
+#[cfg(feature = "dtls")]
+use crate::dtls::DtlsSink;
+use crate::encoding::{encode_binary, Encoding};
+use crate::failover::{self, HealthPolicy};
+use crate::faults::FaultInjection;
+use crate::generator::{Calibration, DataSample, DataSource, Generator, OverflowPolicy, Replay};
+use crate::handlers::{reject_invalid, Dispatcher, Inbound, Request};
+use crate::health;
+use crate::logging;
+use crate::metrics::{self, Metrics};
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{self, MqttSink};
+use crate::ping;
+use crate::protocol::{heartbeat, DataPacket, Direction};
+use crate::ratelimit::RateLimiter;
+use crate::receive_errors::ReceiveErrors;
+use crate::reload::{self, Reloadable, Reloader};
+use crate::sink::{MultiSink, NullSink, OutputSink};
+use crate::state::PersistedState;
+use crate::supervisor::{shared, Supervisor, TaskFailed};
+use crate::target::{resolve_address_from, Target, Targets};
+use crate::ticker::Ticker;
+use crate::transport::{self, HttpSink, Transport};
+use futures::future;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::{
+    error::Error,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::{broadcast, mpsc, watch, Mutex},
+    time,
+};
+use tracing::{debug, error, info, warn};
+
+/// The local ports a [`Node`] is bound to
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoundPorts {
+    pub data: u16,
+    pub acks: u16,
+    pub inbound: u16,
+}
+
+/// An input node with its targets resolved and all sockets bound, ready to [`run`](Node::run)
+pub struct Node {
+    config: Config,
+    initial_targets: Targets,
+    /// from the state file, 0 without one
+    restart_count: u64,
+    channels: Vec<Channel>,
+    outbound_socket_data: UdpSocket,
+    outbound_socket_acks: Arc<UdpSocket>,
+    inbound_socket: UdpSocket,
+    ping_socket: Option<UdpSocket>,
+    /// the failover list, starting with the configured target, and the socket to ping it with
+    failover: Option<(UdpSocket, Vec<Target>)>,
+    metrics_listener: Option<TcpListener>,
+    health_listener: Option<TcpListener>,
+    capture: Capture,
+    /// only set if `capture_file` is
+    capture_writer: Option<CaptureWriter>,
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<(MqttSink, rumqttc::EventLoop)>,
+    /// only set with `transport: http` or `transport: dtls`
+    prepared: Option<Box<dyn OutputSink>>,
+    ports: BoundPorts,
+    /// configs handed in by [`Reloader`]s, applied once running
+    reloads: (
+        mpsc::UnboundedSender<Config>,
+        mpsc::UnboundedReceiver<Config>,
+    ),
+}
+
+impl Node {
+    /// Prepares a node from `config`, filling in defaults for unset options. Fails if a
+    /// target can't be resolved, the data source can't be loaded or a socket can't be bound.
+    ///
+    /// `config` is expected to be [validated](Config::validate) already.
+    pub async fn bind(config: Config) -> Result<Self, Box<dyn Error>> {
+        let mut config = config.with_defaults();
+        debug!(flow = %config.flow_name, area = %config.area, "Starting input node");
+        if config.clock_source == Some(ClockSource::MonotonicAnchored) {
+            clock::anchor();
+        }
+
+        let dry_run = config.dry_run.unwrap();
+        let mut initial_targets =
+            match resolve_targets(&configured_targets(&config), config.bind_address.unwrap()).await
+            {
+                Ok(targets) => targets,
+                // nothing is sent in a dry run, so the targets don't have to exist
+                Err(e) if dry_run => {
+                    warn!(error = %e, "Couldn't resolve targets, ignoring it in a dry run");
+                    Targets::new(Target {
+                        host: config.target_ip.clone(),
+                        port: config.target_port,
+                        address: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), config.target_port),
+                    })
+                }
+                Err(e) => return Err(format!("No valid target address given: {}", e).into()),
+            };
+
+        let persisted = config.state_file.as_deref().and_then(PersistedState::load);
+        // the first start with a state file isn't a restart
+        let restart_count = persisted
+            .as_ref()
+            .map_or(0, |state| state.restart_count + 1);
+        let persisted = persisted.unwrap_or_default();
+        // a configured id wins, a generated one is kept in the state file
+        let node_id = config
+            .node_id
+            .clone()
+            .or_else(|| persisted.node_id.clone())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        config.node_id = Some(node_id.clone());
+        if let Some(state_file) = config.state_file.as_deref().filter(|_| !dry_run) {
+            let state = PersistedState {
+                restart_count,
+                node_id: Some(node_id),
+                ..persisted.clone()
+            };
+            if let Err(e) = state.save(state_file).await {
+                warn!(state_file, error = %e, "Couldn't write state file");
+            }
+        }
+        // continue with the last known targets from a previous run, if there are any
+        let persisted_targets: Vec<(String, u16)> = persisted
+            .targets()
+            .into_iter()
+            .map(|target| (target.host, target.port))
+            .collect();
+        if !persisted_targets.is_empty() {
+            match resolve_targets(&persisted_targets, config.bind_address.unwrap()).await {
+                Ok(targets) => {
+                    info!(%targets, "Restored targets from state file");
+                    initial_targets = targets;
+                }
+                Err(e) => warn!(
+                    error = %e,
+                    "Ignoring targets from state file, falling back to config"
+                ),
+            }
+        }
+
+        let channels = match &config.channels {
+            Some(channels) => channels
+                .iter()
+                .enumerate()
+                .map(|(i, channel)| Channel {
+                    name: Some(channel.name.as_str().into()),
+                    source: Generator {
+                        waveform: channel.waveform.unwrap_or(config.waveform.unwrap()),
+                        amplitude: channel.amplitude.unwrap_or(config.amplitude.unwrap()),
+                        offset: channel.offset.unwrap_or(config.offset.unwrap()),
+                        period: Duration::from_millis(
+                            channel.period_ms.unwrap_or(config.period_ms.unwrap()),
+                        ),
+                        noise_stddev: channel.noise_stddev.unwrap_or(config.noise_stddev.unwrap()),
+                        value_type: channel.value_type.unwrap_or(config.value_type.unwrap()),
+                        range: channel
+                            .value_min
+                            .or(config.value_min)
+                            .zip(channel.value_max.or(config.value_max)),
+                        // different values for every channel, but still reproducible
+                        rng: Generator::rng(config.seed.map(|seed| seed.wrapping_add(i as u64))),
+                    }
+                    .into_source(),
+                    interval: channel.interval.map(Duration::from_millis),
+                })
+                .collect(),
+            None => vec![Channel {
+                name: None,
+                source: match &config.replay_file {
+                    Some(path) => Box::new(Replay::from_file(
+                        path,
+                        config.replay_loop.unwrap(),
+                        config.value_type.unwrap(),
+                    )?),
+                    None => Generator {
+                        waveform: config.waveform.unwrap(),
+                        amplitude: config.amplitude.unwrap(),
+                        offset: config.offset.unwrap(),
+                        period: Duration::from_millis(config.period_ms.unwrap()),
+                        noise_stddev: config.noise_stddev.unwrap(),
+                        value_type: config.value_type.unwrap(),
+                        range: config.value_min.zip(config.value_max),
+                        rng: Generator::rng(config.seed),
+                    }
+                    .into_source(),
+                },
+                interval: None,
+            }],
+        };
+
+        let bind_address = config.bind_address.unwrap();
+        let outbound_socket_data = bind_socket(
+            "outbound data",
+            Some("outbound_port_data"),
+            &config,
+            config.outbound_port_data,
+        )
+        .await?;
+        let outbound_socket_acks = Arc::new(
+            bind_socket(
+                "outbound ACK",
+                Some("outbound_port_acks"),
+                &config,
+                config.outbound_port_acks.unwrap(),
+            )
+            .await?,
+        );
+        let inbound_socket = bind_socket(
+            "inbound",
+            Some("inbound_port"),
+            &config,
+            config.inbound_port,
+        )
+        .await?;
+        // with port 0 the OS picks the port, so report the ones actually in use
+        let ports = BoundPorts {
+            data: outbound_socket_data.local_addr()?.port(),
+            acks: outbound_socket_acks.local_addr()?.port(),
+            inbound: inbound_socket.local_addr()?.port(),
+        };
+
+        // a dry run only generates data, it doesn't talk to anyone
+        let ping_socket = match config.ping_interval_ms.filter(|_| !dry_run) {
+            Some(_) => Some(bind_socket("ping", None, &config, 0).await?),
+            None => None,
+        };
+        let failover = match config.target_failover.as_ref().filter(|_| !dry_run) {
+            Some(backups) => {
+                let mut candidates = vec![];
+                for (host, port) in std::iter::once((&config.target_ip, config.target_port))
+                    .chain(backups.iter().map(|backup| (&backup.host, backup.port)))
+                {
+                    candidates.push(Target {
+                        host: host.clone(),
+                        port,
+                        address: resolve_address_from(host, port, bind_address)
+                            .await
+                            .map_err(|e| format!("Invalid failover target: {}", e))?,
+                    });
+                }
+                Some((bind_socket("failover", None, &config, 0).await?, candidates))
+            }
+            None => None,
+        };
+        let metrics_listener = match config.metrics_port {
+            Some(metrics_port) => {
+                let metrics_address = SocketAddr::new(bind_address, metrics_port);
+                let listener = TcpListener::bind(metrics_address).await.map_err(|e| {
+                    format!(
+                        "Failed to bind metrics server on {}: {} (change `metrics_port`)",
+                        metrics_address, e
+                    )
+                })?;
+                info!(address = %listener.local_addr()?, "Serving metrics at /metrics");
+                Some(listener)
+            }
+            None => None,
+        };
+        let health_listener = match config.health_port {
+            Some(health_port) => {
+                let health_address = SocketAddr::new(bind_address, health_port);
+                let listener = TcpListener::bind(health_address).await.map_err(|e| {
+                    format!(
+                        "Failed to bind health check server on {}: {} (change `health_port`)",
+                        health_address, e
+                    )
+                })?;
+                info!(address = %listener.local_addr()?, "Serving health checks at /healthz and /readyz");
+                Some(listener)
+            }
+            None => None,
+        };
+        #[cfg(feature = "mqtt")]
+        let mqtt = match config.mqtt_url.as_deref().filter(|_| !dry_run) {
+            Some(url) => Some(
+                MqttSink::new(
+                    url,
+                    config.mqtt_topic.as_deref().unwrap(),
+                    config.mqtt_qos.unwrap(),
+                    &config.flow_name,
+                    &config.area,
+                )
+                .await
+                .map_err(|e| format!("Invalid `mqtt_url`: {}", e))?,
+            ),
+            None => None,
+        };
+        let prepared: Option<Box<dyn OutputSink>> = match config.transport.unwrap() {
+            _ if dry_run => None,
+            Transport::Http => Some(Box::new(
+                HttpSink::resolve(
+                    config
+                        .http_url
+                        .as_deref()
+                        .expect("checked by Config::validate"),
+                    config.http_headers.clone().unwrap_or_default(),
+                    Duration::from_millis(config.http_timeout_ms.unwrap()),
+                    config.encoding.unwrap(),
+                    config.bind_address.unwrap(),
+                )
+                .await
+                .map_err(|e| format!("Invalid `http_url`: {}", e))?,
+            )),
+            #[cfg(feature = "dtls")]
+            Transport::Dtls => Some(Box::new(
+                DtlsSink::new(&config).map_err(|e| format!("Invalid DTLS config: {}", e))?,
+            )),
+            _ => None,
+        };
+        let (capture, capture_writer) = match &config.capture_file {
+            Some(path) => {
+                let (capture, writer) = Capture::open(
+                    path,
+                    config.capture_max_bytes.unwrap(),
+                    config.clock_source.unwrap(),
+                )
+                .await
+                .map_err(|e| format!("Failed to open capture file {}: {}", path, e))?;
+                (capture, Some(writer))
+            }
+            None => (Capture::default(), None),
+        };
+
+        // a single line with everything needed to tell nodes apart in aggregated logs
+        info!(
+            version = env!("CARGO_PKG_VERSION"),
+            node_id = config.node_id.as_deref().unwrap(),
+            flow = %config.flow_name,
+            area = %config.area,
+            data = %outbound_socket_data.local_addr()?,
+            acks = %outbound_socket_acks.local_addr()?,
+            inbound = %inbound_socket.local_addr()?,
+            targets = %initial_targets,
+            restart_count,
+            interval_ms = config.interval.unwrap(),
+            encoding = ?config.encoding.unwrap(),
+            transport = ?config.transport.unwrap(),
+            "Input node ready"
+        );
+
+        Ok(Node {
+            config,
+            initial_targets,
+            restart_count,
+            channels,
+            outbound_socket_data,
+            outbound_socket_acks,
+            inbound_socket,
+            ping_socket,
+            failover,
+            metrics_listener,
+            health_listener,
+            capture,
+            capture_writer,
+            #[cfg(feature = "mqtt")]
+            mqtt,
+            prepared,
+            ports,
+            reloads: mpsc::unbounded_channel(),
+        })
+    }
+
+    pub fn ports(&self) -> BoundPorts {
+        self.ports
+    }
+
+    /// Applies new configs to the node while it runs
+    pub fn reloader(&self) -> Reloader {
+        Reloader(self.reloads.0.clone())
+    }
+
+    /// Runs the node until `shutdown` completes, then stops all of its tasks. Tasks that panic are
+    /// restarted, and fail the node once one panicked more than `task_max_restarts` times.
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> Result<(), TaskFailed> {
+        let Node {
+            config,
+            initial_targets,
+            restart_count,
+            channels,
+            outbound_socket_data,
+            outbound_socket_acks,
+            inbound_socket,
+            ping_socket,
+            failover,
+            metrics_listener,
+            health_listener,
+            capture,
+            capture_writer,
+            #[cfg(feature = "mqtt")]
+            mqtt,
+            prepared,
+            ports,
+            reloads: (_, reloads),
+        } = self;
+        // reported as uptime by `queryStatus`
+        let start_instant = std::time::Instant::now();
+
+        // the inbound and resolve tasks publish target changes, the data loop reads the latest ones
+        let (target_tx, target_rx) = watch::channel(initial_targets);
+        let target_tx = Arc::new(target_tx);
+
+        let mut tasks: Vec<tokio::task::JoinHandle<()>> = vec![];
+
+        // can be changed by reloading the config, so every task reads it once per cycle
+        let quiet = Arc::new(AtomicBool::new(config.quiet.unwrap()));
+        let dry_run = config.dry_run.unwrap();
+        if dry_run {
+            info!(
+                "Dry run: data packets are printed instead of sent, control messages are ignored"
+            );
+        }
+
+        // used to tell all tasks to stop once a shutdown signal arrives
+        let (shutdown_tx, _) = broadcast::channel::<()>(1);
+
+        let metrics = Arc::new(Metrics::new());
+        // restarts tasks that panic, and reports those that keep panicking
+        let (supervisor, mut failed_tasks) = Supervisor::new(
+            config.task_max_restarts.unwrap(),
+            metrics.clone(),
+            shutdown_tx.clone(),
+        );
+
+        // written in its own task, so disk I/O never holds up the sockets
+        if let Some(capture_writer) = capture_writer {
+            let capture_writer = shared(capture_writer);
+            tasks.push(supervisor.spawn("capture", move |shutdown| {
+                let capture_writer = capture_writer.clone();
+                async move { capture_writer.lock().await.run(shutdown).await }
+            }));
+        }
+
+        // the data interval can be changed at runtime with `setInterval`
+        let (interval_tx, interval_rx) =
+            watch::channel(Duration::from_millis(config.interval.unwrap()));
+        let interval_tx = Arc::new(interval_tx);
+
+        // the inbound task reports these in `queryStatus` as well
+        let flow_name = config.flow_name.clone();
+        let area = config.area.clone();
+
+        // shared by the data and heartbeat tasks
+        let outbound_socket_data = Arc::new(outbound_socket_data);
+
+        // tell the targets the node is alive, independent of the data rate
+        if let Some(heartbeat_interval) = config.heartbeat_interval_ms.filter(|_| !dry_run) {
+            // from the data port as well
+            let socket = outbound_socket_data.clone();
+            let targets = target_rx.clone();
+            let metrics_heartbeat = metrics.clone();
+            let flow_name = flow_name.clone();
+            let area = area.clone();
+            let node_id = config.node_id.clone().unwrap();
+            let capture = capture.clone();
+            let quiet_heartbeat = quiet.clone();
+
+            tasks.push(supervisor.spawn("heartbeat", move |mut shutdown_heartbeat| {
+                let socket = socket.clone();
+                let targets = targets.clone();
+                let metrics_heartbeat = metrics_heartbeat.clone();
+                let flow_name = flow_name.clone();
+                let area = area.clone();
+                let node_id = node_id.clone();
+                let capture = capture.clone();
+                let quiet_heartbeat = quiet_heartbeat.clone();
+                async move {
+                    let mut interval = time::interval(Duration::from_millis(heartbeat_interval));
+
+                    loop {
+                        tokio::select! {
+                            _ = interval.tick() => {}
+                            _ = shutdown_heartbeat.recv() => break,
+                        }
+                        let quiet = quiet_heartbeat.load(Ordering::Relaxed);
+
+                        let heartbeat = heartbeat(
+                            &flow_name,
+                            &area,
+                            start_instant.elapsed(),
+                            restart_count,
+                            metrics_heartbeat.paused.load(Ordering::Relaxed),
+                            &node_id,
+                        )
+                        .to_string();
+                        let addresses: Vec<SocketAddr> = targets
+                            .borrow()
+                            .iter()
+                            .map(|target| target.address)
+                            .collect();
+                        for address in addresses {
+                            match socket.send_to(heartbeat.as_bytes(), address).await {
+                                Ok(_) => {
+                                    capture.record(Direction::Outbound, address, heartbeat.as_bytes());
+                                    if !quiet {
+                                        debug!(target = %address, "Sent heartbeat");
+                                    }
+                                }
+                                Err(e) => {
+                                    warn!(target = %address, error = %e, "Couldn't send heartbeat")
+                                }
+                            }
+                        }
+                    }
+                }
+            }));
+        }
+
+        // samples are generated and sent in separate tasks, so a slow socket doesn't disturb the
+        // sampling cadence. The generators need access to the receiver to drop the oldest samples.
+        // Samples that belong into the same packet (aggregated channels) are queued together.
+        let (data_tx, data_rx) = mpsc::channel::<Vec<DataSample>>(config.data_queue_size.unwrap());
+        let data_rx = Arc::new(Mutex::new(data_rx));
+        let queue = DataQueue {
+            tx: data_tx,
+            rx: data_rx.clone(),
+            overflow_policy: config.overflow_policy.unwrap(),
+            metrics: metrics.clone(),
+        };
+
+        let jitter = Duration::from_millis(config.jitter_ms.unwrap());
+        let startup_delay = Duration::from_millis(config.startup_delay_ms.unwrap());
+        let missed_tick_behavior = config.missed_tick_behavior.unwrap();
+        let calibration = calibration(&config);
+        let include_raw = config.include_raw.unwrap();
+        let clock = config.clock_source.unwrap();
+        // identifies each sample of the data stream, independent of the current target and channel
+        let seq = Arc::new(AtomicU64::new(0));
+        // with aggregation, the channels only update their latest sample and a separate task
+        // sends all of them together
+        let latest = config
+            .aggregate
+            .unwrap()
+            .then(|| Arc::new(std::sync::Mutex::new(vec![None; channels.len()])));
+
+        // generate input data, one task per channel
+        let start = time::Instant::now();
+        for (index, channel) in channels.into_iter().enumerate() {
+            let Channel {
+                name,
+                source,
+                interval,
+            } = channel;
+            let source = shared(source);
+            // channels with their own interval ignore `setInterval`
+            let interval_rx = match interval {
+                Some(interval) => watch::channel(interval).1,
+                None => interval_rx.clone(),
+            };
+            let queue = queue.clone();
+            let seq = seq.clone();
+            let latest = latest.clone();
+            let metrics_generator = metrics.clone();
+
+            tasks.push(
+                supervisor.spawn("generator", move |mut shutdown_generator| {
+                    let name = name.clone();
+                    let source = source.clone();
+                    let mut interval_rx = interval_rx.clone();
+                    let queue = queue.clone();
+                    let seq = seq.clone();
+                    let latest = latest.clone();
+                    let metrics_generator = metrics_generator.clone();
+                    async move {
+                        let mut data_source = source.lock().await;
+                        if !wait_for_startup(startup_delay, &mut shutdown_generator).await {
+                            return;
+                        }
+                        let mut ticker = Ticker::new(
+                            *interval_rx.borrow_and_update(),
+                            jitter,
+                            missed_tick_behavior,
+                        );
+
+                        loop {
+                            tokio::select! {
+                                _ = ticker.tick() => {}
+                                Ok(()) = interval_rx.changed() => {
+                                    // start counting the new period now instead of sending right away
+                                    ticker.set_period(*interval_rx.borrow_and_update());
+                                    continue;
+                                }
+                                _ = shutdown_generator.recv() => break,
+                            }
+
+                            // keep ticking while paused so resuming picks up the regular cadence
+                            if metrics_generator.paused.load(Ordering::Relaxed) {
+                                continue;
+                            }
+
+                            let Some(raw) = data_source.next(start.elapsed()) else {
+                                // keep the node running so it stays controllable, just stop sending data
+                                info!("Replay file exhausted, no more data will be sent");
+                                break;
+                            };
+                            let mut sample = DataSample {
+                                seq: 0,
+                                channel: name.clone(),
+                                value: calibration.apply(raw),
+                                raw: include_raw.then_some(raw),
+                                generated_at_micros: clock.now_micros(),
+                            };
+
+                            match &latest {
+                                // the aggregating task numbers the samples it sends
+                                Some(latest) => latest.lock().unwrap()[index] = Some(sample),
+                                None => {
+                                    sample.seq = seq.fetch_add(1, Ordering::Relaxed);
+                                    queue.push(vec![sample]).await;
+                                }
+                            }
+                        }
+                    }
+                }),
+            );
+        }
+
+        // send the latest sample of every channel together. Otherwise the queue is closed once
+        // all channels are done, which ends the data task.
+        if let Some(latest) = latest {
+            let interval_rx = interval_rx.clone();
+            let metrics_aggregate = metrics.clone();
+
+            tasks.push(
+                supervisor.spawn("aggregate", move |mut shutdown_aggregate| {
+                    let mut interval_rx = interval_rx.clone();
+                    let metrics_aggregate = metrics_aggregate.clone();
+                    let latest = latest.clone();
+                    let seq = seq.clone();
+                    let queue = queue.clone();
+                    async move {
+                        if !wait_for_startup(startup_delay, &mut shutdown_aggregate).await {
+                            return;
+                        }
+                        let mut ticker = Ticker::new(
+                            *interval_rx.borrow_and_update(),
+                            jitter,
+                            missed_tick_behavior,
+                        );
+
+                        loop {
+                            tokio::select! {
+                                _ = ticker.tick() => {}
+                                Ok(()) = interval_rx.changed() => {
+                                    ticker.set_period(*interval_rx.borrow_and_update());
+                                    continue;
+                                }
+                                _ = shutdown_aggregate.recv() => break,
+                            }
+                            if metrics_aggregate.paused.load(Ordering::Relaxed) {
+                                continue;
+                            }
+
+                            let mut samples: Vec<DataSample> =
+                                latest.lock().unwrap().iter().flatten().cloned().collect();
+                            // no channel has generated a value yet
+                            if samples.is_empty() {
+                                continue;
+                            }
+                            for sample in &mut samples {
+                                sample.seq = seq.fetch_add(1, Ordering::Relaxed);
+                            }
+                            queue.push(samples).await;
+                        }
+                    }
+                }),
+            );
+        } else {
+            drop(queue);
+        }
+
+        let target_data_rx = target_rx.clone();
+        let metrics_data = metrics.clone();
+        let send_max_retries = config.send_max_retries.unwrap();
+        let batch_size = config.batch_size.unwrap();
+        let batch_timeout = Duration::from_millis(config.batch_timeout_ms.unwrap());
+        let max_payload_bytes = config.max_payload_bytes;
+        let faults = FaultInjection {
+            loss_percent: config.simulate_loss_percent.unwrap(),
+            dup_percent: config.simulate_dup_percent.unwrap(),
+            max_delay: Duration::from_millis(config.simulate_delay_ms.unwrap()),
+        };
+        if faults.is_active() {
+            warn!(
+                loss_percent = faults.loss_percent,
+                dup_percent = faults.dup_percent,
+                max_delay_ms = faults.max_delay.as_millis() as u64,
+                "Simulating network faults, data packets will be dropped, duplicated or delayed on purpose"
+            );
+        }
+        let capture_data = capture.clone();
+        let quiet_data = quiet.clone();
+        // the current config, replaced when it is reloaded
+        let (config_tx, config_data_rx) = watch::channel(Arc::new(config.clone()));
+        let config_inbound_rx = config_data_rx.clone();
+        let reload_state = shared((
+            config.clone(),
+            reloads,
+            Reloadable {
+                config: config_tx,
+                interval: interval_tx.clone(),
+                targets: target_tx.clone(),
+                quiet: quiet.clone(),
+                restart_count,
+            },
+        ));
+        tasks.push(supervisor.spawn("reload", move |shutdown| {
+            let reload_state = reload_state.clone();
+            async move {
+                let (current, reloads, reloadable) = &mut *reload_state.lock().await;
+                reload::apply_reloads(current, reloads, reloadable, shutdown).await;
+            }
+        }));
+        #[cfg(feature = "mqtt")]
+        let (mqtt, mqtt_event_loop) = split_mqtt(mqtt);
+        #[cfg(feature = "mqtt")]
+        if let Some((client, event_loop)) = mqtt_event_loop {
+            let event_loop = shared(event_loop);
+            tasks.push(supervisor.spawn("mqtt", move |shutdown| {
+                let event_loop = event_loop.clone();
+                let client = client.clone();
+                async move {
+                    mqtt::run_event_loop(&mut *event_loop.lock().await, client, shutdown).await
+                }
+            }));
+        }
+        let sink: Arc<dyn OutputSink> = build_sinks(
+            &config,
+            outbound_socket_data.clone(),
+            prepared,
+            #[cfg(feature = "mqtt")]
+            mqtt,
+        )
+        .into();
+
+        // send input data
+        tasks.push(supervisor.spawn("data", move |mut shutdown_data| {
+            let data_rx = data_rx.clone();
+            let mut config_data_rx = config_data_rx.clone();
+            let quiet_data = quiet_data.clone();
+            let target_data_rx = target_data_rx.clone();
+            let sink = sink.clone();
+            let metrics_data = metrics_data.clone();
+            let capture_data = capture_data.clone();
+            async move {
+                // one buffer per destination, reused for every packet
+                let mut buffers: Vec<Vec<u8>> = vec![];
+                // the packets of a batch that was too large, sent one after the other
+                let mut parts: VecDeque<Vec<DataSample>> = VecDeque::new();
+                loop {
+                    let samples = match parts.pop_front() {
+                        Some(part) => part,
+                        None => {
+                            let samples = tokio::select! {
+                                samples = async { data_rx.lock().await.recv().await } => samples,
+                                _ = shutdown_data.recv() => break,
+                            };
+                            // the generators are done (replay exhausted)
+                            let Some(mut samples) = samples else {
+                                break;
+                            };
+
+                            // fill up the batch, sending what's there once the first sample has waited too long
+                            let deadline = time::Instant::now() + batch_timeout;
+                            while samples.len() < batch_size {
+                                match time::timeout_at(deadline, async { data_rx.lock().await.recv().await }).await {
+                                    Ok(Some(more)) => samples.extend(more),
+                                    Ok(None) | Err(_) => break,
+                                }
+                            }
+                            match max_payload_bytes {
+                                Some(max_payload_bytes) if samples.len() > 1 => {
+                                    buffers.resize_with(buffers.len().max(1), Vec::new);
+                                    let sample_count = samples.len();
+                                    parts = split_batch(samples, max_payload_bytes, &mut buffers[0], &config_data_rx.borrow()).into();
+                                    if parts.len() > 1 && !quiet_data.load(Ordering::Relaxed) {
+                                        debug!(samples = sample_count, packets = parts.len(), max_payload_bytes, "Split batch exceeding `max_payload_bytes`");
+                                    }
+                                    parts.pop_front().expect("at least one part")
+                                }
+                                _ => samples,
+                            }
+                        }
+                    };
+                    let sample = &samples[0];
+                    let config_data = config_data_rx.borrow_and_update().clone();
+                    let quiet = quiet_data.load(Ordering::Relaxed);
+
+                    // the sink doesn't send anything in a dry run
+                    if dry_run {
+                        buffers.resize_with(1, Vec::new);
+                        print_packet(&mut buffers[0], &samples, &config_data);
+                    }
+
+                    // every destination is sent to (and retried) on its own, so a dead consumer
+                    // doesn't hold up the others
+                    let targets: Vec<SocketAddr> =
+                        target_data_rx.borrow().iter().map(|target| target.address).collect();
+                    let destinations = sink.destinations(&targets);
+                    if buffers.len() < destinations.len() {
+                        buffers.resize_with(destinations.len(), Vec::new);
+                    }
+                    let sends = future::join_all(destinations.into_iter().zip(buffers.iter_mut()).map(|(target, buf)| {
+                        let samples = &samples;
+                        let config_data = &config_data;
+                        let sink = &sink;
+                        let target_data_rx = &target_data_rx;
+                        let metrics_data = &metrics_data;
+                        let capture_data = &capture_data;
+                        let fault = faults.next();
+                        async move {
+                            if !fault.delay.is_zero() {
+                                debug!(%target, seq = sample.seq, delay = ?fault.delay, "Simulating delay");
+                                time::sleep(fault.delay).await;
+                            }
+                            if fault.drop {
+                                // the node believes the packet went out
+                                debug!(%target, seq = sample.seq, "Simulating packet loss");
+                                metrics_data.count_sent();
+                                return;
+                            }
+
+                            // transient errors (e.g. ENOBUFS or a briefly unroutable target) are retried, and a
+                            // sample that still can't be sent is dropped instead of ending the data stream
+                            let mut backoff = DATA_SEND_INITIAL_BACKOFF;
+                            let mut attempt = 0;
+                            loop {
+                                if !quiet {
+                                    debug!(%target, seq = sample.seq, value = %sample.value, samples = samples.len(), attempt, "Sending data");
+                                }
+                                // encoded as late as possible so time spent inside the node isn't counted as latency
+                                encode_packet(buf, samples, config_data);
+                                match sink.send(buf, target).await {
+                                    Ok(()) => {
+                                        metrics_data.count_sent();
+                                        capture_data.record(Direction::Outbound, target, buf);
+                                        if fault.duplicate {
+                                            debug!(%target, seq = sample.seq, "Simulating duplication");
+                                            if sink.send(buf, target).await.is_ok() {
+                                                capture_data.record(Direction::Outbound, target, buf);
+                                            }
+                                        }
+                                        break;
+                                    }
+                                    Err(e) if attempt < send_max_retries => {
+                                        warn!(%target, seq = sample.seq, error = %e, retry_in = ?backoff, "Couldn't send data, retrying");
+                                        time::sleep(backoff).await;
+                                        backoff = (backoff * 2).min(DATA_SEND_MAX_BACKOFF);
+                                        attempt += 1;
+                                        // no point in retrying a target that was removed in the meantime
+                                        let targets: Vec<SocketAddr> = target_data_rx
+                                            .borrow()
+                                            .iter()
+                                            .map(|target| target.address)
+                                            .collect();
+                                        if !sink.destinations(&targets).contains(&target) {
+                                            break;
+                                        }
+                                    }
+                                    Err(e) => {
+                                        warn!(%target, seq = sample.seq, error = %e, "Couldn't send data, dropping the sample");
+                                        metrics_data.count_send_error(target);
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                    }));
+                    tokio::select! {
+                        _ = sends => {}
+                        // retries against an unresponsive endpoint mustn't hold up the shutdown
+                        _ = shutdown_data.recv() => break,
+                    }
+                }
+            }
+        }));
+
+        let target_updates_tx = target_tx.clone();
+        let metrics_inbound = metrics.clone();
+        let quiet_inbound = quiet.clone();
+        let config_inbound = config.clone();
+        let acks = AckSender::new(
+            outbound_socket_acks.clone(),
+            config.ack_repeat.unwrap(),
+            Duration::from_millis(config.ack_repeat_interval_ms.unwrap()),
+            config.ack_confirm_max_attempts.unwrap(),
+            quiet.clone(),
+            config.shared_secret.clone(),
+            capture.clone(),
+        );
+        let inbound = Inbound {
+            config: config.clone(),
+            current_config: config_inbound_rx,
+            targets: target_updates_tx,
+            interval: interval_tx.clone(),
+            metrics: metrics_inbound.clone(),
+            acks,
+            socket: outbound_socket_acks.clone(),
+            capture: capture.clone(),
+            started: start_instant,
+            restart_count,
+            ports,
+            last_update: None,
+            last_issued_at: None,
+        };
+        // the handlers' state survives a restart, the receive buffer is only reused
+        let inbound_state = shared((inbound, vec![0; config.inbound_buffer_size.unwrap()]));
+        let inbound_socket = Arc::new(inbound_socket);
+
+        // receive target updates
+        let supervisor_inbound = supervisor.clone();
+        tasks.push(supervisor.spawn("inbound", move |mut shutdown_inbound| {
+            let supervisor = supervisor_inbound.clone();
+            let config = config_inbound.clone();
+            let inbound_socket = inbound_socket.clone();
+            let inbound_state = inbound_state.clone();
+            let metrics_inbound = metrics_inbound.clone();
+            let quiet_inbound = quiet_inbound.clone();
+            let capture = capture.clone();
+            async move {
+                if dry_run {
+                    return;
+                }
+                let (inbound, buf) = &mut *inbound_state.lock().await;
+                let dispatcher = Dispatcher::new();
+                let mut rate_limiter = config.inbound_rate_limit.map(RateLimiter::new);
+                let mut receive_errors = ReceiveErrors::default();
+                loop {
+                    let received = tokio::select! {
+                        received = inbound_socket.recv_from(buf) => received,
+                        _ = shutdown_inbound.recv() => break,
+                    };
+                    let quiet = quiet_inbound.load(Ordering::Relaxed);
+
+                    let (message_length, src) = match received {
+                        Ok(received) => {
+                            receive_errors.reset();
+                            received
+                        }
+                        Err(e) => {
+                            metrics_inbound
+                                .receive_errors
+                                .fetch_add(1, Ordering::Relaxed);
+                            match receive_errors.record(&e, std::time::Instant::now()) {
+                                Some(backoff) => {
+                                    warn!(error = %e, retry_in = ?backoff, "Couldn't receive on the inbound socket");
+                                    tokio::select! {
+                                        _ = time::sleep(backoff) => continue,
+                                        _ = shutdown_inbound.recv() => break,
+                                    }
+                                }
+                                None => {
+                                    error!(error = %e, "Receiving on the inbound socket keeps failing, shutting down the node");
+                                    supervisor.fail("inbound", format!("receiving keeps failing: {}", e));
+                                    break;
+                                }
+                            }
+                        }
+                    };
+
+                    // before any parsing, that's the work a flood would cause
+                    if let Some(rate_limiter) = &mut rate_limiter {
+                        if !rate_limiter.allow(src.ip(), std::time::Instant::now()) {
+                            metrics_inbound
+                                .rate_limited_messages
+                                .fetch_add(1, Ordering::Relaxed);
+                            if !quiet {
+                                debug!(%src, "Dropped message exceeding `inbound_rate_limit`");
+                            }
+                            continue;
+                        }
+                    }
+                    capture.record(Direction::Inbound, src, &buf[..message_length]);
+                    if message_length == buf.len() {
+                        warn!(
+                            %src,
+                            buffer_size = buf.len(),
+                            "Message filled the whole receive buffer and may have been truncated, consider increasing `inbound_buffer_size`"
+                        );
+                    }
+
+                    // convert to string
+                    let message = match String::from_utf8(buf[..message_length].into()) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            warn!(
+                                %src,
+                                error = %e,
+                                payload = ?&buf[..message_length],
+                                "Ignoring non-UTF-8 message"
+                            );
+                            continue;
+                        }
+                    };
+                    if !quiet {
+                        debug!(%src, %message, "Received message");
+                    }
+
+                    // several messages may be packed into one datagram as newline-delimited JSON
+                    let parsed: Vec<(&str, serde_json::Result<serde_json::Value>)> =
+                        match serde_json::from_str(&message) {
+                            Ok(json) => vec![(message.as_str(), Ok(json))],
+                            Err(_) => message
+                                .lines()
+                                .map(str::trim)
+                                .filter(|line| !line.is_empty())
+                                .map(|line| (line, serde_json::from_str(line)))
+                                .collect(),
+                        };
+                    for (message, json) in parsed {
+                        let json = match json {
+                            Ok(json) => json,
+                            Err(e) => {
+                                warn!(%src, error = %e, %message, "Ignoring malformed JSON");
+                                continue;
+                            }
+                        };
+                        metrics_inbound.count_inbound(json["type"].as_str());
+
+                        if let Some(allowlist) = &config.controller_allowlist {
+                            let is_ping = json["type"] == "udpPing";
+                            if (!is_ping || config.allowlist_pings.unwrap())
+                                && !allowlist.iter().any(|range| range.contains(src.ip()))
+                            {
+                                warn!(%src, message_type = %json["type"], "Rejected message from a source not in `controller_allowlist`");
+                                metrics_inbound
+                                    .rejected_messages
+                                    .fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                        }
+
+                        // pings don't change any state, so they are answered without authentication
+                        if let Some(secret) = &config.shared_secret {
+                            if json["type"] != "udpPing" {
+                                if let Err(e) = secret.verify(&json) {
+                                    warn!(%src, message_type = %json["type"], error = e, "Rejected message that failed HMAC verification");
+                                    metrics_inbound
+                                        .rejected_messages
+                                        .fetch_add(1, Ordering::Relaxed);
+                                    continue;
+                                }
+                            }
+                        }
+
+                        let request = Request {
+                            src,
+                            request_id: json.get("request_id").cloned(),
+                            quiet,
+                            node_id: inbound.config.node_id.as_deref().unwrap().into(),
+                        };
+                        if let Err(e) = dispatcher.dispatch(inbound, &json, &request).await {
+                            reject_invalid(inbound, &json, &request, &e).await;
+                        }
+                    }
+                }
+            }
+        }));
+
+        if let Some(listener) = metrics_listener {
+            let listener = Arc::new(listener);
+            let metrics = metrics.clone();
+            let target_rx = target_rx.clone();
+            tasks.push(supervisor.spawn("metrics", move |shutdown| {
+                let listener = listener.clone();
+                let metrics = metrics.clone();
+                let target_rx = target_rx.clone();
+                async move { metrics::serve(&listener, metrics, target_rx, shutdown).await }
+            }));
+        }
+
+        if let Some(listener) = health_listener {
+            let listener = Arc::new(listener);
+            let metrics = metrics.clone();
+            let readiness_failure = Duration::from_millis(config.readiness_failure_ms.unwrap());
+            tasks.push(supervisor.spawn("health", move |shutdown| {
+                let listener = listener.clone();
+                let metrics = metrics.clone();
+                async move { health::serve(&listener, metrics, readiness_failure, shutdown).await }
+            }));
+        }
+
+        if let (Some(ping_socket), Some(ping_interval)) = (ping_socket, config.ping_interval_ms) {
+            let ping_socket = Arc::new(ping_socket);
+            let target_rx = target_rx.clone();
+            let report_every = config.ping_report_every.unwrap() as usize;
+            let metrics = metrics.clone();
+            let supervisor_ping = supervisor.clone();
+            tasks.push(supervisor.spawn("ping", move |shutdown| {
+                let ping_socket = ping_socket.clone();
+                let target_rx = target_rx.clone();
+                let metrics = metrics.clone();
+                let supervisor = supervisor_ping.clone();
+                async move {
+                    let result = ping::run_pinger(
+                        &ping_socket,
+                        target_rx,
+                        Duration::from_millis(ping_interval),
+                        report_every,
+                        metrics,
+                        shutdown,
+                    )
+                    .await;
+                    if let Err(e) = result {
+                        error!(error = %e, "Receiving on the ping socket keeps failing, shutting down the node");
+                        supervisor.fail("ping", format!("receiving keeps failing: {}", e));
+                    }
+                }
+            }));
+        }
+
+        if let Some((failover_socket, candidates)) = failover {
+            let failover_socket = Arc::new(failover_socket);
+            let target_tx = target_tx.clone();
+            let check_interval = Duration::from_millis(config.failover_check_interval_ms.unwrap());
+            let policy = HealthPolicy {
+                timeout: Duration::from_millis(config.failover_timeout_ms.unwrap()),
+                max_rtt: config.failover_max_rtt_ms.map(Duration::from_millis),
+                failback_after: Duration::from_millis(config.failback_stable_ms.unwrap()),
+            };
+            let metrics = metrics.clone();
+            let supervisor_failover = supervisor.clone();
+            tasks.push(supervisor.spawn("failover", move |shutdown| {
+                let failover_socket = failover_socket.clone();
+                let candidates = candidates.clone();
+                let target_tx = target_tx.clone();
+                let metrics = metrics.clone();
+                let supervisor = supervisor_failover.clone();
+                async move {
+                    let result = failover::run_failover(
+                        &failover_socket,
+                        candidates,
+                        target_tx,
+                        check_interval,
+                        policy,
+                        metrics,
+                        shutdown,
+                    )
+                    .await;
+                    if let Err(e) = result {
+                        error!(error = %e, "Receiving on the failover socket keeps failing, shutting down the node");
+                        supervisor.fail("failover", format!("receiving keeps failing: {}", e));
+                    }
+                }
+            }));
+        }
+
+        // follow DNS changes of the target hosts
+        if let Some(resolve_interval) = config.target_resolve_interval.filter(|_| !dry_run) {
+            let target_resolve_tx = target_tx.clone();
+            let bind_address = config.bind_address.unwrap();
+
+            tasks.push(supervisor.spawn("resolve", move |mut shutdown_resolve| {
+                let target_resolve_tx = target_resolve_tx.clone();
+                async move {
+                    let mut interval = time::interval(Duration::from_millis(resolve_interval));
+                    // the first tick completes immediately, but the targets were just resolved at startup
+                    interval.tick().await;
+
+                    loop {
+                        tokio::select! {
+                            _ = interval.tick() => {}
+                            _ = shutdown_resolve.recv() => break,
+                        }
+
+                        let hosts: Vec<(String, u16)> = target_resolve_tx
+                            .borrow()
+                            .iter()
+                            .map(|target| (target.host.clone(), target.port))
+                            .collect();
+                        for (host, port) in hosts {
+                            match resolve_address_from(&host, port, bind_address).await {
+                                Ok(address) => {
+                                    target_resolve_tx.send_if_modified(|targets| {
+                                        // the targets might have been updated while resolving
+                                        let Some(target) = targets.iter_mut().find(|target| {
+                                            target.host == host && target.port == port
+                                        }) else {
+                                            return false;
+                                        };
+                                        if target.address == address {
+                                            return false;
+                                        }
+                                        info!(
+                                            %host,
+                                            port,
+                                            %address,
+                                            previous = %target.address,
+                                            "Target host resolves to a new address"
+                                        );
+                                        target.address = address;
+                                        true
+                                    });
+                                }
+                                Err(e) => {
+                                    warn!(
+                                        %host,
+                                        port,
+                                        error = %e,
+                                        "Couldn't re-resolve target, keeping the current address"
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+            }));
+        }
+
+        // run until the shutdown, or until a task panicked more often than `task_max_restarts`
+        let result = tokio::select! {
+            _ = shutdown => {
+                info!("Shutdown signal received, stopping...");
+                Ok(())
+            }
+            Some(failed) = failed_tasks.recv() => Err(failed),
+        };
+        // no receivers left means all tasks are already gone, which is fine
+        let _ = shutdown_tx.send(());
+
+        future::join_all(tasks).await;
+
+        // the final accounting, also for nodes without `metrics_port`
+        let stats = metrics.stats();
+        info!(inbound = ?stats.inbound_messages, "Input node stopped: {}", stats);
+        result
+    }
+}
+
+impl Node {
+    /// Generates one sample per channel and sends it to every target once (or prints it, in a dry
+    /// run) instead of running. Channels are sent in separate packets unless `aggregate` is set.
+    /// Fails if a packet couldn't be sent.
+    pub async fn send_once(self) -> Result<(), Box<dyn Error>> {
+        let Node {
+            config,
+            initial_targets,
+            channels,
+            outbound_socket_data,
+            prepared,
+            #[cfg(feature = "mqtt")]
+            mqtt,
+            ..
+        } = self;
+        let calibration = calibration(&config);
+        let include_raw = config.include_raw.unwrap();
+
+        let mut samples = vec![];
+        for (seq, channel) in channels.into_iter().enumerate() {
+            let Channel {
+                name,
+                source: mut data_source,
+                ..
+            } = channel;
+            let raw = data_source
+                .next(Duration::ZERO)
+                .ok_or("Replay file is empty, no data to send")?;
+            samples.push(DataSample {
+                seq: seq as u64,
+                channel: name,
+                value: calibration.apply(raw),
+                raw: include_raw.then_some(raw),
+                generated_at_micros: config.clock_source.unwrap().now_micros(),
+            });
+        }
+        let packets: Vec<&[DataSample]> = if config.aggregate.unwrap() {
+            vec![&samples]
+        } else {
+            samples.chunks(1).collect()
+        };
+
+        #[cfg(feature = "mqtt")]
+        let (mqtt, mut mqtt_event_loop) = split_mqtt(mqtt);
+        let sink = build_sinks(
+            &config,
+            Arc::new(outbound_socket_data),
+            prepared,
+            #[cfg(feature = "mqtt")]
+            mqtt,
+        );
+        let targets: Vec<SocketAddr> = initial_targets
+            .iter()
+            .map(|target| target.address)
+            .collect();
+        let mut buf = vec![];
+        for samples in packets {
+            if config.dry_run.unwrap() {
+                print_packet(&mut buf, samples, &config);
+            }
+            encode_packet(&mut buf, samples, &config);
+            for target in sink.destinations(&targets) {
+                sink.send(&buf, target)
+                    .await
+                    .map_err(|e| format!("Couldn't send data to {}: {}", target, e))?;
+                info!(%target, seq = samples[0].seq, "Sent data");
+            }
+        }
+        // the publishes were only queued, nothing runs the event loop in the background
+        #[cfg(feature = "mqtt")]
+        if let Some((client, event_loop)) = &mut mqtt_event_loop {
+            mqtt::flush(event_loop, client)
+                .await
+                .map_err(|e| format!("Couldn't publish data to MQTT: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+/// The sinks data packets go to: nothing in a dry run, otherwise the targets (through the
+/// configured `transport`, unless `mqtt_only`) and the MQTT broker, if there is one
+fn build_sinks(
+    config: &Config,
+    socket: Arc<UdpSocket>,
+    prepared: Option<Box<dyn OutputSink>>,
+    #[cfg(feature = "mqtt")] mqtt: Option<MqttSink>,
+) -> Box<dyn OutputSink> {
+    let mut sinks: Vec<Box<dyn OutputSink>> = vec![];
+    if config.dry_run.unwrap() {
+        sinks.push(Box::new(NullSink));
+    } else if !config.mqtt_only.unwrap() {
+        sinks.push(transport::sink(
+            config.transport.unwrap(),
+            socket,
+            config.encoding.unwrap(),
+            config.compression.unwrap(),
+            config.bind_address.unwrap(),
+            prepared,
+        ));
+    }
+    #[cfg(feature = "mqtt")]
+    if let Some(mqtt) = mqtt {
+        info!(topic = mqtt.topic(), "Publishing data to MQTT");
+        sinks.push(Box::new(mqtt));
+    }
+    MultiSink::combine(sinks)
+}
+
+/// Separates the MQTT sink from the event loop that has to run for its packets to be published,
+/// together with a client to disconnect it
+#[cfg(feature = "mqtt")]
+fn split_mqtt(
+    mqtt: Option<(MqttSink, rumqttc::EventLoop)>,
+) -> (
+    Option<MqttSink>,
+    Option<(rumqttc::AsyncClient, rumqttc::EventLoop)>,
+) {
+    mqtt.map(|(mqtt, event_loop)| {
+        let client = mqtt.client();
+        (mqtt, (client, event_loop))
+    })
+    .unzip()
+}
+
+/// A sensor of the node, generating values in its own task
+struct Channel {
+    /// tags the samples, `None` for single-channel nodes
+    name: Option<Arc<str>>,
+    source: Box<dyn DataSource>,
+    /// a fixed interval instead of the node's adjustable one
+    interval: Option<Duration>,
+}
+
+/// The producer side of the queue between the generators and the data task
+#[derive(Clone)]
+struct DataQueue {
+    tx: mpsc::Sender<Vec<DataSample>>,
+    rx: Arc<Mutex<mpsc::Receiver<Vec<DataSample>>>>,
+    overflow_policy: OverflowPolicy,
+    metrics: Arc<Metrics>,
+}
+
+impl DataQueue {
+    /// Queues samples to be sent in the same packet, applying the overflow policy if the queue is
+    /// full
+    async fn push(&self, samples: Vec<DataSample>) {
+        let Err(mpsc::error::TrySendError::Full(samples)) = self.tx.try_send(samples) else {
+            return;
+        };
+        let dropped = match self.overflow_policy {
+            OverflowPolicy::DropNewest => samples[0].seq,
+            OverflowPolicy::DropOldest => {
+                let oldest = self.rx.lock().await.try_recv().ok();
+                // another channel may have taken the freed spot, then the new samples are dropped
+                // after all
+                if let Err(mpsc::error::TrySendError::Full(samples)) = self.tx.try_send(samples) {
+                    self.count_dropped(samples[0].seq);
+                }
+                match oldest {
+                    Some(oldest) => oldest[0].seq,
+                    None => return,
+                }
+            }
+        };
+        self.count_dropped(dropped);
+    }
+
+    fn count_dropped(&self, seq: u64) {
+        self.metrics
+            .data_packets_dropped
+            .fetch_add(1, Ordering::Relaxed);
+        warn!(seq, "Send queue full, dropped a sample");
+    }
+}
+
+/// Scaling of the generated values, from the `calibration_*` options
+fn calibration(config: &Config) -> Calibration {
+    Calibration {
+        scale: config.calibration_scale.unwrap(),
+        offset: config.calibration_offset.unwrap(),
+        round: config.calibration_round.unwrap(),
+    }
+}
+
+/// Splits `samples` into batches whose packets are at most `max_bytes` long, halving them until
+/// they fit. A single sample can't be split further, so it is sent even if it is too large.
+fn split_batch(
+    samples: Vec<DataSample>,
+    max_bytes: usize,
+    buf: &mut Vec<u8>,
+    config: &Config,
+) -> Vec<Vec<DataSample>> {
+    encode_packet(buf, &samples, config);
+    if buf.len() <= max_bytes {
+        return vec![samples];
+    }
+    if samples.len() == 1 {
+        warn!(
+            seq = samples[0].seq,
+            bytes = buf.len(),
+            max_bytes,
+            "Sample exceeds `max_payload_bytes` on its own, sending it anyway"
+        );
+        return vec![samples];
+    }
+    let mut first = samples;
+    let second = first.split_off(first.len() / 2);
+    let mut parts = split_batch(first, max_bytes, buf, config);
+    parts.extend(split_batch(second, max_bytes, buf, config));
+    parts
+}
+
+/// Replaces the content of `buf` with the data packet for `samples` in the configured encoding,
+/// sent now
+fn encode_packet(buf: &mut Vec<u8>, samples: &[DataSample], config: &Config) {
+    let timestamp = config.timestamp_data.unwrap();
+    let sent_at_micros = if timestamp {
+        config.clock_source.unwrap().now_micros()
+    } else {
+        0
+    };
+    let packet = DataPacket {
+        meta_extra: config.meta_extra.as_ref(),
+        sent_at_micros: timestamp.then_some(sent_at_micros),
+        message_as_number: config.emit_message_as_number.unwrap(),
+        node_id: config.node_id.as_deref(),
+        ..DataPacket::new(
+            samples,
+            &config.flow_name,
+            &config.area,
+            timestamp,
+            config.checksum.unwrap(),
+        )
+    };
+    match config.encoding.unwrap() {
+        Encoding::Json => packet.write_json(buf),
+        Encoding::Msgpack => packet.write_msgpack(buf),
+        // batches are simply the packets of their samples back to back
+        Encoding::Binary => {
+            buf.clear();
+            for sample in samples {
+                buf.extend_from_slice(&encode_binary(sample, sent_at_micros));
+            }
+        }
+    }
+    let compression = config.compression.unwrap();
+    if compression != Compression::None {
+        let encoded = std::mem::take(buf);
+        compression::compress(compression, &encoded, buf);
+    }
+}
+
+/// Prints the data packet for `samples` instead of sending it, as JSON or (for binary encodings and
+/// compressed packets) hex
+fn print_packet(buf: &mut Vec<u8>, samples: &[DataSample], config: &Config) {
+    encode_packet(buf, samples, config);
+    match (config.encoding.unwrap(), config.compression.unwrap()) {
+        (Encoding::Json, Compression::None) => logging::print_line(&String::from_utf8_lossy(buf)),
+        _ => logging::print_line(&hex::encode(buf)),
+    }
+}
+
+/// Waits `delay` before the first sample, giving receivers time to come up. `false` if the node
+/// shuts down in the meantime.
+async fn wait_for_startup(delay: Duration, shutdown: &mut broadcast::Receiver<()>) -> bool {
+    if delay.is_zero() {
+        return true;
+    }
+    tokio::select! {
+        _ = time::sleep(delay) => true,
+        _ = shutdown.recv() => false,
+    }
+}
+
+/// The `(host, port)` pairs of `target_ip`/`target_port` and `targets`
+pub(crate) fn configured_targets(config: &Config) -> Vec<(String, u16)> {
+    let mut targets = vec![(config.target_ip.clone(), config.target_port)];
+    for target in config.targets.iter().flatten() {
+        targets.push((target.host.clone(), target.port));
+    }
+    targets
+}
+
+/// Resolves `(host, port)` pairs into targets a socket on `bind_address` can send to, failing if
+/// any of them doesn't resolve to such an address. `targets` must not be empty.
+pub(crate) async fn resolve_targets(
+    targets: &[(String, u16)],
+    bind_address: IpAddr,
+) -> Result<Targets, Box<dyn Error>> {
+    let mut resolved: Option<Targets> = None;
+    for (host, port) in targets {
+        let target = Target {
+            host: host.clone(),
+            port: *port,
+            address: resolve_address_from(host, *port, bind_address).await?,
+        };
+        match &mut resolved {
+            Some(resolved) => {
+                resolved.add(target);
+            }
+            None => resolved = Some(Targets::new(target)),
+        }
+    }
+    Ok(resolved.expect("at least one target"))
+}
+
+/// Runs an input node with `config` until `shutdown` completes
+pub async fn run(config: Config, shutdown: impl Future<Output = ()>) -> Result<(), Box<dyn Error>> {
+    Node::bind(config).await?.run(shutdown).await?;
+    Ok(())
+}
+
+/// Delay before retrying a failed data send, doubled after every attempt
+const DATA_SEND_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+/// Upper bound for the delay between retries of a failed data send
+const DATA_SEND_MAX_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Binds a UDP socket on `bind_address` with the configured socket options, naming the socket's
+/// role and the option setting its port in the error if binding fails.
+async fn bind_socket(
+    role: &str,
+    option: Option<&str>,
+    config: &Config,
+    port: u16,
+) -> Result<UdpSocket, Box<dyn Error>> {
+    let address = SocketAddr::new(config.bind_address.unwrap(), port);
+    let socket = bind_with_options(address, config).map_err(|e| {
+        // usually the port is taken, e.g. by another node started with the same config
+        let hint = option
+            .map(|option| format!(" (change `{}`)", option))
+            .unwrap_or_default();
+        format!(
+            "Failed to bind {} socket on {}: {}{}",
+            role, address, e, hint
+        )
+    })?;
+    let sock_ref = SockRef::from(&socket);
+    debug!(role, address = %socket.local_addr()?, "Bound socket");
+    // the OS may clamp the sizes (and Linux doubles them for its own bookkeeping)
+    if config.socket_send_buffer.is_some() || config.socket_recv_buffer.is_some() {
+        info!(
+            role,
+            send_buffer = sock_ref.send_buffer_size()?,
+            recv_buffer = sock_ref.recv_buffer_size()?,
+            "Set socket buffer sizes"
+        );
+    }
+    Ok(socket)
+}
+
+/// `SO_REUSEADDR` and the buffer sizes have to be set before the socket is bound
+fn bind_with_options(address: SocketAddr, config: &Config) -> io::Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    socket.set_reuse_address(config.reuse_address.unwrap())?;
+    if let Some(size) = config.socket_send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.socket_recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    // `target::reachable_from` treats sockets on `::` as dual-stack, which isn't the default
+    // everywhere (e.g. on BSD, Windows or with `net.ipv6.bindv6only=1`)
+    if address.is_ipv6() && address.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    UdpSocket::from_std(socket.into())
+}
@@ -0,0 +1,238 @@
+use crate::metrics::Metrics;
+use crate::receive_errors::ReceiveErrors;
+use crate::target::Targets;
+use serde_json::json;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, watch};
+use tokio::time::{self, Instant};
+use tracing::{debug, info, warn};
+
+/// How many pings can be in flight before the oldest ones are forgotten
+const PING_WINDOW: usize = 64;
+
+/// Keeps track of sent pings and the round-trip times of their replies
+pub struct PingTracker {
+    /// sent pings, indexed by `seq % PING_WINDOW`
+    outstanding: [Option<(u64, Instant)>; PING_WINDOW],
+    samples: Vec<Duration>,
+    report_every: usize,
+}
+
+/// Round-trip time statistics over a number of samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RttStats {
+    pub samples: usize,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    /// mean absolute difference between consecutive samples
+    pub jitter: Duration,
+}
+
+impl PingTracker {
+    pub fn new(report_every: usize) -> Self {
+        PingTracker {
+            outstanding: [None; PING_WINDOW],
+            samples: Vec::with_capacity(report_every),
+            report_every: report_every.max(1),
+        }
+    }
+
+    pub fn sent(&mut self, seq: u64, at: Instant) {
+        self.outstanding[seq as usize % PING_WINDOW] = Some((seq, at));
+    }
+
+    /// Records the reply to ping `seq`, returning its RTT unless the ping is unknown or was
+    /// already answered
+    pub fn reply(&mut self, seq: u64, at: Instant) -> Option<Duration> {
+        let slot = &mut self.outstanding[seq as usize % PING_WINDOW];
+        match *slot {
+            Some((sent_seq, sent_at)) if sent_seq == seq => {
+                *slot = None;
+                let rtt = at.saturating_duration_since(sent_at);
+                self.samples.push(rtt);
+                Some(rtt)
+            }
+            _ => None,
+        }
+    }
+
+    /// Records a reply that doesn't say which ping it answers by matching it to the most recent
+    /// outstanding ping (pings are usually spaced far apart compared to their RTT, and older
+    /// outstanding ones are most likely lost)
+    pub fn reply_unkeyed(&mut self, at: Instant) -> Option<Duration> {
+        let newest = self
+            .outstanding
+            .iter()
+            .flatten()
+            .map(|(seq, _)| *seq)
+            .max()?;
+        self.reply(newest, at)
+    }
+
+    /// Returns the stats of the collected samples once enough of them are there, starting a
+    /// new window
+    pub fn take_report(&mut self) -> Option<RttStats> {
+        if self.samples.len() < self.report_every {
+            return None;
+        }
+        let stats = RttStats::from_samples(&self.samples);
+        self.samples.clear();
+        stats
+    }
+}
+
+impl RttStats {
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        let min = *samples.iter().min()?;
+        let max = *samples.iter().max()?;
+        let avg = samples.iter().sum::<Duration>() / samples.len() as u32;
+        let jitter = if samples.len() > 1 {
+            samples
+                .windows(2)
+                .map(|pair| pair[0].abs_diff(pair[1]))
+                .sum::<Duration>()
+                / (samples.len() - 1) as u32
+        } else {
+            Duration::ZERO
+        };
+
+        Some(RttStats {
+            samples: samples.len(),
+            min,
+            avg,
+            max,
+            jitter,
+        })
+    }
+}
+
+/// The IP the peer at `address` should reply to: the one the socket is bound to, or if it is
+/// bound to all interfaces, the local IP the OS would use to reach `address`
+pub async fn reply_ip(local_address: SocketAddr, address: SocketAddr) -> std::io::Result<IpAddr> {
+    if !local_address.ip().is_unspecified() {
+        return Ok(local_address.ip());
+    }
+    let unspecified = match address {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let probe = UdpSocket::bind((unspecified, 0)).await?;
+    probe.connect(address).await?;
+    Ok(probe.local_addr()?.ip())
+}
+
+/// Periodically pings the primary target and logs RTT statistics every `report_every` replies.
+///
+/// Each ping carries a `nonce` (its sequence number). Replies are matched by their `echo` field
+/// if they are JSON, and otherwise (like the raw timestamp replies of `udpPing`) to the most
+/// recent ping that is still outstanding. Errors receiving replies are backed off from, and
+/// returned once they keep coming (see [`ReceiveErrors`]).
+pub async fn run_pinger(
+    socket: &UdpSocket,
+    targets: watch::Receiver<Targets>,
+    interval: Duration,
+    report_every: usize,
+    metrics: Arc<Metrics>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> io::Result<()> {
+    let local_address = match socket.local_addr() {
+        Ok(address) => address,
+        Err(e) => {
+            warn!(error = %e, "Couldn't get ping socket address, not pinging");
+            return Ok(());
+        }
+    };
+    let mut tracker = PingTracker::new(report_every);
+    let mut interval = time::interval(interval);
+    let mut seq: u64 = 0;
+    let mut buf = [0; 1024];
+    // the reply address only changes when the target does
+    let mut reply_to: Option<(SocketAddr, SocketAddr)> = None;
+    let mut receive_errors = ReceiveErrors::default();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let address = targets.borrow().primary().address;
+                let reply_address = match reply_to {
+                    Some((for_target, reply_address)) if for_target == address => reply_address,
+                    _ => match reply_ip(local_address, address).await {
+                        Ok(ip) => {
+                            let reply_address = SocketAddr::new(ip, local_address.port());
+                            reply_to = Some((address, reply_address));
+                            reply_address
+                        }
+                        Err(e) => {
+                            warn!(target = %address, error = %e, "Couldn't determine reply address for ping");
+                            continue;
+                        }
+                    },
+                };
+
+                let ping = json!({
+                    "type": "udpPing",
+                    "replyTo": reply_address.to_string(),
+                    "nonce": seq,
+                });
+                tracker.sent(seq, Instant::now());
+                if let Err(e) = socket.send_to(ping.to_string().as_bytes(), address).await {
+                    warn!(target = %address, error = %e, "Couldn't send ping");
+                }
+                seq += 1;
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (length, src) = match received {
+                    Ok(received) => {
+                        receive_errors.reset();
+                        received
+                    }
+                    Err(e) => match receive_errors.record(&e, std::time::Instant::now()) {
+                        // e.g. the ICMP port unreachable of a target that is down
+                        Some(backoff) => {
+                            debug!(error = %e, retry_in = ?backoff, "Couldn't receive ping reply");
+                            tokio::select! {
+                                _ = time::sleep(backoff) => continue,
+                                _ = shutdown.recv() => break,
+                            }
+                        }
+                        None => return Err(e),
+                    },
+                };
+                let now = Instant::now();
+                let echoed = serde_json::from_slice::<serde_json::Value>(&buf[..length])
+                    .ok()
+                    .and_then(|reply| reply["echo"].as_u64());
+                let rtt = match echoed {
+                    Some(seq) => tracker.reply(seq, now),
+                    None => tracker.reply_unkeyed(now),
+                };
+                match rtt {
+                    Some(rtt) => {
+                        debug!(%src, rtt_micros = rtt.as_micros() as u64, "Ping reply");
+                        metrics.ping_replies.fetch_add(1, Ordering::Relaxed);
+                    }
+                    None => debug!(%src, "Ignoring late or unexpected ping reply"),
+                }
+
+                if let Some(stats) = tracker.take_report() {
+                    info!(
+                        samples = stats.samples,
+                        min_micros = stats.min.as_micros() as u64,
+                        avg_micros = stats.avg.as_micros() as u64,
+                        max_micros = stats.max.as_micros() as u64,
+                        jitter_micros = stats.jitter.as_micros() as u64,
+                        "Ping RTT"
+                    );
+                }
+            }
+            _ = shutdown.recv() => break,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,981 @@
+use crate::compression;
+use crate::encoding;
+use crate::generator::{DataSample, SampleValue};
+use serde::ser::{SerializeMap, SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How the node answers `udpPing`
+#[derive(
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PingReplyFormat {
+    // the current time as 8 big-endian bytes, or `udpPong` JSON if the ping has a nonce
+    #[default]
+    Raw,
+    // always `udpPong` JSON
+    Json,
+}
+
+// The control messages received on the inbound port are parsed as the struct of their `type`
+// (`updateTarget` as `target::UpdateTarget`), ignoring `type`, `request_id` and `hmac`, which
+// apply to every message and are read from the raw JSON instead.
+
+/// A control message without fields of its own, e.g. `pauseData`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NoFields {}
+
+/// The fields of a `setInterval` message
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SetInterval {
+    pub interval_ms: u64,
+}
+
+/// The fields of a `queryStatus` message
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QueryStatus {
+    #[serde(rename = "replyTo")]
+    pub reply_to: Option<SocketAddr>,
+}
+
+/// The fields of an `updateTargetConfirmed` message
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateTargetConfirmed {
+    pub request_id: Value,
+}
+
+/// The fields of a `udpPing` message
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UdpPing {
+    #[serde(rename = "replyTo")]
+    pub reply_to: Option<SocketAddr>,
+    pub nonce: Option<u64>,
+}
+
+/// Which way a message travels, seen from the node
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    // sent by controllers (or anyone else) to the inbound port
+    Inbound,
+    // sent by the node
+    Outbound,
+}
+
+/// A field of a JSON message
+#[derive(Serialize, Debug)]
+pub struct FieldSpec {
+    pub name: &'static str,
+    /// JSON type, e.g. `string`, `integer` or `boolean`
+    #[serde(rename = "type")]
+    pub ty: &'static str,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+/// A message the node sends or accepts
+#[derive(Serialize, Debug)]
+pub struct MessageSpec {
+    /// the value of the `type` field, or a descriptive name for messages without one
+    pub name: &'static str,
+    pub direction: Direction,
+    pub description: &'static str,
+    pub fields: &'static [FieldSpec],
+}
+
+const fn field(
+    name: &'static str,
+    ty: &'static str,
+    required: bool,
+    description: &'static str,
+) -> FieldSpec {
+    FieldSpec {
+        name,
+        ty,
+        required,
+        description,
+    }
+}
+
+const REQUEST_ID: FieldSpec = field(
+    "request_id",
+    "any",
+    false,
+    "echoed in the ACK, which is then resent until confirmed with `updateTargetConfirmed`",
+);
+const HMAC: FieldSpec = field(
+    "hmac",
+    "string",
+    false,
+    "hex HMAC-SHA256 over the message without this field (compact JSON, sorted keys), required if a shared secret is configured",
+);
+const TYPE: FieldSpec = field("type", "string", true, "the message type");
+const SUCCESS: FieldSpec = field(
+    "success",
+    "boolean",
+    true,
+    "whether the request was applied",
+);
+const ERROR: FieldSpec = field(
+    "error",
+    "string",
+    false,
+    "why the request was rejected, only if `success` is false",
+);
+const NODE_ID: FieldSpec = field(
+    "node_id",
+    "string",
+    true,
+    "the `node_id` of the node that sent the message",
+);
+
+/// Every message type of the wire protocol
+pub const MESSAGES: &[MessageSpec] = &[
+    MessageSpec {
+        name: "data",
+        direction: Direction::Outbound,
+        description: "An input value, sent to every current target each interval",
+        fields: &[
+            field(
+                "message",
+                "string",
+                true,
+                "the value, a number of the configured value type (an integer from 0 to 65535 by default), or a float if calibrated. Sent as a JSON number instead if `emit_message_as_number` is set",
+            ),
+            field("seq", "integer", true, "sequence number, incremented for every sample"),
+            field(
+                "channel",
+                "string",
+                false,
+                "name of the channel that generated the value, if channels are configured",
+            ),
+            field(
+                "meta",
+                "object",
+                true,
+                "`flow_name`, `execution_area`, `node_id` and `seq`, plus `raw` (the value before calibration, as a number) if enabled and the tags of `meta_extra`",
+            ),
+            field(
+                "generated_at_micros",
+                "integer",
+                false,
+                "when the sample was generated (µs since the UNIX epoch), unless timestamps are disabled",
+            ),
+            field(
+                "sent_at_micros",
+                "integer",
+                false,
+                "when the packet was sent (µs since the UNIX epoch), unless timestamps are disabled",
+            ),
+            field(
+                "crc32",
+                "integer",
+                false,
+                "CRC-32 (IEEE) of the UTF-8 bytes of `message`, if checksums are enabled",
+            ),
+        ],
+    },
+    MessageSpec {
+        name: "dataBatch",
+        direction: Direction::Outbound,
+        description: "Several input values in one packet, sent instead of `data` if batching or channel aggregation is enabled",
+        fields: &[
+            field(
+                "samples",
+                "array",
+                true,
+                "the values, objects with the `message`, `seq`, `channel`, `generated_at_micros` and `crc32` fields of `data`, plus `raw` if enabled",
+            ),
+            field("meta", "object", true, "`flow_name`, `execution_area` and `node_id`, plus the tags of `meta_extra`"),
+            field(
+                "sent_at_micros",
+                "integer",
+                false,
+                "when the packet was sent (µs since the UNIX epoch), unless timestamps are disabled",
+            ),
+        ],
+    },
+    MessageSpec {
+        name: "updateTarget",
+        direction: Direction::Inbound,
+        description: "Changes the targets of the data stream",
+        fields: &[
+            TYPE,
+            field(
+                "op",
+                "string",
+                false,
+                "`replace` (default) makes the target the only one, `add` adds it, `remove` removes it",
+            ),
+            field("target", "string", true, "IP address or hostname of the target"),
+            field("target_port", "integer", false, "the target port, required in `absolute` mode"),
+            field(
+                "target_port_base",
+                "integer",
+                false,
+                "in `base_offset` mode, the new port is this plus `target_port % 10000` of the configured port",
+            ),
+            field(
+                "replyTo",
+                "string",
+                false,
+                "`<ip>:<port>` the ACK is sent to, defaults to the sender",
+            ),
+            field(
+                "issued_at",
+                "integer",
+                false,
+                "increasing with every update (e.g. a counter or timestamp), updates older than the last applied one are rejected",
+            ),
+            REQUEST_ID,
+            HMAC,
+        ],
+    },
+    MessageSpec {
+        name: "resetTarget",
+        direction: Direction::Inbound,
+        description: "Reverts the targets to the configured `target_ip`/`target_port` and `targets`",
+        fields: &[TYPE, REQUEST_ID, HMAC],
+    },
+    MessageSpec {
+        name: "updateTargetConfirmed",
+        direction: Direction::Inbound,
+        description: "Stops the node from resending an ACK",
+        fields: &[
+            TYPE,
+            field("request_id", "any", true, "the `request_id` of the ACK"),
+            HMAC,
+        ],
+    },
+    MessageSpec {
+        name: "setInterval",
+        direction: Direction::Inbound,
+        description: "Changes the data interval",
+        fields: &[
+            TYPE,
+            field("interval_ms", "integer", true, "the new interval, greater than 0"),
+            REQUEST_ID,
+            HMAC,
+        ],
+    },
+    MessageSpec {
+        name: "pauseData",
+        direction: Direction::Inbound,
+        description: "Stops sending data packets, the sequence number keeps counting",
+        fields: &[TYPE, REQUEST_ID, HMAC],
+    },
+    MessageSpec {
+        name: "resumeData",
+        direction: Direction::Inbound,
+        description: "Resumes sending data packets",
+        fields: &[TYPE, REQUEST_ID, HMAC],
+    },
+    MessageSpec {
+        name: "queryStatus",
+        direction: Direction::Inbound,
+        description: "Asks for a `status` reply",
+        fields: &[
+            TYPE,
+            field(
+                "replyTo",
+                "string",
+                false,
+                "`<ip>:<port>` the status is sent to, defaults to the sender",
+            ),
+            REQUEST_ID,
+            HMAC,
+        ],
+    },
+    MessageSpec {
+        name: "getTarget",
+        direction: Direction::Inbound,
+        description: "Asks for a `currentTarget` reply, sent to the sender",
+        fields: &[TYPE, REQUEST_ID, HMAC],
+    },
+    MessageSpec {
+        name: "udpPing",
+        direction: Direction::Inbound,
+        description: "Asks for a ping reply, used to measure round-trip times",
+        fields: &[
+            TYPE,
+            field(
+                "replyTo",
+                "string",
+                false,
+                "`<ip>:<port>` the reply is sent to, the sender of the ping if unset",
+            ),
+            field(
+                "nonce",
+                "integer",
+                false,
+                "echoed in the reply. With `ping_reply_format: raw`, the reply is `udpPong` JSON if set, otherwise the current time (µs since the UNIX epoch) as 8 big-endian bytes",
+            ),
+            HMAC,
+        ],
+    },
+    MessageSpec {
+        name: "ack",
+        direction: Direction::Outbound,
+        description: "Answer to `updateTarget`, `resetTarget`, `setInterval`, `pauseData` and `resumeData`, sent to the sender of the request (or the `replyTo` of `updateTarget`)",
+        fields: &[
+            field("type", "string", true, "the type of the request"),
+            SUCCESS,
+            ERROR,
+            field("interval_ms", "integer", false, "the new interval, for `setInterval`"),
+            field("paused", "boolean", false, "whether data is paused, for `pauseData` and `resumeData`"),
+            NODE_ID,
+            field("request_id", "any", false, "the `request_id` of the request"),
+            field("hmac", "string", false, "signature, if a shared secret is configured"),
+        ],
+    },
+    MessageSpec {
+        name: "status",
+        direction: Direction::Outbound,
+        description: "Answer to `queryStatus`",
+        fields: &[
+            TYPE,
+            field("flow_name", "string", true, "configured flow name"),
+            field("area", "string", true, "configured area"),
+            field("target", "string", true, "primary target address as `<ip>:<port>`"),
+            field("targets", "array", true, "addresses of all targets, starting with the primary one"),
+            field("interval_ms", "integer", true, "current data interval"),
+            field("paused", "boolean", true, "whether data is paused"),
+            field("packets_sent", "integer", true, "data packets sent"),
+            field("packets_dropped", "integer", true, "samples dropped because the send queue was full"),
+            field("packets_failed", "integer", true, "samples dropped after failing to send"),
+            field("target_updates", "integer", true, "applied target updates"),
+            field("rejected_messages", "integer", true, "inbound messages rejected by the allowlist or HMAC verification"),
+            field("rate_limited_messages", "integer", true, "inbound messages dropped by `inbound_rate_limit`"),
+            field("invalid_messages", "integer", true, "inbound messages rejected for a missing `type` or invalid fields"),
+            field("inbound_messages", "object", true, "inbound messages by type, including `unknown`"),
+            field("uptime_ms", "integer", true, "time since the node started"),
+            field("uptime_seconds", "integer", true, "time since the node started, in whole seconds"),
+            field("restart_count", "integer", true, "how often the node was restarted, 0 without a `state_file`"),
+            field("ports", "object", true, "bound local ports: `data`, `acks` and `inbound`"),
+            field("time_micros", "integer", true, "current time (µs since the UNIX epoch)"),
+            NODE_ID,
+            field("request_id", "any", false, "the `request_id` of the query"),
+            field("hmac", "string", false, "signature, if a shared secret is configured"),
+        ],
+    },
+    MessageSpec {
+        name: "currentTarget",
+        direction: Direction::Outbound,
+        description: "Answer to `getTarget`",
+        fields: &[
+            TYPE,
+            field("target", "string", true, "primary target address as `<ip>:<port>`"),
+            NODE_ID,
+            field("request_id", "any", false, "the `request_id` of the query"),
+            field("hmac", "string", false, "signature, if a shared secret is configured"),
+        ],
+    },
+    MessageSpec {
+        name: "error",
+        direction: Direction::Outbound,
+        description: "Answer to a JSON message without a `type`, or with missing or invalid fields (except `updateTarget` and `setInterval`, which get a failed `ack`), sent to the sender",
+        fields: &[
+            TYPE,
+            field("reason", "string", true, "what's wrong with the message, e.g. which field is missing"),
+            NODE_ID,
+            field("request_id", "any", false, "the `request_id` of the message"),
+            field("hmac", "string", false, "signature, if a shared secret is configured"),
+        ],
+    },
+    MessageSpec {
+        name: "heartbeat",
+        direction: Direction::Outbound,
+        description: "Sent to every current target every `heartbeat_interval_ms`, even while data is paused",
+        fields: &[
+            TYPE,
+            field("flow_name", "string", true, "configured flow name"),
+            field("area", "string", true, "configured area"),
+            field("uptime_ms", "integer", true, "time since the node started"),
+            field("uptime_seconds", "integer", true, "time since the node started, in whole seconds"),
+            field("restart_count", "integer", true, "how often the node was restarted, 0 without a `state_file`"),
+            field("paused", "boolean", true, "whether data is paused"),
+            NODE_ID,
+        ],
+    },
+    MessageSpec {
+        name: "udpPong",
+        direction: Direction::Outbound,
+        description: "Answer to a `udpPing` with a `nonce`, and to every one with `ping_reply_format: json`",
+        fields: &[
+            TYPE,
+            field("server_time_micros", "integer", true, "current time (µs since the UNIX epoch)"),
+            field("echo", "integer", false, "the `nonce` of the ping, if it had one"),
+            NODE_ID,
+        ],
+    },
+];
+
+/// Machine-readable description of the wire protocol, printed by `--dump-protocol`
+pub fn describe() -> Value {
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "encoding": "JSON over UDP, one message per datagram",
+        "messages": MESSAGES,
+        "binary_data": encoding::describe_binary(),
+        "compression_header": compression::describe_header(),
+    })
+}
+
+/// Names of all message types the node accepts
+pub fn inbound_message_types() -> impl Iterator<Item = &'static str> {
+    MESSAGES
+        .iter()
+        .filter(|message| message.direction == Direction::Inbound)
+        .map(|message| message.name)
+}
+
+/// Builds the data packet for `sample`
+pub fn data_packet(
+    sample: &DataSample,
+    flow_name: &str,
+    area: &str,
+    timestamp: bool,
+    checksum: bool,
+) -> Value {
+    DataPacket::new(
+        std::slice::from_ref(sample),
+        flow_name,
+        area,
+        timestamp,
+        checksum,
+    )
+    .to_value()
+}
+
+/// Builds a data packet carrying several samples, each with its own `seq` and timestamp
+pub fn batch_packet(
+    samples: &[DataSample],
+    flow_name: &str,
+    area: &str,
+    timestamp: bool,
+    checksum: bool,
+) -> Value {
+    DataPacket::new(samples, flow_name, area, timestamp, checksum).to_value()
+}
+
+/// A data packet, serialized straight from its samples. A single sample makes a `data` packet,
+/// several make a `dataBatch`. Sending it this way doesn't allocate anything once the buffer
+/// it's written to is large enough, unlike building a [`Value`] first.
+#[derive(Debug, Clone, Copy)]
+pub struct DataPacket<'a> {
+    pub samples: &'a [DataSample],
+    pub flow_name: &'a str,
+    pub area: &'a str,
+    /// include `generated_at_micros`
+    pub timestamp: bool,
+    pub checksum: bool,
+    /// static tags added to `meta`
+    pub meta_extra: Option<&'a BTreeMap<String, Value>>,
+    pub sent_at_micros: Option<u64>,
+    /// `message` as a number instead of a string
+    pub message_as_number: bool,
+    /// added to `meta` if set
+    pub node_id: Option<&'a str>,
+}
+
+impl<'a> DataPacket<'a> {
+    pub fn new(
+        samples: &'a [DataSample],
+        flow_name: &'a str,
+        area: &'a str,
+        timestamp: bool,
+        checksum: bool,
+    ) -> Self {
+        DataPacket {
+            samples,
+            flow_name,
+            area,
+            timestamp,
+            checksum,
+            meta_extra: None,
+            sent_at_micros: None,
+            message_as_number: false,
+            node_id: None,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).expect("data packets can always be serialized")
+    }
+
+    /// Replaces the content of `buf` with the packet as JSON
+    pub fn write_json(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        serde_json::to_writer(buf, self).expect("data packets can always be serialized");
+    }
+
+    /// Replaces the content of `buf` with the packet as a MessagePack map with the same keys
+    /// and values as the JSON packet
+    pub fn write_msgpack(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        rmp_serde::encode::write_named(buf, self).expect("data packets can always be serialized");
+    }
+
+    fn meta(&self, sample: Option<&'a DataSample>) -> Meta<'a> {
+        Meta {
+            flow_name: self.flow_name,
+            area: self.area,
+            node_id: self.node_id,
+            // batches share their meta, so the raw values go next to the calibrated ones
+            seq: sample.map(|sample| sample.seq),
+            raw: sample.and_then(|sample| sample.raw),
+            extra: self.meta_extra,
+        }
+    }
+}
+
+impl Serialize for DataPacket<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // MessagePack needs the exact number of entries up front
+        let sent_at_entries = self.sent_at_micros.is_some() as usize;
+        match self.samples {
+            [sample] => {
+                let fields = SampleFields {
+                    sample,
+                    timestamp: self.timestamp,
+                    checksum: self.checksum,
+                    message_as_number: self.message_as_number,
+                    with_raw: false,
+                };
+                let mut map = serializer.serialize_map(Some(fields.len() + 1 + sent_at_entries))?;
+                fields.serialize_entries(&mut map)?;
+                map.serialize_entry("meta", &self.meta(Some(sample)))?;
+                if let Some(sent_at_micros) = self.sent_at_micros {
+                    map.serialize_entry("sent_at_micros", &sent_at_micros)?;
+                }
+                map.end()
+            }
+            _ => {
+                let mut map = serializer.serialize_map(Some(2 + sent_at_entries))?;
+                map.serialize_entry("samples", &Samples(self))?;
+                map.serialize_entry("meta", &self.meta(None))?;
+                if let Some(sent_at_micros) = self.sent_at_micros {
+                    map.serialize_entry("sent_at_micros", &sent_at_micros)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+/// The samples of a batch
+struct Samples<'a>(&'a DataPacket<'a>);
+
+impl Serialize for Samples<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.samples.len()))?;
+        for sample in self.0.samples {
+            seq.serialize_element(&SampleFields {
+                sample,
+                timestamp: self.0.timestamp,
+                checksum: self.0.checksum,
+                message_as_number: self.0.message_as_number,
+                with_raw: true,
+            })?;
+        }
+        seq.end()
+    }
+}
+
+/// The fields describing a single sample, shared by data and batch packets
+struct SampleFields<'a> {
+    sample: &'a DataSample,
+    timestamp: bool,
+    checksum: bool,
+    message_as_number: bool,
+    /// include the raw value, which single-sample packets have in their `meta` instead
+    with_raw: bool,
+}
+
+impl SampleFields<'_> {
+    fn len(&self) -> usize {
+        2 + self.sample.channel.is_some() as usize
+            + self.timestamp as usize
+            + self.checksum as usize
+            + (self.with_raw && self.sample.raw.is_some()) as usize
+    }
+
+    fn serialize_entries<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
+        let sample = self.sample;
+        if self.message_as_number {
+            map.serialize_entry("message", &sample.value)?;
+        } else {
+            map.serialize_entry("message", &Message(sample.value))?;
+        }
+        map.serialize_entry("seq", &sample.seq)?;
+        if let Some(channel) = &sample.channel {
+            map.serialize_entry("channel", channel.as_ref())?;
+        }
+        if self.timestamp {
+            map.serialize_entry("generated_at_micros", &sample.generated_at_micros)?;
+        }
+        if self.checksum {
+            // the checksum covers the message as it is sent
+            let mut crc = Crc32(crc32fast::Hasher::new());
+            let _ = write!(crc, "{}", sample.value);
+            map.serialize_entry("crc32", &crc.0.finalize())?;
+        }
+        if let Some(raw) = sample.raw.filter(|_| self.with_raw) {
+            map.serialize_entry("raw", &raw)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for SampleFields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        self.serialize_entries(&mut map)?;
+        map.end()
+    }
+}
+
+/// A value as the string it is sent as, formatted while serializing
+struct Message(SampleValue);
+
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
+/// Checksums formatted text without allocating a string for it
+struct Crc32(crc32fast::Hasher);
+
+impl fmt::Write for Crc32 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.update(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// The `meta` of a data packet
+struct Meta<'a> {
+    flow_name: &'a str,
+    area: &'a str,
+    node_id: Option<&'a str>,
+    /// only for single-sample packets
+    seq: Option<u64>,
+    raw: Option<SampleValue>,
+    extra: Option<&'a BTreeMap<String, Value>>,
+}
+
+impl Serialize for Meta<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let extra = self.extra.into_iter().flatten();
+        let len = 2
+            + self.node_id.is_some() as usize
+            + self.seq.is_some() as usize
+            + self.raw.is_some() as usize
+            + self.extra.map_or(0, BTreeMap::len);
+        let mut map = serializer.serialize_map(Some(len))?;
+        map.serialize_entry("flow_name", self.flow_name)?;
+        map.serialize_entry("execution_area", self.area)?;
+        if let Some(node_id) = self.node_id {
+            map.serialize_entry("node_id", node_id)?;
+        }
+        if let Some(seq) = self.seq {
+            map.serialize_entry("seq", &seq)?;
+        }
+        if let Some(raw) = self.raw {
+            map.serialize_entry("raw", &raw)?;
+        }
+        for (key, value) in extra {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Keys of a data packet's `meta` that the node sets itself, so `meta_extra` can't use them
+pub const RESERVED_META_KEYS: &[&str] = &["flow_name", "execution_area", "node_id", "seq", "raw"];
+
+/// Builds a heartbeat, telling the targets the node is alive
+pub fn heartbeat(
+    flow_name: &str,
+    area: &str,
+    uptime: Duration,
+    restart_count: u64,
+    paused: bool,
+    node_id: &str,
+) -> Value {
+    json!({
+        "type": "heartbeat",
+        "flow_name": flow_name,
+        "area": area,
+        "uptime_ms": uptime.as_millis() as u64,
+        "uptime_seconds": uptime.as_secs(),
+        "restart_count": restart_count,
+        "paused": paused,
+        "node_id": node_id,
+    })
+}
+
+/// Builds the reply to a `udpPing` with a nonce, or to any with `ping_reply_format: json`
+pub fn pong(nonce: Option<u64>, server_time_micros: u64, node_id: &str) -> Value {
+    let mut pong = json!({
+        "type": "udpPong",
+        "server_time_micros": server_time_micros,
+        "node_id": node_id,
+    });
+    if let Some(nonce) = nonce {
+        pong["echo"] = nonce.into();
+    }
+    pong
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::SampleValue;
+    use crate::target::UpdateTarget;
+
+    fn spec(name: &str) -> &'static MessageSpec {
+        MESSAGES
+            .iter()
+            .find(|message| message.name == name)
+            .unwrap()
+    }
+
+    /// Checks that `message` only has fields listed in the spec and has all required ones
+    fn assert_matches_spec(message: &Value, name: &str) {
+        let spec = spec(name);
+        let fields = message.as_object().unwrap();
+        for key in fields.keys() {
+            assert!(
+                spec.fields.iter().any(|field| field.name == key),
+                "`{}` isn't documented for {}",
+                key,
+                name
+            );
+        }
+        for field in spec.fields.iter().filter(|field| field.required) {
+            assert!(
+                fields.contains_key(field.name),
+                "{} is missing `{}`",
+                name,
+                field.name
+            );
+        }
+    }
+
+    #[test]
+    fn data_packets_match_the_spec() {
+        let sample = DataSample {
+            seq: 3,
+            channel: None,
+            value: SampleValue::U16(1234),
+            raw: None,
+            generated_at_micros: 1,
+        };
+        let mut packet = data_packet(&sample, "Flow 1", "room1", true, true);
+        packet["sent_at_micros"] = 2.into();
+        assert_matches_spec(&packet, "data");
+        assert_matches_spec(
+            &data_packet(&sample, "Flow 1", "room1", false, false),
+            "data",
+        );
+    }
+
+    #[test]
+    fn batch_packets_match_the_spec() {
+        let samples = [
+            DataSample {
+                seq: 3,
+                channel: None,
+                value: SampleValue::U16(1234),
+                raw: None,
+                generated_at_micros: 1,
+            },
+            DataSample {
+                seq: 4,
+                channel: None,
+                value: SampleValue::U16(1235),
+                raw: None,
+                generated_at_micros: 2,
+            },
+        ];
+        let mut packet = batch_packet(&samples, "Flow 1", "room1", true, true);
+        packet["sent_at_micros"] = 3.into();
+        assert_matches_spec(&packet, "dataBatch");
+        assert_eq!(packet["samples"][1]["seq"], 4);
+        assert_eq!(packet["samples"][1]["generated_at_micros"], 2);
+        assert_eq!(packet["samples"][0]["crc32"], 2615402659u32);
+    }
+
+    #[test]
+    fn messages_can_be_numbers() {
+        let samples = [SampleValue::U16(1234), SampleValue::F64(21.5)].map(|value| DataSample {
+            seq: 0,
+            channel: None,
+            value,
+            raw: None,
+            generated_at_micros: 1,
+        });
+        let packet = DataPacket {
+            message_as_number: true,
+            ..DataPacket::new(&samples[..1], "Flow 1", "room1", false, true)
+        };
+        assert_eq!(packet.to_value()["message"], 1234);
+        // the checksum is the same as for the string
+        assert_eq!(packet.to_value()["crc32"], 2615402659u32);
+        let batch = DataPacket {
+            message_as_number: true,
+            ..DataPacket::new(&samples, "Flow 1", "room1", false, false)
+        };
+        assert_eq!(batch.to_value()["samples"][1]["message"], 21.5);
+    }
+
+    #[test]
+    fn written_packets_match_the_built_ones() {
+        let samples = [
+            DataSample {
+                seq: 3,
+                channel: Some("temperature".into()),
+                value: SampleValue::F64(21.5),
+                raw: Some(SampleValue::U16(1234)),
+                generated_at_micros: 1,
+            },
+            DataSample {
+                seq: 4,
+                channel: None,
+                value: SampleValue::U16(1235),
+                raw: None,
+                generated_at_micros: 2,
+            },
+        ];
+        let meta_extra = BTreeMap::from([("site".to_string(), json!("berlin"))]);
+        let mut buf = vec![];
+        for samples in [&samples[..1], &samples[..]] {
+            let packet = DataPacket {
+                meta_extra: Some(&meta_extra),
+                sent_at_micros: Some(5),
+                node_id: Some("node-1"),
+                ..DataPacket::new(samples, "Flow 1", "room1", true, true)
+            };
+            let mut expected = DataPacket::new(samples, "Flow 1", "room1", true, true).to_value();
+            expected["meta"]["site"] = "berlin".into();
+            expected["meta"]["node_id"] = "node-1".into();
+            expected["sent_at_micros"] = 5.into();
+
+            packet.write_json(&mut buf);
+            assert_eq!(serde_json::from_slice::<Value>(&buf).unwrap(), expected);
+            packet.write_msgpack(&mut buf);
+            assert_eq!(rmp_serde::from_slice::<Value>(&buf).unwrap(), expected);
+        }
+        assert_eq!(
+            data_packet(&samples[0], "Flow 1", "room1", false, false),
+            json!({
+                "message": "21.5",
+                "seq": 3,
+                "channel": "temperature",
+                "meta": {"flow_name": "Flow 1", "execution_area": "room1", "seq": 3, "raw": 1234},
+            })
+        );
+    }
+
+    #[test]
+    fn checksums_cover_the_message() {
+        let sample = DataSample {
+            seq: 3,
+            channel: None,
+            value: SampleValue::U16(1234),
+            raw: None,
+            generated_at_micros: 1,
+        };
+        let packet = data_packet(&sample, "Flow 1", "room1", true, true);
+        assert_eq!(packet["crc32"], 2615402659u32);
+        assert!(data_packet(&sample, "Flow 1", "room1", true, false)
+            .get("crc32")
+            .is_none());
+    }
+
+    #[test]
+    fn ping_replies_match_the_spec() {
+        assert_matches_spec(&pong(Some(7), 1, "node-1"), "udpPong");
+        assert_matches_spec(&pong(None, 1, "node-1"), "udpPong");
+    }
+
+    #[test]
+    fn message_names_are_unique() {
+        for message in MESSAGES {
+            assert_eq!(
+                MESSAGES.iter().filter(|m| m.name == message.name).count(),
+                1,
+                "{} is listed twice",
+                message.name
+            );
+        }
+    }
+
+    #[test]
+    fn inbound_messages_are_typed() {
+        assert_eq!(
+            SetInterval::deserialize(
+                &json!({"type": "setInterval", "interval_ms": 500, "request_id": 1})
+            )
+            .unwrap(),
+            SetInterval { interval_ms: 500 }
+        );
+        assert_eq!(
+            UdpPing::deserialize(
+                &json!({"type": "udpPing", "replyTo": "127.0.0.1:5000", "nonce": 7})
+            )
+            .unwrap(),
+            UdpPing {
+                reply_to: Some("127.0.0.1:5000".parse().unwrap()),
+                nonce: Some(7),
+            }
+        );
+        assert_eq!(
+            NoFields::deserialize(&json!({"type": "pauseData", "request_id": 1})).unwrap(),
+            NoFields {}
+        );
+
+        let missing =
+            UpdateTarget::deserialize(&json!({"type": "updateTarget", "target_port": 5000}))
+                .unwrap_err();
+        assert!(missing.to_string().contains("target"), "{}", missing);
+        assert!(
+            SetInterval::deserialize(&json!({"type": "setInterval", "interval_ms": "fast"}))
+                .is_err()
+        );
+        assert_eq!(
+            UdpPing::deserialize(&json!({"type": "udpPing"})).unwrap(),
+            UdpPing {
+                reply_to: None,
+                nonce: None,
+            }
+        );
+        assert!(UdpPing::deserialize(&json!({"type": "udpPing", "replyTo": "nowhere"})).is_err());
+
+        let relayed =
+            json!({"type": "updateTarget", "target": "10.0.0.5", "replyTo": "[fd00::1]:5000"});
+        assert_eq!(
+            UpdateTarget::deserialize(&relayed).unwrap().reply_to,
+            Some("[fd00::1]:5000".parse().unwrap())
+        );
+        assert!(UpdateTarget::deserialize(
+            &json!({"type": "updateTarget", "target": "10.0.0.5", "replyTo": "nowhere"})
+        )
+        .is_err());
+    }
+}
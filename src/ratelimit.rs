@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Sources tracked individually, any further ones share a single bucket so a flood from many
+/// (e.g. spoofed) addresses can't grow the map without bound
+const MAX_SOURCES: usize = 1024;
+
+/// Limits the inbound messages per second of every source IP with a token bucket each, so one
+/// noisy peer can't crowd out the controller. Up to one second's worth of messages can arrive in
+/// a burst.
+pub(crate) struct RateLimiter {
+    /// messages per second
+    rate: f64,
+    buckets: HashMap<IpAddr, Bucket>,
+    /// shared by the sources beyond [`MAX_SOURCES`]
+    others: Bucket,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: f64, now: Instant) -> Self {
+        Bucket {
+            tokens: rate,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> Self {
+        let rate = rate as f64;
+        RateLimiter {
+            rate,
+            buckets: HashMap::new(),
+            others: Bucket::full(rate, Instant::now()),
+        }
+    }
+
+    /// Whether a message from `ip` arriving at `now` is within the limit, using up a token if so
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let rate = self.rate;
+        let ip = ip.to_canonical();
+        if !self.buckets.contains_key(&ip) && self.buckets.len() >= MAX_SOURCES {
+            // buckets that have refilled completely behave like new ones, so they can go
+            self.buckets.retain(|_, bucket| {
+                bucket.refill(rate, now);
+                bucket.tokens < rate
+            });
+        }
+        let bucket = if self.buckets.len() < MAX_SOURCES {
+            self.buckets
+                .entry(ip)
+                .or_insert_with(|| Bucket::full(rate, now))
+        } else {
+            self.buckets.get_mut(&ip).unwrap_or(&mut self.others)
+        };
+        bucket.refill(rate, now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_every_source_on_its_own() {
+        let mut limiter = RateLimiter::new(10);
+        let now = Instant::now();
+        let noisy: IpAddr = "10.0.0.9".parse().unwrap();
+        let controller: IpAddr = "10.0.0.5".parse().unwrap();
+
+        let allowed = (0..100).filter(|_| limiter.allow(noisy, now)).count();
+        assert_eq!(allowed, 10);
+        assert!(limiter.allow(controller, now));
+
+        // refilled at the rate
+        let later = now + Duration::from_millis(300);
+        let allowed = (0..100).filter(|_| limiter.allow(noisy, later)).count();
+        assert_eq!(allowed, 3);
+    }
+
+    #[test]
+    fn sources_beyond_the_limit_share_a_bucket() {
+        let mut limiter = RateLimiter::new(2);
+        let now = Instant::now();
+        for i in 0..MAX_SOURCES as u32 {
+            assert!(limiter.allow(IpAddr::from((0x0a00_0000 + i).to_be_bytes()), now));
+        }
+        let others: Vec<bool> = (0..3)
+            .map(|i| limiter.allow(IpAddr::from([192, 168, 0, i]), now))
+            .collect();
+        assert_eq!(others, vec![true, true, false]);
+        assert_eq!(limiter.buckets.len(), MAX_SOURCES);
+
+        // once the tracked sources are quiet again, new ones get their own bucket
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.allow(IpAddr::from([192, 168, 0, 2]), later));
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+}
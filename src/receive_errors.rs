@@ -0,0 +1,93 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Delay before receiving again after an error on a socket, doubled with every further error in
+/// a row
+const RECEIVE_ERROR_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+/// Upper bound for the delay after an error on a socket
+const RECEIVE_ERROR_MAX_BACKOFF: Duration = Duration::from_secs(1);
+/// How long receiving may keep failing with errors that aren't transient before the node gives up
+const RECEIVE_ERROR_FATAL_AFTER: Duration = Duration::from_secs(30);
+
+/// The errors in a row of receiving on a socket, so they are backed off from instead of retried
+/// in a hot loop
+#[derive(Default)]
+pub(crate) struct ReceiveErrors {
+    in_a_row: u32,
+    /// when the first error that isn't transient since the last received message happened
+    failing_since: Option<Instant>,
+}
+
+impl ReceiveErrors {
+    /// Called for every received message
+    pub fn reset(&mut self) {
+        *self = ReceiveErrors::default();
+    }
+
+    /// Records `error`, returning how long to wait before receiving again, or `None` if
+    /// receiving has been failing for too long to go on
+    pub fn record(&mut self, error: &io::Error, now: Instant) -> Option<Duration> {
+        self.in_a_row = self.in_a_row.saturating_add(1);
+        if !is_transient(error) {
+            let since = *self.failing_since.get_or_insert(now);
+            if now.saturating_duration_since(since) >= RECEIVE_ERROR_FATAL_AFTER {
+                return None;
+            }
+        }
+        let factor = 2u32.saturating_pow(self.in_a_row - 1);
+        Some(
+            RECEIVE_ERROR_INITIAL_BACKOFF
+                .saturating_mul(factor)
+                .min(RECEIVE_ERROR_MAX_BACKOFF),
+        )
+    }
+}
+
+/// Errors a socket recovers from by itself, like the ICMP port unreachable messages
+/// some OSes report on the next receive after sending to a closed port
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_from_receive_errors_until_giving_up() {
+        let mut errors = ReceiveErrors::default();
+        let start = std::time::Instant::now();
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let broken = io::Error::from(io::ErrorKind::BrokenPipe);
+
+        assert_eq!(
+            errors.record(&refused, start),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(
+            errors.record(&broken, start),
+            Some(Duration::from_millis(20))
+        );
+        for _ in 0..10 {
+            errors.record(&refused, start);
+        }
+        assert_eq!(errors.record(&refused, start), Some(Duration::from_secs(1)));
+        // only errors that aren't transient add up to giving up
+        let later = start + RECEIVE_ERROR_FATAL_AFTER;
+        assert!(errors.record(&refused, later).is_some());
+        assert_eq!(errors.record(&broken, later), None);
+
+        errors.reset();
+        assert_eq!(
+            errors.record(&broken, later),
+            Some(Duration::from_millis(10))
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use crate::config::Config;
+use crate::node::{configured_targets, resolve_targets};
+use crate::state::PersistedState;
+use crate::target::Targets;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{info, warn};
+
+/// The options a reload applies to a running node, all others only take effect after a restart
+pub const RELOADABLE_FIELDS: &[&str] = &[
+    "interval",
+    "target_ip",
+    "target_port",
+    "targets",
+    "meta_extra",
+    "quiet",
+];
+
+/// Hands new configs to a running [`Node`](crate::Node), e.g. after its config file changed.
+/// Sockets, counters and everything else that isn't [reloadable](RELOADABLE_FIELDS) are kept.
+#[derive(Clone)]
+pub struct Reloader(pub(crate) mpsc::UnboundedSender<Config>);
+
+impl Reloader {
+    /// Applies `config`, which is expected to be [validated](Config::validate) already. Does
+    /// nothing once the node has stopped.
+    pub fn reload(&self, config: Config) {
+        let _ = self.0.send(config);
+    }
+}
+
+/// Where the running node keeps what a reload can change
+pub(crate) struct Reloadable {
+    pub config: watch::Sender<Arc<Config>>,
+    pub interval: Arc<watch::Sender<Duration>>,
+    pub targets: Arc<watch::Sender<Targets>>,
+    pub quiet: Arc<AtomicBool>,
+    /// kept in the state file along with the targets
+    pub restart_count: u64,
+}
+
+/// Applies every config from `reloads` on top of `current` until the shutdown
+pub(crate) async fn apply_reloads(
+    current: &mut Config,
+    reloads: &mut mpsc::UnboundedReceiver<Config>,
+    reloadable: &Reloadable,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    loop {
+        let mut config = tokio::select! {
+            Some(config) = reloads.recv() => config.with_defaults(),
+            _ = shutdown.recv() => break,
+        };
+        // a generated `node_id` isn't in the config, and isn't a change
+        if config.node_id.is_none() {
+            config.node_id = current.node_id.clone();
+        }
+
+        for field in changed_fields(current, &config) {
+            if !RELOADABLE_FIELDS.contains(&field.as_str()) {
+                warn!(field, "Changed option only takes effect after a restart");
+            }
+        }
+
+        if configured_targets(&config) != configured_targets(current) {
+            // the error isn't `Send`, so it mustn't be held across the state file write
+            let resolved =
+                resolve_targets(&configured_targets(&config), config.bind_address.unwrap())
+                    .await
+                    .map_err(|e| e.to_string());
+            match resolved {
+                Ok(targets) => {
+                    info!(%targets, "Targets updated from the config");
+                    if let Some(state_file) = &current.state_file {
+                        let state = PersistedState::from_targets(
+                            &targets,
+                            reloadable.restart_count,
+                            current.node_id.clone(),
+                        );
+                        if let Err(e) = state.save(state_file).await {
+                            warn!(state_file, error = %e, "Couldn't write state file");
+                        }
+                    }
+                    reloadable.targets.send_replace(targets);
+                    current.target_ip = config.target_ip;
+                    current.target_port = config.target_port;
+                    current.targets = config.targets;
+                }
+                Err(e) => {
+                    warn!(error = %e, "Couldn't resolve the new targets, keeping the current ones")
+                }
+            }
+        }
+        if config.interval != current.interval {
+            let interval_ms = config.interval.unwrap();
+            info!(interval_ms, "Data interval updated from the config");
+            reloadable
+                .interval
+                .send_replace(Duration::from_millis(interval_ms));
+            current.interval = config.interval;
+        }
+        current.meta_extra = config.meta_extra;
+        current.quiet = config.quiet;
+        reloadable
+            .quiet
+            .store(current.quiet.unwrap(), Ordering::Relaxed);
+
+        reloadable.config.send_replace(Arc::new(current.clone()));
+        info!("Config reloaded");
+    }
+}
+
+/// The top-level options that differ between `a` and `b`
+fn changed_fields(a: &Config, b: &Config) -> Vec<String> {
+    let (Ok(serde_json::Value::Object(a)), Ok(serde_json::Value::Object(b))) =
+        (serde_json::to_value(a), serde_json::to_value(b))
+    else {
+        return vec![];
+    };
+    a.iter()
+        .filter(|(key, value)| b.get(*key) != Some(value))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_changed_fields() {
+        let config: Config = serde_yaml::from_str(
+            "
+area: room1
+flow_name: Flow 1
+target_ip: 127.0.0.1
+target_port: 33001
+outbound_port_data: 22001
+inbound_port: 22002
+",
+        )
+        .unwrap();
+        let mut changed = config.clone();
+        changed.interval = Some(500);
+        changed.inbound_port = 22003;
+        assert_eq!(changed_fields(&config, &config), Vec::<String>::new());
+        assert_eq!(
+            changed_fields(&config, &changed),
+            vec!["inbound_port", "interval"]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use futures::future::{self, BoxFuture};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+
+/// Where data packets go, chosen by the config when the node starts. The data task asks the sink
+/// where the next packet has to go, then sends it to every destination on its own (with retries).
+pub(crate) trait OutputSink: Send + Sync {
+    /// Where the next packet goes, given the current targets. Sinks with a destination of their
+    /// own (e.g. an HTTP endpoint) ignore the targets.
+    fn destinations(&self, targets: &[SocketAddr]) -> Vec<SocketAddr>;
+
+    /// Sends a packet to one of the [destinations](OutputSink::destinations)
+    fn send<'a>(
+        &'a self,
+        payload: &'a [u8],
+        destination: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// One datagram per packet and target, the default
+pub(crate) struct UdpSink(pub Arc<UdpSocket>);
+
+impl OutputSink for UdpSink {
+    fn destinations(&self, targets: &[SocketAddr]) -> Vec<SocketAddr> {
+        targets.to_vec()
+    }
+
+    fn send<'a>(
+        &'a self,
+        payload: &'a [u8],
+        destination: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { self.0.send_to(payload, destination).await.map(|_| ()) })
+    }
+}
+
+/// Sends nothing, for dry runs
+pub(crate) struct NullSink;
+
+impl OutputSink for NullSink {
+    fn destinations(&self, _targets: &[SocketAddr]) -> Vec<SocketAddr> {
+        vec![]
+    }
+
+    fn send<'a>(
+        &'a self,
+        _payload: &'a [u8],
+        _destination: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Sends every packet through several sinks, e.g. to the targets and an MQTT broker
+pub(crate) struct MultiSink {
+    sinks: Vec<Box<dyn OutputSink>>,
+    /// the destinations of every sink for the current packet
+    routes: Mutex<Vec<Vec<SocketAddr>>>,
+}
+
+impl MultiSink {
+    /// The sink itself if there's only one
+    pub fn combine(mut sinks: Vec<Box<dyn OutputSink>>) -> Box<dyn OutputSink> {
+        if sinks.len() == 1 {
+            return sinks.pop().expect("just checked");
+        }
+        Box::new(MultiSink {
+            routes: Mutex::new(vec![vec![]; sinks.len()]),
+            sinks,
+        })
+    }
+}
+
+impl OutputSink for MultiSink {
+    fn destinations(&self, targets: &[SocketAddr]) -> Vec<SocketAddr> {
+        let routes: Vec<Vec<SocketAddr>> = self
+            .sinks
+            .iter()
+            .map(|sink| sink.destinations(targets))
+            .collect();
+        let mut destinations = vec![];
+        for destination in routes.iter().flatten() {
+            if !destinations.contains(destination) {
+                destinations.push(*destination);
+            }
+        }
+        *self.routes.lock().unwrap() = routes;
+        destinations
+    }
+
+    /// Sends through every sink that has `destination`, failing if any of them fails
+    fn send<'a>(
+        &'a self,
+        payload: &'a [u8],
+        destination: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let routes = self.routes.lock().unwrap().clone();
+        Box::pin(async move {
+            let results = future::join_all(
+                self.sinks
+                    .iter()
+                    .zip(routes)
+                    .filter(|(_, destinations)| destinations.contains(&destination))
+                    .map(|(sink, _)| sink.send(payload, destination)),
+            )
+            .await;
+            results.into_iter().collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Remembers the packets sent through it
+    struct RecordingSink {
+        destination: Option<SocketAddr>,
+        sent: Mutex<Vec<(Vec<u8>, SocketAddr)>>,
+    }
+
+    impl OutputSink for Arc<RecordingSink> {
+        fn destinations(&self, targets: &[SocketAddr]) -> Vec<SocketAddr> {
+            match self.destination {
+                Some(destination) => vec![destination],
+                None => targets.to_vec(),
+            }
+        }
+
+        fn send<'a>(
+            &'a self,
+            payload: &'a [u8],
+            destination: SocketAddr,
+        ) -> BoxFuture<'a, io::Result<()>> {
+            self.sent
+                .lock()
+                .unwrap()
+                .push((payload.to_vec(), destination));
+            Box::pin(future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test]
+    async fn multi_sink_sends_through_every_sink() {
+        let targets: Vec<SocketAddr> = vec![
+            "127.0.0.1:5000".parse().unwrap(),
+            "127.0.0.1:5001".parse().unwrap(),
+        ];
+        let endpoint: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let to_targets = Arc::new(RecordingSink {
+            destination: None,
+            sent: Mutex::default(),
+        });
+        let to_endpoint = Arc::new(RecordingSink {
+            destination: Some(endpoint),
+            sent: Mutex::default(),
+        });
+        let sink = MultiSink::combine(vec![
+            Box::new(to_targets.clone()),
+            Box::new(to_endpoint.clone()),
+        ]);
+
+        let destinations = sink.destinations(&targets);
+        assert_eq!(destinations, vec![targets[0], targets[1], endpoint]);
+        for destination in destinations {
+            sink.send(b"packet", destination).await.unwrap();
+        }
+        assert_eq!(
+            *to_targets.sent.lock().unwrap(),
+            vec![
+                (b"packet".to_vec(), targets[0]),
+                (b"packet".to_vec(), targets[1])
+            ]
+        );
+        assert_eq!(
+            *to_endpoint.sent.lock().unwrap(),
+            vec![(b"packet".to_vec(), endpoint)]
+        );
+
+        assert!(NullSink.destinations(&targets).is_empty());
+    }
+}
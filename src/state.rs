@@ -0,0 +1,64 @@
+use crate::target::Targets;
+
+/// Runtime state that is persisted across restarts
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct PersistedState {
+    /// only written by older versions, which supported a single target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PersistedTarget>,
+    #[serde(default)]
+    pub targets: Vec<PersistedTarget>,
+    /// how often the node started again with this state file, incremented on every start
+    #[serde(default)]
+    pub restart_count: u64,
+    /// the generated `node_id`, so the node keeps it across restarts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+}
+
+/// A target as it was after the last successful `updateTarget`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct PersistedTarget {
+    pub host: String,
+    pub port: u16,
+}
+
+impl PersistedState {
+    /// The state to persist for the current `targets`
+    pub fn from_targets(targets: &Targets, restart_count: u64, node_id: Option<String>) -> Self {
+        PersistedState {
+            restart_count,
+            node_id,
+            target: None,
+            targets: targets
+                .iter()
+                .map(|target| PersistedTarget {
+                    host: target.host.clone(),
+                    port: target.port,
+                })
+                .collect(),
+        }
+    }
+
+    /// The persisted targets, if there are any
+    pub fn targets(&self) -> Vec<PersistedTarget> {
+        match &self.target {
+            Some(target) if self.targets.is_empty() => vec![target.clone()],
+            _ => self.targets.clone(),
+        }
+    }
+
+    /// Reads the state file, returning `None` if it is missing or can't be parsed
+    pub fn load(path: &str) -> Option<Self> {
+        let content = std::fs::read_to_string(path).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Writes the state file atomically, by writing to a temporary file first and then renaming it
+    pub async fn save(&self, path: &str) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        let temp_path = format!("{}.tmp", path);
+        tokio::fs::write(&temp_path, json).await?;
+        tokio::fs::rename(&temp_path, path).await
+    }
+}
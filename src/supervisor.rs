@@ -0,0 +1,181 @@
+use crate::metrics::Metrics;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{error, info};
+
+/// Delay before restarting a panicked task, doubled after every restart of the same task
+const RESTART_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Upper bound for the delay before restarting a panicked task
+const RESTART_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// What a task keeps across restarts, e.g. a socket or the receiving end of a channel. The task
+/// locks it while it runs, and a panic frees it again for the restarted task.
+pub(crate) type Shared<T> = Arc<tokio::sync::Mutex<T>>;
+
+pub(crate) fn shared<T>(value: T) -> Shared<T> {
+    Arc::new(tokio::sync::Mutex::new(value))
+}
+
+/// A task of the node kept panicking or couldn't go on, so the node shut down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskFailed {
+    pub task: &'static str,
+    /// how often the task panicked, 0 if it stopped because of an error instead
+    pub panics: u32,
+    pub reason: String,
+}
+
+impl fmt::Display for TaskFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the {} task failed: {}", self.task, self.reason)
+    }
+}
+
+impl std::error::Error for TaskFailed {}
+
+/// Spawns the tasks of a node and restarts those that panic, with a backoff. A task that panics
+/// more than `max_restarts` times isn't restarted anymore, and is reported to the node instead,
+/// which then shuts down.
+#[derive(Clone)]
+pub(crate) struct Supervisor {
+    max_restarts: u32,
+    metrics: Arc<Metrics>,
+    shutdown: broadcast::Sender<()>,
+    failed: mpsc::UnboundedSender<TaskFailed>,
+}
+
+impl Supervisor {
+    /// Also returns where the tasks that failed for good are reported
+    pub fn new(
+        max_restarts: u32,
+        metrics: Arc<Metrics>,
+        shutdown: broadcast::Sender<()>,
+    ) -> (Self, mpsc::UnboundedReceiver<TaskFailed>) {
+        let (failed, failed_rx) = mpsc::unbounded_channel();
+        let supervisor = Supervisor {
+            max_restarts,
+            metrics,
+            shutdown,
+            failed,
+        };
+        (supervisor, failed_rx)
+    }
+
+    /// Shuts down the node because the task `name` can't go on, e.g. after an error it can't
+    /// recover from
+    pub fn fail(&self, name: &'static str, reason: String) {
+        let _ = self.failed.send(TaskFailed {
+            task: name,
+            panics: 0,
+            reason,
+        });
+    }
+
+    /// Spawns the task built by `make`, and builds and spawns it again whenever it panics, until
+    /// the shutdown. `make` gets the receiver the task should stop on. `name` tells the task
+    /// apart in the logs.
+    pub fn spawn<F, Fut>(&self, name: &'static str, mut make: F) -> JoinHandle<()>
+    where
+        F: FnMut(broadcast::Receiver<()>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        let mut task_shutdown = self.shutdown.subscribe();
+        tokio::spawn(async move {
+            let mut backoff = RESTART_INITIAL_BACKOFF;
+            let mut panics = 0;
+            loop {
+                let e = match tokio::spawn(make(task_shutdown)).await {
+                    Ok(()) => return,
+                    // only cancelled when the runtime shuts down
+                    Err(e) if !e.is_panic() => return,
+                    Err(e) => e,
+                };
+                panics += 1;
+                supervisor
+                    .metrics
+                    .panicked_tasks
+                    .fetch_add(1, Ordering::Relaxed);
+                if panics > supervisor.max_restarts {
+                    error!(task = name, error = %e, panics, "Task panicked too often, shutting down the node");
+                    let _ = supervisor.failed.send(TaskFailed {
+                        task: name,
+                        panics,
+                        reason: format!("panicked {} times, giving up", panics),
+                    });
+                    return;
+                }
+                supervisor
+                    .metrics
+                    .tasks_down
+                    .fetch_add(1, Ordering::Relaxed);
+                error!(task = name, error = %e, panics, restart_in = ?backoff, "Task panicked, restarting it");
+                let stopped = tokio::select! {
+                    _ = time::sleep(backoff) => false,
+                    _ = shutdown.recv() => true,
+                };
+                supervisor
+                    .metrics
+                    .tasks_down
+                    .fetch_sub(1, Ordering::Relaxed);
+                task_shutdown = supervisor.shutdown.subscribe();
+                // a shutdown right before subscribing only reached `shutdown`
+                if stopped || shutdown.try_recv().is_ok() {
+                    return;
+                }
+                backoff = (backoff * 2).min(RESTART_MAX_BACKOFF);
+                info!(task = name, "Restarted task");
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    #[tokio::test]
+    async fn restarts_panicked_tasks_until_giving_up() {
+        let metrics = Arc::new(Metrics::new());
+        let (shutdown, _) = broadcast::channel(1);
+        let (supervisor, mut failed) = Supervisor::new(2, metrics.clone(), shutdown);
+
+        let runs = Arc::new(AtomicU32::new(0));
+        let runs_task = runs.clone();
+        let task = supervisor.spawn("flaky", move |_| {
+            let runs = runs_task.clone();
+            async move {
+                // panics twice, then finishes
+                if runs.fetch_add(1, Ordering::Relaxed) < 2 {
+                    panic!("flaky");
+                }
+            }
+        });
+        task.await.unwrap();
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.panicked_tasks.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.tasks_down.load(Ordering::Relaxed), 0);
+        assert!(failed.try_recv().is_err());
+
+        supervisor
+            .spawn("broken", |_| async { panic!("broken") })
+            .await
+            .unwrap();
+        assert_eq!(
+            failed.recv().await,
+            Some(TaskFailed {
+                task: "broken",
+                panics: 3,
+                reason: "panicked 3 times, giving up".to_string()
+            })
+        );
+    }
+}
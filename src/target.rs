@@ -0,0 +1,486 @@
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+use tracing::info;
+
+/// How `updateTarget` messages specify the new target port
+#[derive(
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum UpdateTargetMode {
+    // `target_port` if given, otherwise `target_port_base` plus the last four digits of the
+    // configured `target_port`
+    #[default]
+    BaseOffset,
+    // only `target_port`, used as is
+    Absolute,
+}
+
+/// The destination of the data stream, along with the host it was resolved from
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    pub address: SocketAddr,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.address.fmt(f)
+    }
+}
+
+/// All destinations of the data stream, in the order they were added. Never empty, the first
+/// one is the primary target, which is pinged and reported to controllers that only know a
+/// single target.
+#[derive(Debug, Clone)]
+pub struct Targets(Vec<Target>);
+
+impl Targets {
+    pub fn new(primary: Target) -> Self {
+        Targets(vec![primary])
+    }
+
+    pub fn primary(&self) -> &Target {
+        &self.0[0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Target> {
+        self.0.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Target> {
+        self.0.iter_mut()
+    }
+
+    pub fn contains(&self, address: SocketAddr) -> bool {
+        self.0.iter().any(|target| target.address == address)
+    }
+
+    /// Makes `target` the only target
+    pub fn replace(&mut self, target: Target) {
+        self.0 = vec![target];
+    }
+
+    /// Adds `target` unless a target with the same host and port exists, returning whether it was added
+    pub fn add(&mut self, target: Target) -> bool {
+        if self
+            .0
+            .iter()
+            .any(|existing| existing.host == target.host && existing.port == target.port)
+        {
+            return false;
+        }
+        self.0.push(target);
+        true
+    }
+
+    /// Puts `target` in place of the target with `host` and `port`, returning whether it was there
+    pub fn substitute(&mut self, host: &str, port: u16, target: Target) -> bool {
+        match self
+            .0
+            .iter_mut()
+            .find(|existing| existing.host == host && existing.port == port)
+        {
+            Some(existing) => {
+                *existing = target;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the target with `host` and `port`, which must not be the only one
+    pub fn remove(&mut self, host: &str, port: u16) -> Result<(), String> {
+        let index = self
+            .0
+            .iter()
+            .position(|target| target.host == host && target.port == port)
+            .ok_or_else(|| format!("{}:{} is not a current target", host, port))?;
+        if self.0.len() == 1 {
+            return Err("the last target can't be removed".to_string());
+        }
+        self.0.remove(index);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Targets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, target) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            target.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// A `host:port` pair from the config, resolved when the node starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetAddress {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for TargetAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected <host>:<port>, got '{}'", s))?;
+        // IPv6 addresses are written as `[::1]:5000`
+        let host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        let port = port
+            .parse::<u16>()
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or_else(|| format!("invalid port '{}'", port))?;
+        if host.is_empty() {
+            return Err(format!("missing host in '{}'", s));
+        }
+        Ok(TargetAddress {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for TargetAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl serde::Serialize for TargetAddress {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for TargetAddress {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// What an `updateTarget` message does with the target it names
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetOp {
+    /// the target becomes the only one
+    #[default]
+    Replace,
+    /// the target is added to the current ones
+    Add,
+    /// the target is removed from the current ones
+    Remove,
+}
+
+impl fmt::Display for TargetOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TargetOp::Replace => "replace",
+            TargetOp::Add => "add",
+            TargetOp::Remove => "remove",
+        })
+    }
+}
+
+/// The fields of an `updateTarget` message
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UpdateTarget {
+    pub target: String,
+    pub target_port: Option<u16>,
+    pub target_port_base: Option<u64>,
+    #[serde(default)]
+    pub op: TargetOp,
+    /// increases with every update a controller sends, to detect reordered ones
+    pub issued_at: Option<u64>,
+    /// where the ACK goes instead of the sender
+    #[serde(rename = "replyTo")]
+    pub reply_to: Option<SocketAddr>,
+}
+
+/// Computes the new target port of the base-offset scheme: flow processors listen on
+/// `<base> + <last four digits of the configured target port>`, so controllers only need to
+/// send the base (e.g. 30000) of the processor taking over. Returns `None` if the result
+/// doesn't fit into a port number.
+pub fn port_from_base(base: u64, configured_port: u16) -> Option<u16> {
+    u16::try_from(base)
+        .ok()?
+        .checked_add(configured_port % 10000)
+}
+
+/// Extracts the new target host and port from an `updateTarget` message
+pub fn parse_update_target(
+    message: &UpdateTarget,
+    mode: UpdateTargetMode,
+    configured_port: u16,
+) -> Result<(String, u16), String> {
+    let host = message.target.clone();
+    if host.is_empty() {
+        return Err("`target` must not be empty".to_string());
+    }
+
+    let port = match (mode, message.target_port) {
+        // controllers that know the exact port can always send it
+        (_, Some(port)) => port,
+        (UpdateTargetMode::BaseOffset, None) => {
+            let base = message
+                .target_port_base
+                .ok_or("missing `target_port` or `target_port_base`")?;
+            port_from_base(base, configured_port).ok_or_else(|| {
+                format!(
+                    "`target_port_base` {} plus {} exceeds the highest port",
+                    base,
+                    configured_port % 10000
+                )
+            })?
+        }
+        (UpdateTargetMode::Absolute, None) => return Err("missing `target_port`".to_string()),
+    };
+    if port == 0 {
+        return Err("the target port must not be 0".to_string());
+    }
+    Ok((host, port))
+}
+
+/// Resolves a hostname or IP literal to a socket address, using the first result if there are several
+pub async fn resolve_address(host: &str, port: u16) -> Result<SocketAddr, Box<dyn Error>> {
+    let results = lookup(host, port).await?;
+    log_ignored(host, port, &results, 0);
+    Ok(results[0])
+}
+
+/// Resolves a hostname or IP literal to an address a UDP socket bound to `bind_address` can send
+/// to, using the first such result if there are several. Fails with an explanation if all results
+/// are of the other IP version.
+pub async fn resolve_address_from(
+    host: &str,
+    port: u16,
+    bind_address: IpAddr,
+) -> Result<SocketAddr, Box<dyn Error>> {
+    let results = lookup(host, port).await?;
+    let (index, address) = results
+        .iter()
+        .enumerate()
+        .find_map(|(i, address)| Some((i, reachable_from(*address, bind_address)?)))
+        .ok_or_else(|| {
+            format!(
+                "{}:{} only resolved to {:?}, which can't be reached from `bind_address` {}",
+                host, port, results, bind_address
+            )
+        })?;
+    log_ignored(host, port, &results, index);
+    Ok(address)
+}
+
+/// `address` in the form a UDP socket bound to `bind_address` can send to, if it can at all.
+/// IPv4 sockets can't reach IPv6 addresses, except IPv4-mapped ones, which are turned into plain
+/// IPv4 addresses. Sockets bound to `::` are dual-stack and reach both versions, sockets bound to
+/// a specific IPv6 address only reach IPv6 addresses.
+pub fn reachable_from(address: SocketAddr, bind_address: IpAddr) -> Option<SocketAddr> {
+    match (bind_address, address.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => Some(address),
+        (IpAddr::V4(_), IpAddr::V6(ip)) => ip
+            .to_ipv4_mapped()
+            .map(|ip| SocketAddr::new(ip.into(), address.port())),
+        (IpAddr::V6(bind_ip), IpAddr::V4(_)) => bind_ip.is_unspecified().then_some(address),
+    }
+}
+
+/// All addresses `host` resolves to, at least one
+async fn lookup(host: &str, port: u16) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+    let results: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("Couldn't resolve {}:{}: {}", host, port, e))?
+        .collect();
+    if results.is_empty() {
+        return Err(format!("{}:{} didn't resolve to any address", host, port).into());
+    }
+    Ok(results)
+}
+
+/// Logs which `results` of a lookup weren't used, if there were several
+fn log_ignored(host: &str, port: u16, results: &[SocketAddr], used: usize) {
+    if results.len() > 1 {
+        let ignored: Vec<SocketAddr> = results
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != used)
+            .map(|(_, address)| *address)
+            .collect();
+        info!(
+            host,
+            port,
+            address = %results[used],
+            ?ignored,
+            "Host resolved to multiple addresses, using the first usable one"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn target(host: &str, port: u16) -> Target {
+        Target {
+            host: host.to_string(),
+            port,
+            address: SocketAddr::new("127.0.0.1".parse().unwrap(), port),
+        }
+    }
+
+    #[test]
+    fn targets_add_replace_and_remove() {
+        let mut targets = Targets::new(target("primary", 5000));
+        assert!(targets.add(target("logger", 6000)));
+        assert!(!targets.add(target("logger", 6000)));
+        assert_eq!(targets.iter().count(), 2);
+        assert!(targets.contains("127.0.0.1:6000".parse().unwrap()));
+
+        assert!(targets.remove("logger", 5000).is_err());
+        assert!(targets.remove("primary", 5000).is_ok());
+        assert_eq!(targets.primary().host, "logger");
+        // there's always at least one target
+        assert!(targets.remove("logger", 6000).is_err());
+
+        assert!(targets.substitute("logger", 6000, target("backup", 6001)));
+        assert!(!targets.substitute("logger", 6000, target("backup", 6002)));
+        assert_eq!(targets.primary().host, "backup");
+
+        targets.add(target("second", 7000));
+        targets.replace(target("third", 8000));
+        assert_eq!(targets.iter().count(), 1);
+        assert_eq!(targets.primary().host, "third");
+    }
+
+    #[test]
+    fn target_addresses() {
+        let address: TargetAddress = "logger.local:6000".parse().unwrap();
+        assert_eq!(address.host, "logger.local");
+        assert_eq!(address.port, 6000);
+        let address: TargetAddress = "[::1]:6000".parse().unwrap();
+        assert_eq!(address.host, "::1");
+        assert_eq!(address.to_string(), "[::1]:6000");
+
+        assert!("logger.local".parse::<TargetAddress>().is_err());
+        assert!("logger.local:0".parse::<TargetAddress>().is_err());
+        assert!(":6000".parse::<TargetAddress>().is_err());
+    }
+
+    /// Deserializes and parses an `updateTarget` message like the inbound handler does
+    fn parse_update(
+        message: Value,
+        mode: UpdateTargetMode,
+        configured_port: u16,
+    ) -> Result<(String, u16), String> {
+        let message: UpdateTarget = serde_json::from_value(message).map_err(|e| e.to_string())?;
+        parse_update_target(&message, mode, configured_port)
+    }
+
+    #[test]
+    fn target_ops() {
+        let op = |message: Value| {
+            serde_json::from_value::<UpdateTarget>(message).map(|message| message.op)
+        };
+        assert_eq!(op(json!({"target": "a"})).unwrap(), TargetOp::Replace);
+        assert_eq!(
+            op(json!({"target": "a", "op": "add"})).unwrap(),
+            TargetOp::Add
+        );
+        assert_eq!(
+            op(json!({"target": "a", "op": "remove"})).unwrap(),
+            TargetOp::Remove
+        );
+        assert!(op(json!({"target": "a", "op": "append"})).is_err());
+        assert!(op(json!({"target": "a", "op": 1})).is_err());
+    }
+
+    #[test]
+    fn port_from_base_keeps_the_last_four_digits() {
+        assert_eq!(port_from_base(40000, 33001), Some(43001));
+        assert_eq!(port_from_base(0, 9999), Some(9999));
+    }
+
+    #[test]
+    fn port_from_base_at_the_upper_boundary() {
+        assert_eq!(port_from_base(60000, 35535), Some(65535));
+        assert_eq!(port_from_base(60000, 35536), None);
+        assert_eq!(port_from_base(65535, 30000), Some(65535));
+        assert_eq!(port_from_base(65535, 30001), None);
+        assert_eq!(port_from_base(65536, 30000), None);
+    }
+
+    #[test]
+    fn parse_update_target_modes() {
+        let message = json!({"target": "10.0.0.5", "target_port_base": 30000});
+        assert_eq!(
+            parse_update(message.clone(), UpdateTargetMode::BaseOffset, 21001),
+            Ok(("10.0.0.5".to_string(), 31001))
+        );
+        assert!(parse_update(message.clone(), UpdateTargetMode::Absolute, 21001).is_err());
+
+        let message = json!({"target": "10.0.0.5", "target_port_base": 30000, "target_port": 5123});
+        assert_eq!(
+            parse_update(message.clone(), UpdateTargetMode::BaseOffset, 21001),
+            Ok(("10.0.0.5".to_string(), 5123))
+        );
+        assert_eq!(
+            parse_update(message.clone(), UpdateTargetMode::Absolute, 21001),
+            Ok(("10.0.0.5".to_string(), 5123))
+        );
+
+        let overflowing = json!({"target": "10.0.0.5", "target_port_base": 60000});
+        assert!(parse_update(overflowing, UpdateTargetMode::BaseOffset, 35536).is_err());
+        let out_of_range = json!({"target": "10.0.0.5", "target_port": 65536});
+        assert!(parse_update(out_of_range, UpdateTargetMode::Absolute, 33001).is_err());
+        assert!(parse_update(json!({}), UpdateTargetMode::BaseOffset, 33001).is_err());
+        let zero = json!({"target": "10.0.0.5", "target_port": 0});
+        assert!(parse_update(zero, UpdateTargetMode::BaseOffset, 33001).is_err());
+        let no_host = json!({"target": "", "target_port": 5123});
+        assert!(parse_update(no_host, UpdateTargetMode::BaseOffset, 33001).is_err());
+    }
+
+    #[test]
+    fn addresses_reachable_from_the_bind_address() {
+        let v4: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:5000".parse().unwrap();
+        let any_v4: IpAddr = "0.0.0.0".parse().unwrap();
+        let any_v6: IpAddr = "::".parse().unwrap();
+
+        assert_eq!(reachable_from(v4, any_v4), Some(v4));
+        assert_eq!(reachable_from(v6, any_v4), None);
+        assert_eq!(reachable_from(mapped, any_v4), Some(v4));
+        assert_eq!(reachable_from(v6, any_v6), Some(v6));
+        assert_eq!(reachable_from(v4, any_v6), Some(v4));
+        assert_eq!(reachable_from(v4, "::1".parse().unwrap()), None);
+    }
+}
@@ -0,0 +1,132 @@
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::{self, Instant, Interval};
+
+/// What the fixed data interval does after ticks were missed because the node was busy
+#[derive(
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum MissedTickBehavior {
+    // fire all missed ticks right away to catch up
+    Burst,
+    // tick right away once, then continue one period after that
+    Delay,
+    // drop the missed ticks and continue on the original schedule
+    #[default]
+    Skip,
+}
+
+impl From<MissedTickBehavior> for time::MissedTickBehavior {
+    fn from(behavior: MissedTickBehavior) -> Self {
+        match behavior {
+            MissedTickBehavior::Burst => time::MissedTickBehavior::Burst,
+            MissedTickBehavior::Delay => time::MissedTickBehavior::Delay,
+            MissedTickBehavior::Skip => time::MissedTickBehavior::Skip,
+        }
+    }
+}
+
+/// Paces the data generator, either on a fixed interval or, with jitter, by sleeping a random
+/// duration around the period after every tick
+pub struct Ticker {
+    period: Duration,
+    jitter: Duration,
+    missed_tick_behavior: MissedTickBehavior,
+    interval: Interval,
+    /// when the next jittered tick is due
+    next: Instant,
+}
+
+impl Ticker {
+    /// Creates a ticker whose first tick completes immediately. A `jitter` of zero ticks on a
+    /// fixed interval, handling missed ticks according to `missed_tick_behavior`.
+    pub fn new(
+        period: Duration,
+        jitter: Duration,
+        missed_tick_behavior: MissedTickBehavior,
+    ) -> Self {
+        let now = Instant::now();
+        Ticker {
+            period,
+            jitter,
+            missed_tick_behavior,
+            interval: interval_at(now, period, missed_tick_behavior),
+            next: now,
+        }
+    }
+
+    /// Waits for the next tick. Cancel safe, so it can be used in `select!`.
+    pub async fn tick(&mut self) {
+        if self.jitter.is_zero() {
+            self.interval.tick().await;
+            return;
+        }
+        time::sleep_until(self.next).await;
+        self.next = Instant::now() + jittered(self.period, self.jitter);
+    }
+
+    /// Switches to a new period, with the next tick one full (jittered) period from now
+    pub fn set_period(&mut self, period: Duration) {
+        let now = Instant::now();
+        self.period = period;
+        self.interval = interval_at(now + period, period, self.missed_tick_behavior);
+        self.next = now + jittered(period, self.jitter);
+    }
+}
+
+fn interval_at(
+    start: Instant,
+    period: Duration,
+    missed_tick_behavior: MissedTickBehavior,
+) -> Interval {
+    let mut interval = time::interval_at(start, period);
+    interval.set_missed_tick_behavior(missed_tick_behavior.into());
+    interval
+}
+
+/// `period` plus a uniformly distributed offset in `[-jitter, jitter]`, so the average stays
+/// `period` unless the jitter is larger than the period and has to be cut off at zero
+fn jittered(period: Duration, jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return period;
+    }
+    let jitter = jitter.as_micros() as i64;
+    let offset = rand::thread_rng().gen_range(-jitter..=jitter);
+    let micros = (period.as_micros() as i64 + offset).max(0);
+    Duration::from_micros(micros as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_stays_within_bounds_and_averages_out() {
+        let period = Duration::from_millis(100);
+        let jitter = Duration::from_millis(20);
+        let samples: Vec<Duration> = (0..10000).map(|_| jittered(period, jitter)).collect();
+
+        assert!(samples
+            .iter()
+            .all(|sample| *sample >= period - jitter && *sample <= period + jitter));
+        let average = samples.iter().sum::<Duration>() / samples.len() as u32;
+        assert!(average.abs_diff(period) < Duration::from_millis(1));
+    }
+
+    #[test]
+    fn jitter_larger_than_the_period_is_cut_off() {
+        let period = Duration::from_millis(10);
+        assert!((0..1000)
+            .all(|_| jittered(period, Duration::from_millis(50)) <= Duration::from_millis(60)));
+        assert_eq!(jittered(period, Duration::ZERO), period);
+    }
+}
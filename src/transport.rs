@@ -0,0 +1,347 @@
+use crate::compression::Compression;
+use crate::encoding::Encoding;
+use crate::sink::{OutputSink, UdpSink};
+use crate::target::resolve_address;
+use crate::url::{parse_url, Url};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio::time::{self, Instant};
+use tracing::{info, warn};
+
+/// Delay before reconnecting to a target after a failed connection attempt, doubled after every
+/// attempt
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Upper bound for the delay between connection attempts
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// How long a connection attempt may take before it counts as failed
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How data packets get to the targets. Control messages and ACKs always use UDP.
+#[derive(
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    // one datagram per packet
+    #[default]
+    Udp,
+    // a stream per target, uncompressed JSON packets are newline-delimited, all others prefixed
+    // with their length
+    Tcp,
+    // a POST request per packet to `http_url`, instead of the targets
+    Http,
+    // a DTLS session per target, one record per packet (needs the `dtls` feature)
+    Dtls,
+}
+
+/// The sink for `transport`. `prepared` is the sink of transports that need some setup when the
+/// node is bound, i.e. [`Transport::Http`] and [`Transport::Dtls`].
+pub(crate) fn sink(
+    transport: Transport,
+    socket: Arc<UdpSocket>,
+    encoding: Encoding,
+    compression: Compression,
+    bind_address: IpAddr,
+    prepared: Option<Box<dyn OutputSink>>,
+) -> Box<dyn OutputSink> {
+    match transport {
+        Transport::Udp => Box::new(UdpSink(socket)),
+        Transport::Tcp => Box::new(TcpSink {
+            encoding,
+            compression,
+            bind_address,
+            connections: Default::default(),
+        }),
+        Transport::Http | Transport::Dtls => prepared.expect("set up by Node::bind"),
+    }
+}
+
+/// One TCP connection per target, each (re)established when a packet is sent to it
+pub(crate) struct TcpSink {
+    encoding: Encoding,
+    /// compressed packets may contain newlines, so they are always prefixed with their length
+    compression: Compression,
+    bind_address: IpAddr,
+    connections: std::sync::Mutex<HashMap<SocketAddr, Arc<Mutex<Connection<TcpStream>>>>>,
+}
+
+impl OutputSink for TcpSink {
+    /// Closes the connections to addresses that aren't targets anymore
+    fn destinations(&self, targets: &[SocketAddr]) -> Vec<SocketAddr> {
+        self.connections
+            .lock()
+            .unwrap()
+            .retain(|address, _| targets.contains(address));
+        targets.to_vec()
+    }
+
+    fn send<'a>(&'a self, payload: &'a [u8], target: SocketAddr) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.send_framed(payload, target))
+    }
+}
+
+impl TcpSink {
+    /// Sends a packet to `target`, connecting first if needed
+    async fn send_framed(&self, payload: &[u8], target: SocketAddr) -> io::Result<()> {
+        let connection = self
+            .connections
+            .lock()
+            .unwrap()
+            .entry(target)
+            .or_default()
+            .clone();
+        let mut connection = connection.lock().await;
+        let stream = connection
+            .connect(target, connect(target, self.bind_address))
+            .await?;
+        let frame = frame(payload, self.encoding, self.compression);
+        let result = stream.write_all(&frame).await;
+        if let Err(e) = &result {
+            warn!(%target, error = %e, "Lost data connection");
+            connection.stream = None;
+        }
+        result
+    }
+}
+
+/// `payload` as sent over TCP: followed by a newline if it is uncompressed JSON, otherwise
+/// prefixed with its length as a big-endian u32
+fn frame(payload: &[u8], encoding: Encoding, compression: Compression) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    if encoding == Encoding::Json && compression == Compression::None {
+        frame.extend_from_slice(payload);
+        frame.push(b'\n');
+    } else {
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+    }
+    frame
+}
+
+/// The connection to a single target, over a stream of type `S`
+pub(crate) struct Connection<S> {
+    pub stream: Option<S>,
+    backoff: Duration,
+    /// no connection attempts before this
+    retry_at: Instant,
+}
+
+impl<S> Default for Connection<S> {
+    fn default() -> Self {
+        Connection {
+            stream: None,
+            backoff: RECONNECT_INITIAL_BACKOFF,
+            retry_at: Instant::now(),
+        }
+    }
+}
+
+impl<S> Connection<S> {
+    /// The open stream, establishing it with `connect` first if there is none. Fails right away
+    /// while waiting for the next attempt after a failed one.
+    pub async fn connect(
+        &mut self,
+        target: SocketAddr,
+        connect: impl Future<Output = io::Result<S>>,
+    ) -> io::Result<&mut S> {
+        if self.stream.is_none() {
+            if Instant::now() < self.retry_at {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "not connected, waiting to reconnect",
+                ));
+            }
+            match time::timeout(CONNECT_TIMEOUT, connect).await {
+                Ok(Ok(stream)) => {
+                    info!(%target, "Connected data stream");
+                    self.backoff = RECONNECT_INITIAL_BACKOFF;
+                    self.stream = Some(stream);
+                }
+                result => {
+                    let e = match result {
+                        Ok(Err(e)) => e,
+                        _ => io::Error::new(io::ErrorKind::TimedOut, "connection timed out"),
+                    };
+                    warn!(%target, error = %e, retry_in = ?self.backoff, "Couldn't connect data stream");
+                    self.retry_at = Instant::now() + self.backoff;
+                    self.backoff = (self.backoff * 2).min(RECONNECT_MAX_BACKOFF);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(self.stream.as_mut().expect("just connected"))
+    }
+}
+
+/// Connects from `bind_address`, or from an address picked by the OS if it is unspecified
+async fn connect(target: SocketAddr, bind_address: IpAddr) -> io::Result<TcpStream> {
+    let socket = match target {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if !bind_address.is_unspecified() {
+        socket.bind(SocketAddr::new(bind_address, 0))?;
+    }
+    let stream = socket.connect(target).await?;
+    // packets are small and latency matters more than throughput
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// An HTTP server data packets are POSTed to instead of the targets, with a new connection for
+/// every request
+pub(crate) struct HttpSink {
+    address: SocketAddr,
+    /// `<host>[:<port>]` as given in the URL, for the `Host` header
+    authority: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    content_type: &'static str,
+    timeout: Duration,
+    /// connections are made from here, like the ones of [`TcpSink`]
+    bind_address: IpAddr,
+}
+
+impl OutputSink for HttpSink {
+    fn destinations(&self, _targets: &[SocketAddr]) -> Vec<SocketAddr> {
+        vec![self.address]
+    }
+
+    fn send<'a>(
+        &'a self,
+        payload: &'a [u8],
+        _endpoint: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.post(payload))
+    }
+}
+
+impl HttpSink {
+    /// Resolves the host of `url` (`http://<host>[:<port>][/<path>]`). `headers` are added to
+    /// every request, which is sent from `bind_address` and fails if it takes longer than
+    /// `timeout`.
+    pub async fn resolve(
+        url: &str,
+        headers: impl IntoIterator<Item = (String, String)>,
+        timeout: Duration,
+        encoding: Encoding,
+        bind_address: IpAddr,
+    ) -> Result<Self, Box<dyn Error>> {
+        if url.starts_with("https://") {
+            return Err(format!("`{}`: HTTPS isn't supported", url).into());
+        }
+        let Url {
+            authority,
+            host,
+            port,
+            path,
+        } = parse_url(url, "http", 80)?;
+        Ok(HttpSink {
+            address: resolve_address(&host, port).await?,
+            authority,
+            path,
+            headers: headers.into_iter().collect(),
+            content_type: match encoding {
+                Encoding::Json => "application/json",
+                Encoding::Msgpack => "application/msgpack",
+                Encoding::Binary => "application/octet-stream",
+            },
+            timeout,
+            bind_address,
+        })
+    }
+
+    async fn post(&self, payload: &[u8]) -> io::Result<()> {
+        time::timeout(self.timeout, self.request(payload))
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "HTTP request timed out",
+                ))
+            })
+    }
+
+    async fn request(&self, payload: &[u8]) -> io::Result<()> {
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.path,
+            self.authority,
+            self.content_type,
+            payload.len()
+        );
+        for (name, value) in &self.headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        let mut request = request.into_bytes();
+        request.extend_from_slice(payload);
+
+        let mut stream = connect(self.address, self.bind_address).await?;
+        stream.write_all(&request).await?;
+
+        // only the status line matters
+        let mut response = Vec::new();
+        let mut buf = [0; 512];
+        while !response.contains(&b'\n') {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..read]);
+        }
+        let status_line = String::from_utf8_lossy(&response);
+        let status_line = status_line.lines().next().unwrap_or_default();
+        match status_line.split(' ').nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some(_) => Err(io::Error::other(format!(
+                "server answered `{}`",
+                status_line
+            ))),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no valid HTTP response",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_compressed_json_with_its_length() {
+        assert_eq!(
+            frame(b"{}", Encoding::Json, Compression::None),
+            b"{}\n".to_vec()
+        );
+        // a newline in compressed data mustn't end the frame
+        let compressed = [crate::compression::HEADER_GZIP, b'\n', 0x1f];
+        assert_eq!(
+            frame(&compressed, Encoding::Json, Compression::Gzip),
+            [&[0, 0, 0, 3][..], &compressed].concat()
+        );
+        assert_eq!(
+            frame(b"\x01\x02", Encoding::Binary, Compression::None),
+            vec![0, 0, 0, 2, 1, 2]
+        );
+    }
+}
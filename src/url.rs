@@ -0,0 +1,85 @@
+/// The parts of a `<scheme>://<host>[:<port>][/<path>]` URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Url {
+    /// host and port as given, e.g. for the `Host` header
+    pub authority: String,
+    /// without the brackets of an IPv6 address
+    pub host: String,
+    pub port: u16,
+    /// `/` if not given
+    pub path: String,
+}
+
+/// Parses `url`, which has to use `scheme`, using `default_port` if it has no port
+pub(crate) fn parse_url(url: &str, scheme: &str, default_port: u16) -> Result<Url, String> {
+    let rest = url
+        .strip_prefix(scheme)
+        .and_then(|rest| rest.strip_prefix("://"))
+        .ok_or_else(|| format!("`{}` isn't a {}:// URL", url, scheme))?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        // an IPv6 address without a port
+        Some((host, _)) if host.starts_with('[') && !host.ends_with(']') => {
+            (authority, default_port)
+        }
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| format!("invalid port in `{}`", url))?,
+        ),
+        None => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(format!("no host in `{}`", url));
+    }
+    Ok(Url {
+        authority: authority.to_string(),
+        host: host.to_string(),
+        port,
+        path: path.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(authority: &str, host: &str, port: u16, path: &str) -> Url {
+        Url {
+            authority: authority.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_urls() {
+        assert_eq!(
+            parse_url("http://collector", "http", 80),
+            Ok(url("collector", "collector", 80, "/"))
+        );
+        assert_eq!(
+            parse_url("http://10.0.0.5:8080/ingest?area=room1", "http", 80),
+            Ok(url("10.0.0.5:8080", "10.0.0.5", 8080, "/ingest?area=room1"))
+        );
+        assert_eq!(
+            parse_url("http://[fd00::5]/ingest", "http", 80),
+            Ok(url("[fd00::5]", "fd00::5", 80, "/ingest"))
+        );
+        assert_eq!(
+            parse_url("mqtt://[fd00::5]:1884/", "mqtt", 1883),
+            Ok(url("[fd00::5]:1884", "fd00::5", 1884, "/"))
+        );
+        assert!(parse_url("https://collector", "http", 80).is_err());
+        assert!(parse_url("tcp://broker", "mqtt", 1883).is_err());
+        assert!(parse_url("mqttx://broker", "mqtt", 1883).is_err());
+        assert!(parse_url("http://collector:x/", "http", 80).is_err());
+        assert!(parse_url("http:///ingest", "http", 80).is_err());
+        assert!(parse_url("mqtt://", "mqtt", 1883).is_err());
+    }
+}
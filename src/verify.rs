@@ -0,0 +1,326 @@
+use crate::compression::Compression;
+use crate::config::Config;
+use crate::encoding::Encoding;
+use crate::node::Node;
+use crate::transport::Transport;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+use tracing::{info, warn};
+
+/// How long the listener keeps going after the node stopped, for packets still in flight
+const GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+/// What the listener of `verify` received, compared to the sequence numbers the node sent
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct Report {
+    /// samples received, including duplicates
+    pub received: u64,
+    /// samples that never arrived. Only gaps are noticed, so samples lost at the very end of the
+    /// test aren't counted.
+    pub lost: u64,
+    pub duplicated: u64,
+    /// samples that arrived after one with a higher sequence number
+    pub reordered: u64,
+    /// packets that aren't data packets of the configured `encoding` and `compression`
+    pub invalid: u64,
+    /// `lost` relative to the samples sent up to the last one received
+    pub loss_percent: f64,
+    /// the highest sequence number received
+    pub last_seq: Option<u64>,
+}
+
+impl Report {
+    /// Whether anything arrived, and everything in order exactly once
+    pub fn passed(&self) -> bool {
+        self.received > 0
+            && self.lost == 0
+            && self.duplicated == 0
+            && self.reordered == 0
+            && self.invalid == 0
+    }
+}
+
+/// Checks the sequence numbers of received samples for gaps, duplicates and reordering. The
+/// node numbers its samples from 0, across all channels.
+#[derive(Debug, Default)]
+pub struct SeqChecker {
+    next: u64,
+    /// ranges (start to exclusive end) of sequence numbers skipped so far, split up again if
+    /// they arrive late, so a stray huge `seq` doesn't need memory for every number it skips
+    missing: BTreeMap<u64, u64>,
+    /// the number of sequence numbers in `missing`
+    lost: u64,
+    received: u64,
+    duplicated: u64,
+    reordered: u64,
+    invalid: u64,
+}
+
+impl SeqChecker {
+    pub fn record(&mut self, seq: u64) {
+        self.received += 1;
+        if seq >= self.next {
+            if seq > self.next {
+                self.missing.insert(self.next, seq);
+                self.lost += seq - self.next;
+            }
+            self.next = seq.saturating_add(1);
+            return;
+        }
+        match self.missing.range(..=seq).next_back() {
+            Some((&start, &end)) if seq < end => {
+                self.missing.remove(&start);
+                if start < seq {
+                    self.missing.insert(start, seq);
+                }
+                if seq + 1 < end {
+                    self.missing.insert(seq + 1, end);
+                }
+                self.lost -= 1;
+                self.reordered += 1;
+            }
+            _ => self.duplicated += 1,
+        }
+    }
+
+    pub fn record_invalid(&mut self) {
+        self.invalid += 1;
+    }
+
+    pub fn report(&self) -> Report {
+        let lost = self.lost;
+        Report {
+            received: self.received,
+            lost,
+            duplicated: self.duplicated,
+            reordered: self.reordered,
+            invalid: self.invalid,
+            loss_percent: match self.next {
+                0 => 0.0,
+                sent => lost as f64 / sent as f64 * 100.0,
+            },
+            last_seq: self.next.checked_sub(1),
+        }
+    }
+}
+
+/// The sequence numbers of the samples in a packet the node sent to its targets. Other messages
+/// (heartbeats and pings) are always JSON with a `type` and have none.
+pub fn sequence_numbers(
+    packet: &[u8],
+    encoding: Encoding,
+    compression: Compression,
+) -> Result<Vec<u64>, String> {
+    if let Ok(Value::Object(message)) = serde_json::from_slice::<Value>(packet) {
+        if message.contains_key("type") {
+            return Ok(vec![]);
+        }
+    }
+    let packet: Cow<[u8]> = match compression {
+        Compression::None => Cow::Borrowed(packet),
+        #[cfg(feature = "decoder")]
+        _ => Cow::Owned(crate::compression::decompress(packet)?),
+        #[cfg(not(feature = "decoder"))]
+        _ => return Err(missing_decoder("compression")),
+    };
+    let packet: Value = match encoding {
+        Encoding::Json => serde_json::from_slice(&packet).map_err(|e| e.to_string())?,
+        Encoding::Msgpack => rmp_serde::from_slice(&packet).map_err(|e| e.to_string())?,
+        // batches are packets back to back
+        #[cfg(feature = "decoder")]
+        Encoding::Binary => {
+            use crate::encoding::{decode_binary, BINARY_PACKET_SIZE};
+
+            let packets = packet.chunks_exact(BINARY_PACKET_SIZE);
+            if !packets.remainder().is_empty() || packet.is_empty() {
+                return Err(format!(
+                    "expected a multiple of {} bytes, got {}",
+                    BINARY_PACKET_SIZE,
+                    packet.len()
+                ));
+            }
+            return packets
+                .map(|packet| decode_binary(packet).map(|sample| sample.seq))
+                .collect();
+        }
+        #[cfg(not(feature = "decoder"))]
+        Encoding::Binary => return Err(missing_decoder("encoding")),
+    };
+    let seq = |value: &Value| value["seq"].as_u64().ok_or("a sample has no `seq`");
+    match packet["samples"].as_array() {
+        Some(samples) => Ok(samples.iter().map(seq).collect::<Result<_, _>>()?),
+        None => Ok(vec![seq(&packet["meta"])?]),
+    }
+}
+
+fn missing_decoder(field: &str) -> String {
+    format!(
+        "verifying `{}` requires building with the `decoder` feature",
+        field
+    )
+}
+
+/// Runs a node with `config` for `duration`, while a listener on `listen_address` checks the data
+/// packets arriving there with a [`SeqChecker`]. The node's target has to lead to the listener,
+/// e.g. by being this host and `listen_address`'s port.
+pub async fn run(
+    config: Config,
+    duration: Duration,
+    listen_address: SocketAddr,
+) -> Result<Report, Box<dyn Error>> {
+    let config = config.with_defaults();
+    let (encoding, compression) = (config.encoding.unwrap(), config.compression.unwrap());
+    if config.transport != Some(Transport::Udp) {
+        return Err("verify only supports `transport: udp`".into());
+    }
+    if config.dry_run.unwrap() || config.mqtt_only.unwrap() {
+        return Err(
+            "verify needs the node to send to its targets, not `dry_run` or `mqtt_only`".into(),
+        );
+    }
+    // fail before anything is sent if the packets can't be decoded
+    if !cfg!(feature = "decoder") {
+        if encoding == Encoding::Binary {
+            return Err(missing_decoder("encoding").into());
+        }
+        if compression != Compression::None {
+            return Err(missing_decoder("compression").into());
+        }
+    }
+
+    let listener = UdpSocket::bind(listen_address).await.map_err(|e| {
+        format!(
+            "Failed to bind verify listener on {}: {} (change `--listen-port`)",
+            listen_address, e
+        )
+    })?;
+    let node = Node::bind(config).await?;
+    info!(listen_address = %listener.local_addr()?, ?duration, "Verifying data packets");
+
+    let mut checker = SeqChecker::default();
+    let receive = async {
+        let mut buf = vec![0; 65536];
+        loop {
+            let length = match listener.recv(&mut buf).await {
+                Ok(length) => length,
+                Err(e) => {
+                    warn!(error = %e, "Couldn't receive on the verify listener");
+                    continue;
+                }
+            };
+            match sequence_numbers(&buf[..length], encoding, compression) {
+                Ok(seqs) => seqs.into_iter().for_each(|seq| checker.record(seq)),
+                Err(e) => {
+                    warn!(error = %e, "Received an invalid data packet");
+                    checker.record_invalid();
+                }
+            }
+        }
+    };
+    let result = tokio::select! {
+        result = async {
+            let result = node.run(time::sleep(duration)).await;
+            time::sleep(GRACE_PERIOD).await;
+            result
+        } => result,
+        _ = receive => Ok(()),
+    };
+    result?;
+    Ok(checker.report())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn finds_gaps_duplicates_and_reordering() {
+        let mut checker = SeqChecker::default();
+        for seq in [0, 1, 3, 4, 2, 4, 7] {
+            checker.record(seq);
+        }
+        let report = checker.report();
+        assert_eq!(report.received, 7);
+        // 5 and 6
+        assert_eq!(report.lost, 2);
+        assert_eq!(report.duplicated, 1);
+        assert_eq!(report.reordered, 1);
+        assert_eq!(report.loss_percent, 25.0);
+        assert_eq!(report.last_seq, Some(7));
+        assert!(!report.passed());
+
+        let mut checker = SeqChecker::default();
+        (0..10).for_each(|seq| checker.record(seq));
+        assert!(checker.report().passed());
+
+        // a stray packet with a huge `seq`, then the rest of a gap arriving late
+        let mut checker = SeqChecker::default();
+        for seq in [0, 1_000_000_000_000_000, 5, 1, 5] {
+            checker.record(seq);
+        }
+        let report = checker.report();
+        assert_eq!(report.lost, 1_000_000_000_000_000 - 3);
+        assert_eq!(report.reordered, 2);
+        assert_eq!(report.duplicated, 1);
+        assert_eq!(report.last_seq, Some(1_000_000_000_000_000));
+        assert!(!SeqChecker::default().report().passed());
+    }
+
+    #[test]
+    fn reads_the_sequence_numbers_of_data_packets() {
+        let packet = |value: Value| value.to_string().into_bytes();
+        let json = |packet: &[u8]| sequence_numbers(packet, Encoding::Json, Compression::None);
+
+        let data = packet(json!({"message": "1", "meta": {"seq": 3}}));
+        assert_eq!(json(&data), Ok(vec![3]));
+        let batch = packet(json!({"samples": [{"seq": 4}, {"seq": 5}], "meta": {}}));
+        assert_eq!(json(&batch), Ok(vec![4, 5]));
+        let heartbeat = packet(json!({"type": "heartbeat"}));
+        assert_eq!(json(&heartbeat), Ok(vec![]));
+        assert!(json(b"{\"meta\":{}}").is_err());
+
+        let msgpack = rmp_serde::to_vec_named(&json!({"meta": {"seq": 6}})).unwrap();
+        assert_eq!(
+            sequence_numbers(&msgpack, Encoding::Msgpack, Compression::None),
+            Ok(vec![6])
+        );
+        // heartbeats stay JSON
+        assert_eq!(
+            sequence_numbers(&heartbeat, Encoding::Msgpack, Compression::None),
+            Ok(vec![])
+        );
+    }
+
+    #[cfg(feature = "decoder")]
+    #[test]
+    fn reads_the_sequence_numbers_of_binary_batches() {
+        use crate::encoding::{encode_binary, BINARY_PACKET_SIZE};
+        use crate::generator::{DataSample, SampleValue};
+
+        let batch: Vec<u8> = (7..10)
+            .flat_map(|seq| {
+                let sample = DataSample {
+                    seq,
+                    channel: None,
+                    value: SampleValue::U16(1234),
+                    raw: None,
+                    generated_at_micros: 1,
+                };
+                encode_binary(&sample, 2)
+            })
+            .collect();
+        let binary = |packet: &[u8]| sequence_numbers(packet, Encoding::Binary, Compression::None);
+        assert_eq!(binary(&batch), Ok(vec![7, 8, 9]));
+        assert_eq!(binary(&batch[..BINARY_PACKET_SIZE]), Ok(vec![7]));
+        // a packet cut short
+        assert!(binary(&batch[..BINARY_PACKET_SIZE * 2 + 3]).is_err());
+        assert!(binary(&[]).is_err());
+    }
+}
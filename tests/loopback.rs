@@ -0,0 +1,1185 @@
+use decentralized_automation_networks_virtual_input_node::{
+    compression::{self, Compression},
+    config::Config,
+    fleet,
+    protocol::PingReplyFormat,
+    transport::Transport,
+    verify, BoundPorts, Node,
+};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+async fn receive_json(socket: &UdpSocket) -> Value {
+    let mut buf = [0; 2048];
+    let length = timeout(RECEIVE_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .expect("timed out waiting for a packet")
+        .unwrap();
+    serde_json::from_slice(&buf[..length]).unwrap()
+}
+
+fn config(target_port: u16) -> Config {
+    serde_json::from_value(json!({
+        "area": "room1",
+        "flow_name": "Flow 1",
+        "target_ip": "127.0.0.1",
+        "target_port": target_port,
+        "outbound_port_data": 0,
+        "inbound_port": 0,
+        "bind_address": "127.0.0.1",
+        "interval": 20,
+        "ack_repeat": 1,
+        "quiet": true,
+    }))
+    .unwrap()
+}
+
+/// Runs `node` until `test`, which gets the node's ports, completes
+async fn run_node<Fut: Future<Output = ()>>(node: Node, test: impl FnOnce(BoundPorts) -> Fut) {
+    let ports = node.ports();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let test = async {
+        test(ports).await;
+        shutdown_tx.send(()).unwrap();
+    };
+    let (result, ()) = tokio::join!(
+        node.run(async {
+            let _ = shutdown_rx.await;
+        }),
+        test
+    );
+    result.unwrap();
+}
+
+/// Binds a node with `config` and runs it until `test`, which gets the node's ports, completes
+async fn run_node_with<Fut: Future<Output = ()>>(
+    config: Config,
+    test: impl FnOnce(BoundPorts) -> Fut,
+) {
+    run_node(Node::bind(config).await.unwrap(), test).await;
+}
+
+#[tokio::test]
+async fn sends_data_and_follows_target_updates() {
+    let first_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let second_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let first_port = first_target.local_addr().unwrap().port();
+    let second_port = second_target.local_addr().unwrap().port();
+
+    run_node_with(config(first_port), |ports| async move {
+        let inbound_port = ports.inbound;
+        let mut last_seq = None;
+        for _ in 0..3 {
+            let packet = receive_json(&first_target).await;
+            let seq = packet["seq"].as_u64().unwrap();
+            assert!(packet["message"].as_str().unwrap().parse::<u16>().is_ok());
+            assert_eq!(packet["meta"]["flow_name"], "Flow 1");
+            assert_eq!(packet["meta"]["execution_area"], "room1");
+            assert_eq!(packet["meta"]["seq"], seq);
+            assert!(packet["sent_at_micros"].is_u64());
+            if let Some(last_seq) = last_seq {
+                assert_eq!(seq, last_seq + 1);
+            }
+            last_seq = Some(seq);
+        }
+
+        // the new port is `target_port_base` plus the last four digits of the configured port
+        let update = json!({
+            "type": "updateTarget",
+            "target": "127.0.0.1",
+            "target_port_base": second_port - first_port % 10000,
+        });
+        controller
+            .send_to(update.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        let ack = receive_json(&controller).await;
+        assert_eq!(ack["type"], "updateTarget");
+        assert_eq!(ack["success"], true);
+
+        let packet = receive_json(&second_target).await;
+        assert!(packet["seq"].as_u64().unwrap() > last_seq.unwrap());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn applies_absolute_target_updates_and_rejects_invalid_ones() {
+    let first_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let second_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let first_port = first_target.local_addr().unwrap().port();
+    let second_port = second_target.local_addr().unwrap().port();
+
+    run_node_with(config(first_port), |ports| async move {
+        let inbound_port = ports.inbound;
+        receive_json(&first_target).await;
+
+        let invalid = json!({"type": "updateTarget", "target": "127.0.0.1", "target_port": 0});
+        controller
+            .send_to(invalid.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        let nack = receive_json(&controller).await;
+        assert_eq!(nack["type"], "updateTarget");
+        assert_eq!(nack["success"], false);
+        assert!(nack["error"].is_string());
+        // the old target is kept
+        receive_json(&first_target).await;
+
+        let unresolvable = json!({
+            "type": "updateTarget",
+            "target": "unresolvable.invalid",
+            "target_port": second_port,
+            "request_id": 7,
+        });
+        controller
+            .send_to(
+                unresolvable.to_string().as_bytes(),
+                ("127.0.0.1", inbound_port),
+            )
+            .await
+            .unwrap();
+        let nack = receive_json(&controller).await;
+        assert_eq!(nack["success"], false);
+        assert_eq!(nack["request_id"], 7);
+        controller
+            .send_to(
+                json!({"type": "updateTargetConfirmed", "request_id": 7})
+                    .to_string()
+                    .as_bytes(),
+                ("127.0.0.1", inbound_port),
+            )
+            .await
+            .unwrap();
+        receive_json(&first_target).await;
+
+        let update =
+            json!({"type": "updateTarget", "target": "127.0.0.1", "target_port": second_port});
+        controller
+            .send_to(update.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        let ack = receive_json(&controller).await;
+        assert_eq!(ack["success"], true);
+        receive_json(&second_target).await;
+
+        // the ACK goes to `replyTo` instead, e.g. the controller's public address behind NAT
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let update = json!({
+            "type": "updateTarget",
+            "target": "127.0.0.1",
+            "target_port": first_port,
+            "replyTo": relay.local_addr().unwrap().to_string(),
+        });
+        controller
+            .send_to(update.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        let ack = receive_json(&relay).await;
+        assert_eq!(ack["success"], true);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn answers_a_corrected_retry_with_the_same_request_id() {
+    let first_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let second_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let second_port = second_target.local_addr().unwrap().port();
+    run_node_with(config(first_target.local_addr().unwrap().port()), |ports| async move {
+        let inbound_port = ports.inbound;
+        let send = |message: Value| {
+            let controller = &controller;
+            async move {
+                controller
+                    .send_to(message.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+                    .await
+                    .unwrap();
+            }
+        };
+        // the next reply of `type` other than resends of earlier ones
+        let receive = |ty: &'static str, success: bool| {
+            let controller = &controller;
+            async move {
+                loop {
+                    let reply = receive_json(controller).await;
+                    if reply["type"] == ty && reply["success"] == success {
+                        return reply;
+                    }
+                }
+            }
+        };
+
+        send(json!({"type": "updateTarget", "target": "127.0.0.1", "target_port": 0, "request_id": 9})).await;
+        let nack = receive("updateTarget", false).await;
+        assert_eq!(nack["request_id"], 9);
+
+        // the retry replaces the NACK that is still being resent
+        send(json!({"type": "updateTarget", "target": "127.0.0.1", "target_port": second_port, "request_id": 9})).await;
+        let ack = receive("updateTarget", true).await;
+        assert_eq!(ack["request_id"], 9);
+        receive_json(&second_target).await;
+
+        // a request of another type gets its own ACK
+        send(json!({"type": "setInterval", "interval_ms": 50, "request_id": 9})).await;
+        let ack = receive("setInterval", true).await;
+        assert_eq!(ack["interval_ms"], 50);
+
+        send(json!({"type": "updateTargetConfirmed", "request_id": 9})).await;
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn keeps_handling_messages_while_repeating_acks() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let target_port = target.local_addr().unwrap().port();
+    let mut config = config(target_port);
+    config.ack_repeat = Some(10);
+    config.ack_repeat_interval_ms = Some(500);
+    run_node_with(config, |ports| async move {
+        let inbound_port = ports.inbound;
+        for message in [
+            json!({"type": "updateTarget", "target": "127.0.0.1", "target_port": target_port}),
+            json!({"type": "udpPing", "nonce": 3}),
+        ] {
+            controller
+                .send_to(message.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+                .await
+                .unwrap();
+        }
+        assert_eq!(receive_json(&controller).await["type"], "updateTarget");
+        // answered before the ACK is repeated
+        let pong = timeout(Duration::from_millis(300), receive_json(&controller))
+            .await
+            .expect("the ping waited for the repeated ACK");
+        assert_eq!(pong["echo"], 3);
+        assert_eq!(receive_json(&controller).await["type"], "updateTarget");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn rejects_reordered_target_updates() {
+    let first_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let second_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let first_port = first_target.local_addr().unwrap().port();
+    let second_port = second_target.local_addr().unwrap().port();
+    run_node_with(config(first_port), |ports| async move {
+        let inbound_port = ports.inbound;
+        let update = |port: u16, issued_at: Option<u64>| {
+            let mut update =
+                json!({"type": "updateTarget", "target": "127.0.0.1", "target_port": port});
+            if let Some(issued_at) = issued_at {
+                update["issued_at"] = issued_at.into();
+            }
+            update.to_string()
+        };
+
+        controller
+            .send_to(
+                update(second_port, Some(2)).as_bytes(),
+                ("127.0.0.1", inbound_port),
+            )
+            .await
+            .unwrap();
+        assert_eq!(receive_json(&controller).await["success"], true);
+
+        // sent before the first one, but arriving after it
+        controller
+            .send_to(
+                update(first_port, Some(1)).as_bytes(),
+                ("127.0.0.1", inbound_port),
+            )
+            .await
+            .unwrap();
+        let nack = receive_json(&controller).await;
+        assert_eq!(nack["success"], false);
+        assert!(nack["error"].as_str().unwrap().contains("issued_at"));
+        receive_json(&second_target).await;
+
+        // without `issued_at`, the last update wins
+        controller
+            .send_to(
+                update(first_port, None).as_bytes(),
+                ("127.0.0.1", inbound_port),
+            )
+            .await
+            .unwrap();
+        assert_eq!(receive_json(&controller).await["success"], true);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn resets_to_the_configured_target() {
+    let configured = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    run_node_with(
+        config(configured.local_addr().unwrap().port()),
+        |ports| async move {
+            let inbound_port = ports.inbound;
+            let update = json!({
+                "type": "updateTarget",
+                "target": "127.0.0.1",
+                "target_port": other.local_addr().unwrap().port(),
+            });
+            controller
+                .send_to(update.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+                .await
+                .unwrap();
+            assert_eq!(receive_json(&controller).await["success"], true);
+            let last_seq = receive_json(&other).await["seq"].as_u64().unwrap();
+
+            controller
+                .send_to(
+                    br#"{"type":"resetTarget","request_id":"r1"}"#,
+                    ("127.0.0.1", inbound_port),
+                )
+                .await
+                .unwrap();
+            let ack = receive_json(&controller).await;
+            assert_eq!(ack["type"], "resetTarget");
+            assert_eq!(ack["success"], true);
+            assert_eq!(ack["request_id"], "r1");
+            // skipping what was sent to the configured target before the update
+            while receive_json(&configured).await["seq"].as_u64().unwrap() <= last_seq {}
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn fans_out_to_all_targets() {
+    let first_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let second_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let third_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let first_port = first_target.local_addr().unwrap().port();
+    let second_port = second_target.local_addr().unwrap().port();
+    let third_port = third_target.local_addr().unwrap().port();
+
+    let mut config = config(first_port);
+    config.targets = Some(vec![format!("127.0.0.1:{}", second_port).parse().unwrap()]);
+    run_node_with(config, |ports| async move {
+        let inbound_port = ports.inbound;
+        let first = receive_json(&first_target).await;
+        let second = receive_json(&second_target).await;
+        assert_eq!(first["seq"], second["seq"]);
+
+        for (op, port) in [("add", third_port), ("remove", first_port)] {
+            let update = json!({
+                "type": "updateTarget",
+                "op": op,
+                "target": "127.0.0.1",
+                "target_port": port,
+            });
+            controller
+                .send_to(update.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+                .await
+                .unwrap();
+            let ack = receive_json(&controller).await;
+            assert_eq!(ack["success"], true, "{} failed: {}", op, ack);
+        }
+        // a retransmission is only acknowledged again, removing the target again would fail
+        let repeated = json!({
+            "type": "updateTarget",
+            "op": "remove",
+            "target": "127.0.0.1",
+            "target_port": first_port,
+        });
+        controller
+            .send_to(repeated.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        assert_eq!(receive_json(&controller).await["success"], true);
+
+        // the first target is gone, so the other two get the same packets from now on
+        let mut second = receive_json(&second_target).await["seq"].as_u64();
+        let mut third = receive_json(&third_target).await["seq"].as_u64();
+        while second != third {
+            // the second target has been receiving for longer, so it may still have older packets queued
+            if second < third {
+                second = receive_json(&second_target).await["seq"].as_u64();
+            } else {
+                third = receive_json(&third_target).await["seq"].as_u64();
+            }
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn tags_and_aggregates_channels() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let port = target.local_addr().unwrap().port();
+
+    let channels = json!([
+        {"name": "temperature", "waveform": "constant", "offset": 2000},
+        {"name": "humidity", "waveform": "constant", "offset": 60, "interval": 10},
+    ]);
+    let mut tagged = config(port);
+    tagged.channels = Some(serde_json::from_value(channels.clone()).unwrap());
+    let mut aggregated = tagged.clone();
+    aggregated.aggregate = Some(true);
+
+    // the nodes run one after the other, sending to the same target
+    let target = &target;
+    for (config, aggregate) in [(tagged, false), (aggregated, true)] {
+        run_node_with(config, |_| async move {
+            let mut seen = Vec::new();
+            while seen.len() < 2 {
+                let packet = receive_json(target).await;
+                let samples = match packet.get("samples") {
+                    Some(samples) => samples.as_array().unwrap().clone(),
+                    None => vec![packet],
+                };
+                // the first aggregated packet may be sent before both channels have a value
+                if aggregate && samples.len() == 2 {
+                    assert_eq!(samples[0]["channel"], "temperature");
+                    assert_eq!(samples[1]["channel"], "humidity");
+                }
+                for sample in samples {
+                    let expected = match sample["channel"].as_str().unwrap() {
+                        "temperature" => "2000",
+                        "humidity" => "60",
+                        channel => panic!("unexpected channel {}", channel),
+                    };
+                    assert_eq!(sample["message"], expected);
+                    if !seen.contains(&sample["channel"]) {
+                        seen.push(sample["channel"].clone());
+                    }
+                }
+            }
+        })
+        .await;
+        // don't mix up the packets of both nodes
+        while timeout(Duration::from_millis(50), target.recv(&mut [0; 2048]))
+            .await
+            .is_ok()
+        {}
+    }
+}
+
+#[tokio::test]
+async fn sends_heartbeats_while_paused() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config(target.local_addr().unwrap().port());
+    config.heartbeat_interval_ms = Some(20);
+    run_node_with(config, |ports| async move {
+        let inbound_port = ports.inbound;
+        controller
+            .send_to(br#"{"type":"pauseData"}"#, ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        assert_eq!(receive_json(&controller).await["success"], true);
+
+        loop {
+            let packet = receive_json(&target).await;
+            if packet["type"] == "heartbeat" && packet["paused"] == true {
+                assert_eq!(packet["flow_name"], "Flow 1");
+                assert_eq!(packet["area"], "room1");
+                assert!(packet["uptime_ms"].is_u64());
+                assert_eq!(packet["restart_count"], 0);
+                break;
+            }
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn counts_restarts_in_the_state_file() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let state_file = std::env::temp_dir().join(format!("vin-{}-restarts.json", std::process::id()));
+    let _ = std::fs::remove_file(&state_file);
+    let mut config = config(target.local_addr().unwrap().port());
+    config.state_file = Some(state_file.to_string_lossy().into_owned());
+
+    // the first start doesn't count
+    drop(Node::bind(config.clone()).await.unwrap());
+    drop(Node::bind(config.clone()).await.unwrap());
+    run_node_with(config, |ports| async move {
+        let inbound_port = ports.inbound;
+        controller
+            .send_to(br#"{"type":"queryStatus"}"#, ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        let status = receive_json(&controller).await;
+        assert_eq!(status["restart_count"], 2);
+        assert_eq!(status["uptime_seconds"], 0);
+    })
+    .await;
+    let _ = std::fs::remove_file(&state_file);
+}
+
+#[tokio::test]
+async fn keeps_a_generated_node_id_in_the_state_file() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let state_file = std::env::temp_dir().join(format!("vin-{}-node-id.json", std::process::id()));
+    let _ = std::fs::remove_file(&state_file);
+    let mut config = config(target.local_addr().unwrap().port());
+    config.state_file = Some(state_file.to_string_lossy().into_owned());
+
+    drop(Node::bind(config.clone()).await.unwrap());
+    let state: Value =
+        serde_json::from_str(&std::fs::read_to_string(&state_file).unwrap()).unwrap();
+    let node_id = state["node_id"].as_str().unwrap().to_string();
+    assert!(!node_id.is_empty());
+    run_node_with(config, |ports| async move {
+        let inbound_port = ports.inbound;
+        let packet = receive_json(&target).await;
+        assert_eq!(packet["meta"]["node_id"], node_id.as_str());
+        controller
+            .send_to(br#"{"type":"queryStatus"}"#, ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        assert_eq!(receive_json(&controller).await["node_id"], node_id.as_str());
+    })
+    .await;
+    let _ = std::fs::remove_file(&state_file);
+}
+
+#[tokio::test]
+async fn answers_target_queries_with_the_primary_target() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config(target.local_addr().unwrap().port());
+    config.node_id = Some("node-1".to_string());
+    run_node_with(config, |ports| async move {
+        let inbound_port = ports.inbound;
+        controller
+            .send_to(
+                br#"{"type":"getTarget","request_id":7}"#,
+                ("127.0.0.1", inbound_port),
+            )
+            .await
+            .unwrap();
+        let reply = receive_json(&controller).await;
+        assert_eq!(
+            reply,
+            json!({
+                "type": "currentTarget",
+                "target": target.local_addr().unwrap().to_string(),
+                "node_id": "node-1",
+                "request_id": 7,
+            })
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn rebinds_taken_ports_with_reuse_address() {
+    let mut config = config(9);
+    config.reuse_address = Some(true);
+    config.socket_send_buffer = Some(65536);
+    config.socket_recv_buffer = Some(65536);
+    let first = Node::bind(config.clone()).await.unwrap();
+    config.inbound_port = first.ports().inbound;
+    let second = Node::bind(config.clone()).await.unwrap();
+    assert_eq!(second.ports().inbound, first.ports().inbound);
+
+    config.reuse_address = Some(false);
+    let error = Node::bind(config).await.err().unwrap();
+    assert!(error.to_string().contains("(change `inbound_port`)"));
+}
+
+#[tokio::test]
+async fn sends_to_ipv4_targets_from_sockets_on_all_ipv6_interfaces() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config(target.local_addr().unwrap().port());
+    config.bind_address = Some("::".parse().unwrap());
+    run_node_with(config, |_| async move {
+        assert!(receive_json(&target).await["seq"].is_u64());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn answers_pings_without_reply_address_to_the_sender() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let pinger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    run_node_with(
+        config(target.local_addr().unwrap().port()),
+        |ports| async move {
+            let inbound_port = ports.inbound;
+            // an invalid reply address is reported to the sender without affecting the next ping
+            for ping in [
+                json!({"type": "udpPing", "replyTo": "not an address", "nonce": 1}),
+                json!({"type": "udpPing", "nonce": 2}),
+            ] {
+                pinger
+                    .send_to(ping.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(receive_json(&pinger).await["type"], "error");
+            let pong = receive_json(&pinger).await;
+            assert_eq!(pong["type"], "udpPong");
+            assert_eq!(pong["echo"], 2);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn answers_every_ping_with_a_pong_in_json_format() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let pinger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config(target.local_addr().unwrap().port());
+    config.ping_reply_format = Some(PingReplyFormat::Json);
+    run_node_with(config, |ports| async move {
+        let inbound_port = ports.inbound;
+        let ping = json!({"type": "udpPing", "nonce": 5});
+        pinger
+            .send_to(ping.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        let pong = receive_json(&pinger).await;
+        assert_eq!(pong["type"], "udpPong");
+        assert_eq!(pong["echo"], 5);
+        assert!(pong["server_time_micros"].as_u64().unwrap() > 0);
+
+        let ping = json!({"type": "udpPing"});
+        pinger
+            .send_to(ping.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        let pong = receive_json(&pinger).await;
+        assert_eq!(pong["type"], "udpPong");
+        assert!(pong.get("echo").is_none());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn waits_for_the_startup_delay() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config(target.local_addr().unwrap().port());
+    config.startup_delay_ms = Some(300);
+    let started = std::time::Instant::now();
+    run_node_with(config, |_| async move {
+        receive_json(&target).await;
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn applies_reloaded_configs_without_rebinding() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let new_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = config(target.local_addr().unwrap().port());
+    let node = Node::bind(config.clone()).await.unwrap();
+    let reloader = node.reloader();
+
+    run_node(node, |ports| async move {
+        let inbound_port = ports.inbound;
+        receive_json(&target).await;
+
+        let mut reloaded = config;
+        reloaded.target_port = new_target.local_addr().unwrap().port();
+        reloaded.interval = Some(50);
+        reloaded.meta_extra = Some([("site".to_string(), json!("berlin"))].into());
+        reloader.reload(reloaded);
+        loop {
+            let packet = receive_json(&new_target).await;
+            if packet["meta"]["site"] == "berlin" {
+                break;
+            }
+        }
+
+        // still on the same inbound socket, with the counters kept
+        controller
+            .send_to(br#"{"type":"queryStatus"}"#, ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        let status = receive_json(&controller).await;
+        assert_eq!(status["interval_ms"], 50);
+        assert!(status["packets_sent"].as_u64().unwrap() >= 2);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn splits_batches_exceeding_the_payload_limit() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config(target.local_addr().unwrap().port());
+    config.interval = Some(1);
+    config.batch_size = Some(8);
+    config.max_payload_bytes = Some(400);
+    run_node_with(config, |_| async move {
+        let mut buf = [0; 2048];
+        let mut next_seq = 0;
+        while next_seq < 16 {
+            let length = timeout(RECEIVE_TIMEOUT, target.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(length <= 400, "{} bytes", length);
+            let packet: Value = serde_json::from_slice(&buf[..length]).unwrap();
+            // no sample is lost or reordered by splitting
+            for sample in packet["samples"].as_array().unwrap() {
+                assert_eq!(sample["seq"], next_seq);
+                next_seq += 1;
+            }
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn compresses_batches() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config(target.local_addr().unwrap().port());
+    config.interval = Some(1);
+    config.batch_size = Some(8);
+    config.compression = Some(Compression::Zstd);
+    run_node_with(config, |_| async move {
+        let mut buf = [0; 2048];
+        let length = timeout(RECEIVE_TIMEOUT, target.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf[0], compression::HEADER_ZSTD);
+        let packet: Value =
+            serde_json::from_slice(&zstd::decode_all(&buf[1..length]).unwrap()).unwrap();
+        assert_eq!(packet["samples"].as_array().unwrap().len(), 8);
+        assert!(length < packet.to_string().len() / 2);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn verifies_the_sequence_of_batches() {
+    // a free port for the listener, which the node then sends to
+    let port = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = config(port);
+    config.interval = Some(5);
+    config.batch_size = Some(4);
+    let report = verify::run(
+        config,
+        Duration::from_millis(300),
+        ([127, 0, 0, 1], port).into(),
+    )
+    .await
+    .unwrap();
+    assert!(report.passed(), "{:?}", report);
+    assert!(report.received >= 20);
+    assert_eq!(report.last_seq, Some(report.received - 1));
+}
+
+#[tokio::test]
+async fn runs_several_nodes_in_one_process() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let nodes = ["Flow 1", "Flow 2"].map(|flow| {
+        let mut config = config(target.local_addr().unwrap().port());
+        config.flow_name = flow.to_string();
+        (format!("{}.yaml", flow), config)
+    });
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+    let test = async {
+        let mut flows = std::collections::BTreeSet::new();
+        while flows.len() < 2 {
+            let packet = receive_json(&target).await;
+            flows.insert(packet["meta"]["flow_name"].as_str().unwrap().to_string());
+        }
+        shutdown_tx.send(()).unwrap();
+    };
+
+    let (result, ()) = tokio::join!(
+        fleet::run(nodes.to_vec(), false, async {
+            let _ = shutdown_rx.await;
+        }),
+        test
+    );
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn sends_a_single_packet_once() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config(target.local_addr().unwrap().port());
+    config.meta_extra = Some([("site".to_string(), json!("berlin"))].into());
+    let node = Node::bind(config).await.unwrap();
+
+    node.send_once().await.unwrap();
+    let packet = receive_json(&target).await;
+    assert_eq!(packet["seq"], 0);
+    assert_eq!(packet["meta"]["flow_name"], "Flow 1");
+    assert_eq!(packet["meta"]["site"], "berlin");
+}
+
+#[tokio::test]
+async fn sends_data_over_tcp_and_reconnects() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config(listener.local_addr().unwrap().port());
+    config.transport = Some(Transport::Tcp);
+    run_node_with(config, |_| async move {
+        let (stream, _) = timeout(RECEIVE_TIMEOUT, listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let first: Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        let second: Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(second["seq"], first["seq"].as_u64().unwrap() + 1);
+
+        // the node connects again after losing the connection
+        drop(lines);
+        let (stream, _) = timeout(RECEIVE_TIMEOUT, listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let packet: Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert!(packet["seq"].as_u64().unwrap() > second["seq"].as_u64().unwrap());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn prefixes_compressed_tcp_packets_with_their_length() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config(listener.local_addr().unwrap().port());
+    config.transport = Some(Transport::Tcp);
+    config.interval = Some(1);
+    config.batch_size = Some(8);
+    config.compression = Some(Compression::Zstd);
+    run_node_with(config, |_| async move {
+        let (mut stream, _) = timeout(RECEIVE_TIMEOUT, listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut next_seq = None;
+        let mut newlines = 0;
+        // compressed data is close to random, so some packets contain a newline byte
+        for _ in 0..50 {
+            let length = timeout(RECEIVE_TIMEOUT, stream.read_u32())
+                .await
+                .unwrap()
+                .unwrap();
+            let mut packet = vec![0; length as usize];
+            stream.read_exact(&mut packet).await.unwrap();
+            newlines += packet.contains(&b'\n') as usize;
+            let payload = match packet[0] {
+                compression::HEADER_ZSTD => zstd::decode_all(&packet[1..]).unwrap(),
+                _ => packet[1..].to_vec(),
+            };
+            let batch: Value = serde_json::from_slice(&payload).unwrap();
+            for sample in batch["samples"].as_array().unwrap() {
+                let seq = sample["seq"].as_u64().unwrap();
+                // samples dropped by the full send queue leave gaps
+                if let Some(next_seq) = next_seq {
+                    assert!(seq >= next_seq);
+                }
+                next_seq = Some(seq + 1);
+            }
+        }
+        assert!(newlines > 0);
+    })
+    .await;
+}
+
+/// Reads a request from the next connection and answers it with `status`, returning the request
+/// line, headers and body
+async fn receive_http_request(listener: &TcpListener, status: &str) -> (Vec<String>, Value) {
+    let (stream, _) = timeout(RECEIVE_TIMEOUT, listener.accept())
+        .await
+        .expect("timed out waiting for a request")
+        .unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut head = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        if line.trim_end().is_empty() {
+            break;
+        }
+        head.push(line.trim_end().to_string());
+    }
+    let length: usize = head
+        .iter()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await.unwrap();
+    reader
+        .into_inner()
+        .write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes())
+        .await
+        .unwrap();
+    (head, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn posts_data_over_http_and_retries_failed_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    // the UDP target is ignored with `transport: http`
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = config(target.local_addr().unwrap().port());
+    config.transport = Some(Transport::Http);
+    config.http_url = Some(format!(
+        "http://127.0.0.1:{}/ingest",
+        listener.local_addr().unwrap().port()
+    ));
+    config.http_headers = Some(
+        [("Authorization".to_string(), "Bearer token".to_string())]
+            .into_iter()
+            .collect(),
+    );
+    run_node_with(config, |_| async move {
+        let (head, packet) = receive_http_request(&listener, "503 Service Unavailable").await;
+        assert_eq!(head[0], "POST /ingest HTTP/1.1");
+        assert!(head.contains(&"Content-Type: application/json".to_string()));
+        assert!(head.contains(&"Authorization: Bearer token".to_string()));
+        assert_eq!(packet["meta"]["flow_name"], "Flow 1");
+
+        // the rejected packet is sent again
+        let (_, retried) = receive_http_request(&listener, "200 OK").await;
+        assert_eq!(retried["seq"], packet["seq"]);
+        let mut buf = [0; 2048];
+        assert!(timeout(Duration::from_millis(100), target.recv(&mut buf))
+            .await
+            .is_err());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn fails_over_to_a_backup_that_answers_pings() {
+    // the primary target receives data but never answers pings
+    let primary = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let backup = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let backup_port = backup.local_addr().unwrap().port();
+
+    let mut config = config(primary.local_addr().unwrap().port());
+    config.target_failover = Some(vec![format!("127.0.0.1:{}", backup_port).parse().unwrap()]);
+    config.failover_check_interval_ms = Some(20);
+    config.failover_timeout_ms = Some(200);
+    run_node_with(config, |_| async move {
+        receive_json(&primary).await;
+        loop {
+            let message = receive_json(&backup).await;
+            if message["type"] == "udpPing" {
+                let reply =
+                    json!({"type": "udpPong", "echo": message["nonce"], "server_time_micros": 0});
+                let reply_to: std::net::SocketAddr =
+                    message["replyTo"].as_str().unwrap().parse().unwrap();
+                backup
+                    .send_to(reply.to_string().as_bytes(), reply_to)
+                    .await
+                    .unwrap();
+            } else if message["seq"].is_u64() {
+                break;
+            }
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn answers_messages_missing_fields_with_an_error() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let relay_address = relay.local_addr().unwrap();
+    let mut config = config(target.local_addr().unwrap().port());
+    config.node_id = Some("node-1".to_string());
+    run_node_with(config, |ports| async move {
+        let inbound_port = ports.inbound;
+        let send = |message: &'static str| {
+            let controller = &controller;
+            async move {
+                controller
+                    .send_to(message.as_bytes(), ("127.0.0.1", inbound_port))
+                    .await
+                    .unwrap();
+            }
+        };
+        send(r#"{"target":"127.0.0.1","request_id":"a"}"#).await;
+        assert_eq!(
+            receive_json(&controller).await,
+            json!({"type": "error", "reason": "missing field `type`", "node_id": "node-1", "request_id": "a"})
+        );
+        send(r#"{"type":"updateTargetConfirmed"}"#).await;
+        assert_eq!(
+            receive_json(&controller).await,
+            json!({"type": "error", "reason": "missing field `request_id`", "node_id": "node-1"})
+        );
+        // controllers of target updates keep getting the failed ACK they wait for
+        send(r#"{"type":"updateTarget"}"#).await;
+        let nack = receive_json(&controller).await;
+        assert_eq!(nack["type"], "updateTarget");
+        assert_eq!(nack["success"], false);
+        assert_eq!(nack["error"], "missing field `target`");
+        // ... at their reply address if they gave one
+        let relayed = json!({"type": "updateTarget", "replyTo": relay_address.to_string()});
+        controller
+            .send_to(relayed.to_string().as_bytes(), ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        let nack = receive_json(&relay).await;
+        assert_eq!(nack["type"], "updateTarget");
+        assert_eq!(nack["success"], false);
+
+        send(r#"{"type":"queryStatus"}"#).await;
+        assert_eq!(receive_json(&controller).await["invalid_messages"], 4);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn ignores_messages_of_unknown_types() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    run_node_with(
+        config(target.local_addr().unwrap().port()),
+        |ports| async move {
+            let inbound_port = ports.inbound;
+            controller
+                .send_to(
+                    br#"{"type":"selfDestruct","request_id":1}"#,
+                    ("127.0.0.1", inbound_port),
+                )
+                .await
+                .unwrap();
+            let mut buf = [0; 2048];
+            assert!(
+                timeout(Duration::from_millis(200), controller.recv(&mut buf))
+                    .await
+                    .is_err(),
+                "unknown types mustn't be answered"
+            );
+            controller
+                .send_to(br#"{"type":"queryStatus"}"#, ("127.0.0.1", inbound_port))
+                .await
+                .unwrap();
+            let status = receive_json(&controller).await;
+            assert_eq!(status["inbound_messages"]["unknown"], 1);
+            assert_eq!(status["invalid_messages"], 0);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn handles_several_messages_in_one_datagram() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let controller = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    run_node_with(config(target.local_addr().unwrap().port()), |ports| async move {
+        let inbound_port = ports.inbound;
+        // the malformed line in between is skipped
+        let messages = "{\"type\":\"queryStatus\",\"request_id\":1}\n{not json\n{\"type\":\"queryStatus\",\"request_id\":2}\n";
+        controller
+            .send_to(messages.as_bytes(), ("127.0.0.1", inbound_port))
+            .await
+            .unwrap();
+        assert_eq!(receive_json(&controller).await["request_id"], 1);
+        let status = receive_json(&controller).await;
+        assert_eq!(status["request_id"], 2);
+        // both queries are counted by the time the second one is answered
+        assert_eq!(status["inbound_messages"]["queryStatus"], 2);
+        assert_eq!(status["inbound_messages"]["unknown"], 0);
+        assert_eq!(status["rejected_messages"], 0);
+
+    })
+    .await;
+}
+
+/// A connected UDP socket as the stream a DTLS server reads records from and writes them to
+#[cfg(feature = "dtls")]
+#[derive(Debug)]
+struct Datagrams(std::net::UdpSocket);
+
+#[cfg(feature = "dtls")]
+impl std::io::Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+#[cfg(feature = "dtls")]
+impl std::io::Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "dtls")]
+#[tokio::test]
+async fn encrypts_data_with_dtls() {
+    use openssl::ssl::{Ssl, SslContext, SslMethod, SslOptions};
+    use std::io::Read;
+
+    let target = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    target.set_read_timeout(Some(RECEIVE_TIMEOUT)).unwrap();
+    let mut config = config(target.local_addr().unwrap().port());
+    config.transport = Some(Transport::Dtls);
+    config.dtls_psk = Some("c0ffee".parse().unwrap());
+    let server = tokio::task::spawn_blocking(move || {
+        // the session is with the socket the node opened for it
+        let (_, node_address) = target.peek_from(&mut [0; 1]).unwrap();
+        target.connect(node_address).unwrap();
+        let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+        context.set_options(SslOptions::NO_QUERY_MTU);
+        context.set_cipher_list("PSK").unwrap();
+        context.set_psk_server_callback(|_, identity, psk| {
+            assert_eq!(identity, Some(&b"Flow 1"[..]));
+            psk[..3].copy_from_slice(&[0xc0, 0xff, 0xee]);
+            Ok(3)
+        });
+        let mut ssl = Ssl::new(&context.build()).unwrap();
+        ssl.set_mtu(1472).unwrap();
+        let mut session = ssl.accept(Datagrams(target)).unwrap();
+
+        let mut packets = vec![];
+        for _ in 0..2 {
+            let mut buf = [0; 2048];
+            let length = session.read(&mut buf).unwrap();
+            packets.push(serde_json::from_slice::<Value>(&buf[..length]).unwrap());
+        }
+        packets
+    });
+
+    run_node_with(config, |_| async move {
+        let packets = server.await.unwrap();
+        assert_eq!(packets[0]["meta"]["flow_name"], "Flow 1");
+        assert_eq!(packets[1]["seq"], packets[0]["seq"].as_u64().unwrap() + 1);
+    })
+    .await;
+}